@@ -1,23 +1,41 @@
+use rocket::data::{ByteUnit, Data, Limits};
+use rocket::fairing::AdHoc;
 use rocket::fs::TempFile;
+use rocket::http::uri::fmt::Path as UriPath;
+use rocket::http::uri::Segments;
+use rocket::http::ContentType;
 use rocket::serde::json::{Error, Json};
 use rocket::serde::{Deserialize, Serialize};
 use rocket::State;
 use rocket::{catch, catchers, routes, Build, Request, Rocket};
-use std::io::ErrorKind;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{ErrorKind, Seek};
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::auth::{self, Auth, AuthFailureResponse, Identity, ReadAccess, ReadOnly, WriteAccess};
 use crate::config;
+use crate::error::ErrorCode;
+use crate::export;
 use crate::hash;
+use crate::index::Index;
 use crate::location;
 use crate::metadata;
+use crate::query;
 use crate::responses;
 use crate::store;
+use crate::upload;
 
-use crate::outpack_file::OutpackFile;
+use crate::outpack_file::{FileRequest, OutpackFile, PacketArchive};
 use responses::{FailResponse, OutpackError, OutpackSuccess};
 
 type OutpackResult<T> = Result<OutpackSuccess<T>, OutpackError>;
 
+/// The largest chunk accepted by `PUT /upload/<id>`, unless overridden
+/// by the `upload-chunk` data limit in Rocket's configuration.
+pub const UPLOAD_CHUNK_LIMIT: ByteUnit = ByteUnit::Mebibyte(64);
+
 // This mostly exists to smooth over a difference with original
 // version, which used Root as the object; soon we will update this to
 // report actual versions back.
@@ -28,31 +46,36 @@ pub struct ApiRoot {
 
 #[catch(500)]
 fn internal_error(_req: &Request) -> Json<FailResponse> {
-    Json(FailResponse::from(OutpackError {
-        error: String::from("UNKNOWN_ERROR"),
-        detail: String::from("Something went wrong"),
-        kind: Some(ErrorKind::Other),
-    }))
+    Json(FailResponse::from(OutpackError::new(
+        ErrorCode::UnknownError,
+        "Something went wrong",
+    )))
 }
 
 #[catch(404)]
 fn not_found(_req: &Request) -> Json<FailResponse> {
-    Json(FailResponse::from(OutpackError {
-        error: String::from("NOT_FOUND"),
-        detail: String::from("This route does not exist"),
-        kind: Some(ErrorKind::NotFound),
-    }))
+    Json(FailResponse::from(OutpackError::new(
+        ErrorCode::NotFound,
+        "This route does not exist",
+    )))
 }
 
 #[catch(400)]
 fn bad_request(_req: &Request) -> Json<FailResponse> {
-    Json(FailResponse::from(OutpackError {
-        error: String::from("BAD_REQUEST"),
-        detail: String::from(
-            "The request could not be understood by the server due to malformed syntax",
-        ),
-        kind: Some(ErrorKind::InvalidInput),
-    }))
+    Json(FailResponse::from(OutpackError::new(
+        ErrorCode::BadRequest,
+        "The request could not be understood by the server due to malformed syntax",
+    )))
+}
+
+#[catch(401)]
+fn unauthorized(req: &Request) -> AuthFailureResponse {
+    auth::failure_response(req)
+}
+
+#[catch(403)]
+fn forbidden(req: &Request) -> AuthFailureResponse {
+    auth::failure_response(req)
 }
 
 #[rocket::get("/")]
@@ -63,70 +86,242 @@ fn index(_root: &State<String>) -> OutpackResult<ApiRoot> {
     .into())
 }
 
+// Packets the caller may not read are reported as not existing at
+// all, rather than as forbidden, so as not to reveal their ids.
+fn check_readable(root: &str, identity: &Identity, id: &str) -> Result<(), OutpackError> {
+    if identity.is_restricted() && !identity.can_read(&metadata::get_packet(root, id)?.name) {
+        return Err(OutpackError::new(
+            ErrorCode::PacketNotFound,
+            format!("packet with id '{}' does not exist", id),
+        ));
+    }
+    Ok(())
+}
+
+// The index as the caller is allowed to see it, so that nothing they
+// are given can depend on the packets they cannot read.
+fn readable_index(root: &str, identity: &Identity) -> io::Result<Arc<Index>> {
+    let index = crate::index::get_packet_index(root)?;
+    if !identity.is_restricted() {
+        return Ok(index);
+    }
+    Ok(Arc::new(
+        index.restrict(|packet| identity.can_read(&packet.name)),
+    ))
+}
+
 #[rocket::get("/metadata/list")]
-fn list_location_metadata(root: &State<String>) -> OutpackResult<Vec<location::LocationEntry>> {
-    location::read_locations(root)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+fn list_location_metadata(
+    root: &State<String>,
+    access: ReadAccess,
+) -> OutpackResult<Vec<location::LocationEntry>> {
+    let mut entries = location::read_locations(root)?;
+    if access.0.is_restricted() {
+        let readable: HashSet<String> = crate::index::read_packets(root)?
+            .iter()
+            .filter(|packet| access.0.can_read(&packet.name))
+            .map(|packet| packet.id.clone())
+            .collect();
+        entries.retain(|entry| readable.contains(&entry.packet));
+    }
+    Ok(entries.into())
 }
 
 #[rocket::get("/packit/metadata?<known_since>")]
 fn get_metadata(
     root: &State<String>,
     known_since: Option<f64>,
+    access: ReadAccess,
 ) -> OutpackResult<Vec<metadata::PackitPacket>> {
-    metadata::get_packit_metadata_from_date(root, known_since)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+    let mut packets = metadata::get_packit_metadata_from_date(root, known_since)?;
+    packets.retain(|packet| access.0.can_read(&packet.name));
+    Ok(packets.into())
 }
 
 #[rocket::get("/metadata/<id>/json")]
-fn get_metadata_by_id(root: &State<String>, id: String) -> OutpackResult<serde_json::Value> {
+fn get_metadata_by_id(
+    root: &State<String>,
+    id: String,
+    access: ReadAccess,
+) -> OutpackResult<serde_json::Value> {
+    check_readable(root, &access.0, &id)?;
     metadata::get_metadata_by_id(root, &id)
         .map_err(OutpackError::from)
         .map(OutpackSuccess::from)
 }
 
 #[rocket::get("/metadata/<id>/text")]
-fn get_metadata_raw(root: &State<String>, id: String) -> Result<String, OutpackError> {
+fn get_metadata_raw(
+    root: &State<String>,
+    id: String,
+    access: ReadAccess,
+) -> Result<String, OutpackError> {
+    check_readable(root, &access.0, &id)?;
     metadata::get_metadata_text(root, &id).map_err(OutpackError::from)
 }
 
 #[rocket::get("/file/<hash>")]
-async fn get_file(root: &State<String>, hash: String) -> Result<OutpackFile, OutpackError> {
-    let path = store::file_path(root, &hash);
-    OutpackFile::open(hash, path?)
+async fn get_file(
+    root: &State<String>,
+    hash: String,
+    request: FileRequest,
+    access: ReadAccess,
+) -> Result<OutpackFile, OutpackError> {
+    if access.0.is_restricted() {
+        // Only files belonging to a packet the caller can read.
+        if readable_index(root, &access.0)?
+            .packets_with_file(&hash)
+            .is_empty()
+        {
+            return Err(OutpackError::new(
+                ErrorCode::FileNotFound,
+                format!("hash '{}' not found", hash),
+            ));
+        }
+    }
+    let path = store::find_file(root, &hash);
+    OutpackFile::open(hash, path?, request)
         .await
         .map_err(OutpackError::from)
 }
 
-#[rocket::get("/checksum?<alg>")]
-async fn get_checksum(root: &State<String>, alg: Option<String>) -> OutpackResult<String> {
-    metadata::get_ids_digest(root, alg)
+#[rocket::get("/packet/<id>/file/<path..>")]
+async fn get_packet_file(
+    root: &State<String>,
+    id: String,
+    path: Segments<'_, UriPath>,
+    request: FileRequest,
+    access: ReadAccess,
+) -> Result<OutpackFile, OutpackError> {
+    check_readable(root, &access.0, &id)?;
+    // Files are looked up by their path in the metadata, rather than on
+    // disk, so the path needs no further checks.
+    let path = path.collect::<Vec<_>>().join("/");
+    let (file, location) = export::find_packet_file(root, &id, &path)?;
+    let filename = path.rsplit('/').next().unwrap_or(&path).to_string();
+    OutpackFile::open_named(file.hash, location, filename, request)
+        .await
         .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+}
+
+#[rocket::get("/packet/<id>/archive?<format>&<glob>")]
+async fn get_packet_archive(
+    root: &State<String>,
+    id: String,
+    format: Option<String>,
+    glob: Option<String>,
+    access: ReadAccess,
+) -> Result<PacketArchive, OutpackError> {
+    check_readable(root, &access.0, &id)?;
+    let format = match format {
+        Some(format) => format.parse::<export::ArchiveFormat>()?,
+        None => export::ArchiveFormat::Zip,
+    };
+    let root = root.to_string();
+    let filename = format!("{}.{}", id, format.extension());
+    // Archives are written to a temporary file (which is gone as soon as
+    // it is closed) so that we can stream it back with a known length.
+    let file = rocket::tokio::task::spawn_blocking(move || -> io::Result<std::fs::File> {
+        let mut file = tempfile::tempfile()?;
+        export::write_archive(&root, &id, format, glob.as_deref(), &mut file)?;
+        file.rewind()?;
+        Ok(file)
+    })
+    .await
+    .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))??;
+    Ok(PacketArchive {
+        filename,
+        content_type: match format {
+            export::ArchiveFormat::Zip => ContentType::ZIP,
+            export::ArchiveFormat::TarGz => ContentType::GZIP,
+        },
+        file: rocket::tokio::fs::File::from_std(file),
+    })
+}
+
+#[rocket::get("/checksum?<alg>")]
+async fn get_checksum(
+    root: &State<String>,
+    alg: Option<String>,
+    access: ReadAccess,
+) -> OutpackResult<String> {
+    let digest = if access.0.is_restricted() {
+        let ids = readable_index(root, &access.0)?
+            .packets
+            .iter()
+            .map(|packet| packet.id.clone())
+            .collect();
+        metadata::get_digest_of_ids(root, ids, alg)
+    } else {
+        metadata::get_ids_digest(root, alg)
+    };
+    digest.map_err(OutpackError::from).map(OutpackSuccess::from)
 }
 
 #[rocket::post("/packets/missing", format = "json", data = "<ids>")]
 async fn get_missing_packets(
     root: &State<String>,
     ids: Result<Json<Ids>, Error<'_>>,
+    access: ReadAccess,
 ) -> OutpackResult<Vec<String>> {
     let ids = ids?;
-    metadata::get_missing_ids(root, &ids.ids, Some(ids.unpacked))
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+    let mut missing = metadata::get_missing_ids(root, &ids.ids, Some(ids.unpacked))?;
+    if access.0.is_restricted() {
+        // Packets the caller cannot read are missing as far as they are
+        // concerned.
+        let index = readable_index(root, &access.0)?;
+        let readable: HashSet<&str> = index.packets.iter().map(|p| p.id.as_str()).collect();
+        for id in &ids.ids {
+            let id = metadata::get_valid_id(id)?;
+            if !readable.contains(id.as_str()) && !missing.contains(&id) {
+                missing.push(id);
+            }
+        }
+    }
+    Ok(missing.into())
 }
 
 #[rocket::post("/files/missing", format = "json", data = "<hashes>")]
 async fn get_missing_files(
     root: &State<String>,
     hashes: Result<Json<Hashes>, Error<'_>>,
+    access: ReadAccess,
 ) -> OutpackResult<Vec<String>> {
     let hashes = hashes?;
-    store::get_missing_files(root, &hashes.hashes)
-        .map_err(OutpackError::from)
-        .map(OutpackSuccess::from)
+    let mut missing = store::get_missing_files(root, &hashes.hashes)?;
+    if access.0.is_restricted() {
+        // As with packets, files that are only in packets the caller
+        // cannot read are missing as far as they are concerned.
+        let index = readable_index(root, &access.0)?;
+        for hash in &hashes.hashes {
+            if index.packets_with_file(hash).is_empty() && !missing.contains(hash) {
+                missing.push(hash.clone());
+            }
+        }
+    }
+    Ok(missing.into())
+}
+
+#[rocket::post("/packets/query", format = "json", data = "<query>")]
+async fn query_packets(
+    root: &State<String>,
+    query: Result<Json<Query>, Error<'_>>,
+    access: ReadAccess,
+) -> OutpackResult<QueryResult> {
+    let query = query?.into_inner();
+    let context = query::QueryContext {
+        this: query.this,
+        environment: query.environment,
+    };
+    let index = readable_index(root, &access.0)?;
+    let packets =
+        query::find_packets_in(&index, &query.query, &context).map_err(OutpackError::from)?;
+    let result = if query.include_metadata {
+        QueryResult::Packets(packets.iter().map(metadata::PackitPacket::from).collect())
+    } else {
+        QueryResult::Ids(packets.into_iter().map(|packet| packet.id).collect())
+    };
+    Ok(result.into())
 }
 
 #[rocket::post("/file/<hash>", format = "binary", data = "<file>")]
@@ -134,6 +329,7 @@ async fn add_file(
     root: &State<String>,
     hash: String,
     file: TempFile<'_>,
+    _access: WriteAccess,
 ) -> Result<OutpackSuccess<()>, OutpackError> {
     store::put_file(root, file, &hash)
         .await
@@ -146,6 +342,7 @@ async fn add_packet(
     root: &State<String>,
     hash: String,
     packet: String,
+    _access: WriteAccess,
 ) -> Result<OutpackSuccess<()>, OutpackError> {
     let hash = hash.parse::<hash::Hash>().map_err(OutpackError::from)?;
     metadata::add_metadata(root, &packet, &hash)
@@ -153,6 +350,77 @@ async fn add_packet(
         .map(OutpackSuccess::from)
 }
 
+#[rocket::post("/upload/<hash>")]
+fn start_upload(
+    root: &State<String>,
+    hash: String,
+    _access: WriteAccess,
+) -> OutpackResult<upload::UploadSession> {
+    upload::start_session(root, &hash)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[rocket::get("/upload/<id>")]
+fn get_upload(
+    root: &State<String>,
+    id: String,
+    _access: WriteAccess,
+) -> OutpackResult<upload::UploadSession> {
+    upload::get_session(root, &id)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[rocket::put("/upload/<id>?<offset>", data = "<chunk>")]
+async fn put_upload_chunk(
+    root: &State<String>,
+    id: String,
+    offset: u64,
+    chunk: Data<'_>,
+    limits: &Limits,
+    _access: WriteAccess,
+) -> OutpackResult<upload::UploadSession> {
+    let limit = limits.get("upload-chunk").unwrap_or(UPLOAD_CHUNK_LIMIT);
+    let chunk = chunk.open(limit).into_bytes().await?;
+    if !chunk.is_complete() {
+        return Err(OutpackError::new(
+            ErrorCode::BadRequest,
+            format!("Chunks must be no larger than {}", limit),
+        ));
+    }
+    let root = root.to_string();
+    // Writing the chunk and syncing it to disk should not hold up the
+    // async runtime.
+    rocket::tokio::task::spawn_blocking(move || upload::write_chunk(&root, &id, offset, &chunk))
+        .await
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[rocket::post("/upload/<id>/finish")]
+async fn finish_upload(
+    root: &State<String>,
+    id: String,
+    _access: WriteAccess,
+) -> OutpackResult<()> {
+    let root = root.to_string();
+    // Finishing hashes the whole file, which may be very large.
+    rocket::tokio::task::spawn_blocking(move || upload::finish_session(&root, &id))
+        .await
+        .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
+#[rocket::delete("/upload/<id>")]
+fn abort_upload(root: &State<String>, id: String, _access: WriteAccess) -> OutpackResult<()> {
+    upload::abort_session(root, &id)
+        .map_err(OutpackError::from)
+        .map(OutpackSuccess::from)
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct Ids {
@@ -166,8 +434,39 @@ struct Hashes {
     hashes: Vec<String>,
 }
 
-pub fn check_config(config: &config::Config) -> Result<(), String> {
-    // These two are probably always constraints for using the server:
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Query {
+    query: String,
+    #[serde(default)]
+    include_metadata: bool,
+    #[serde(default)]
+    this: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    environment: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum QueryResult {
+    Ids(Vec<String>),
+    Packets(Vec<metadata::PackitPacket>),
+}
+
+/// Check that the server can serve a root with this config. Any hash
+/// algorithm will do, and files are served from the archive when there
+/// is no file store. Accepting packets needs a file store to put their
+/// files in, and a complete tree, but a read-only server needs neither.
+pub fn check_config(config: &config::Config, read_only: bool) -> Result<(), String> {
+    // Whatever we are allowed to do, we must be able to find files.
+    if !config.core.use_file_store && config.core.path_archive.is_none() {
+        return Err(String::from(
+            "Outpack must be configured to use a file store or an archive",
+        ));
+    }
+    if read_only {
+        return Ok(());
+    }
     if !config.core.use_file_store {
         return Err(String::from(
             "Outpack must be configured to use a file store",
@@ -178,35 +477,55 @@ pub fn check_config(config: &config::Config) -> Result<(), String> {
             "Outpack must be configured to require a complete tree",
         ));
     }
-    // These two we can relax over time:
-    if config.core.hash_algorithm != hash::HashAlgorithm::Sha256 {
-        return Err(format!(
-            "Outpack must be configured to use hash algorithm 'sha256', but you are using '{}'",
-            config.core.hash_algorithm
-        ));
-    }
-    if config.core.path_archive.is_some() {
-        return Err(format!(
-            "Outpack must be configured to *not* use an archive, but your path_archive is '{}'",
-            config.core.path_archive.as_ref().unwrap()
-        ));
-    }
     Ok(())
 }
 
-pub fn preflight(root_path: &str) -> Result<(), String> {
+/// Check that `root_path` can be served, returning why the server must
+/// be read-only, if it must: because `read_only` was asked for, because
+/// the root's config says so, or because the root has no file store to
+/// add files to.
+pub fn preflight(root_path: &str, read_only: bool) -> Result<Option<&'static str>, String> {
     if !Path::new(&root_path).join(".outpack").exists() {
         return Err(format!("Outpack root not found at '{}'", root_path));
     }
     let config = config::read_config(root_path)
         .map_err(|e| format!("Failed to read outpack config from '{}': {}", root_path, e))?;
-    check_config(&config)
+    let reason = if read_only {
+        Some("it was asked to be")
+    } else if config.server.as_ref().is_some_and(|s| s.read_only) {
+        Some("the root's config says so")
+    } else if !config.core.use_file_store {
+        Some("the root has no file store")
+    } else {
+        None
+    };
+    check_config(&config, reason.is_some())?;
+    Ok(reason)
 }
 
-fn api_build(root: &str) -> Rocket<Build> {
+/// How a server is run, beyond the root it serves.
+#[derive(Default)]
+pub struct ServerOptions {
+    pub auth: Auth,
+    /// Reject every request that would change the repository.
+    pub read_only: bool,
+}
+
+fn api_build(root: &str, options: ServerOptions) -> Rocket<Build> {
     rocket::build()
         .manage(String::from(root))
-        .register("/", catchers![internal_error, not_found, bad_request])
+        .manage(options.auth)
+        .manage(ReadOnly(options.read_only))
+        .register(
+            "/",
+            catchers![
+                internal_error,
+                not_found,
+                bad_request,
+                unauthorized,
+                forbidden
+            ],
+        )
         .mount(
             "/",
             routes![
@@ -216,18 +535,46 @@ fn api_build(root: &str) -> Rocket<Build> {
                 get_metadata_by_id,
                 get_metadata_raw,
                 get_file,
+                get_packet_file,
+                get_packet_archive,
                 get_checksum,
                 get_missing_packets,
                 get_missing_files,
+                query_packets,
                 add_file,
-                add_packet
+                add_packet,
+                start_upload,
+                get_upload,
+                put_upload_chunk,
+                finish_upload,
+                abort_upload
             ],
         )
 }
 
 pub fn api(root: &str) -> Result<Rocket<Build>, String> {
-    preflight(root)?;
-    Ok(api_build(root))
+    api_with_options(root, ServerOptions::default())
+}
+
+pub fn api_with_options(root: &str, options: ServerOptions) -> Result<Rocket<Build>, String> {
+    let reason = preflight(root, options.read_only)?;
+    let rocket = api_build(
+        root,
+        ServerOptions {
+            read_only: reason.is_some(),
+            ..options
+        },
+    );
+    Ok(match reason {
+        // Logged once the server is up, as that is when Rocket's logging
+        // starts, so that nobody is surprised by refused writes.
+        Some(reason) => rocket.attach(AdHoc::on_liftoff("Read-only", move |_| {
+            Box::pin(async move {
+                rocket::warn!("Serving read-only, as {}", reason);
+            })
+        })),
+        None => rocket,
+    })
 }
 
 #[cfg(test)]
@@ -246,39 +593,54 @@ mod tests {
             path_archive,
             use_file_store,
             require_complete_tree,
+            extra: serde_json::Map::new(),
         };
-        config::Config { location, core }
+        config::Config {
+            location,
+            core,
+            server: None,
+            extra: serde_json::Map::new(),
+        }
     }
 
     #[test]
     fn can_validate_config() {
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha1, None, true, true));
-        assert_eq!(res,
-                   Err(String::from("Outpack must be configured to use hash algorithm 'sha256', but you are using 'sha1'")));
+        let config = make_config(hash::HashAlgorithm::Sha256, None, false, true);
+        let err = Err(String::from(
+            "Outpack must be configured to use a file store or an archive",
+        ));
+        assert_eq!(check_config(&config, false), err);
+        assert_eq!(check_config(&config, true), err);
 
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha256, None, false, true));
+        let config = make_config(
+            hash::HashAlgorithm::Sha256,
+            Some(String::from("archive")),
+            false,
+            true,
+        );
         assert_eq!(
-            res,
+            check_config(&config, false),
             Err(String::from(
                 "Outpack must be configured to use a file store"
             ))
         );
+        assert_eq!(check_config(&config, true), Ok(()));
 
-        let res = check_config(&make_config(hash::HashAlgorithm::Sha256, None, true, false));
+        let config = make_config(hash::HashAlgorithm::Sha256, None, true, false);
         assert_eq!(
-            res,
+            check_config(&config, false),
             Err(String::from(
                 "Outpack must be configured to require a complete tree"
             ))
         );
+        assert_eq!(check_config(&config, true), Ok(()));
 
-        let res = check_config(&make_config(
-            hash::HashAlgorithm::Sha256,
+        let config = make_config(
+            hash::HashAlgorithm::Sha1,
             Some(String::from("archive")),
             true,
             true,
-        ));
-        assert_eq!(res,
-                   Err(String::from("Outpack must be configured to *not* use an archive, but your path_archive is 'archive'")));
+        );
+        assert_eq!(check_config(&config, false), Ok(()));
     }
 }
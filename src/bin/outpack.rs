@@ -1,34 +1,403 @@
-extern crate core;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use std::{env, io};
 
-use getopts::Options;
-use std::env;
+use outpack::config::{Location, LocationKind};
+use outpack::gc::{collect_garbage, GcOptions};
+use outpack::query::QueryContext;
+use outpack::verify::VerifyOptions;
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
-    print!("{}", opts.usage(&brief));
+#[derive(Parser, Debug)]
+#[command(
+    name = "outpack",
+    version,
+    about = "Interact with an outpack repository"
+)]
+struct Cli {
+    /// Path to the outpack root. By default this is found by searching
+    /// upwards from the current directory.
+    #[arg(short, long, global = true)]
+    root: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
 }
 
-fn parse_args(args: &[String]) -> Option<String> {
-    let program = args[0].clone();
-    let mut opts = Options::new();
-    opts.reqopt("r", "root", "outpack root path (required)", ".");
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => {
-            print_usage(&program, opts);
-            panic!("{}", f.to_string())
-        }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a new outpack repository
+    Init {
+        /// Where to create the repository (default: --root, or the
+        /// current directory)
+        path: Option<PathBuf>,
+        /// Store packets in an archive at this path, relative to the root
+        #[arg(long)]
+        path_archive: Option<String>,
+        /// Store files in a content-addressed file store
+        #[arg(long)]
+        use_file_store: bool,
+        /// Require that all dependencies of a packet are present
+        #[arg(long)]
+        require_complete_tree: bool,
+    },
+
+    /// Find packets matching a query
+    Query {
+        query: String,
+        /// Parse the query and print its syntax tree without running it
+        #[arg(long)]
+        parse_only: bool,
+        /// Value for a this: lookup (can be repeated)
+        #[arg(long = "this", value_name = "KEY=VALUE", value_parser = parse_binding)]
+        this: Vec<(String, Value)>,
+        /// Value for an environment: lookup (can be repeated)
+        #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_binding)]
+        environment: Vec<(String, Value)>,
+    },
+
+    /// Serve the repository over HTTP
+    Serve {
+        /// An auth config, listing tokens and/or a JWT public key
+        #[arg(long)]
+        auth: Option<PathBuf>,
+        /// Reject every request that would change the repository
+        #[arg(long)]
+        read_only: bool,
+    },
+
+    /// List the packets in the repository
+    List,
+
+    /// Print the metadata of a packet
+    Show { id: String },
+
+    /// List the files in a packet
+    Files { id: String },
+
+    /// Check the repository for corrupt, missing and orphaned objects
+    Verify {
+        /// Also check all metadata against the outpack metadata schema
+        #[arg(long)]
+        schema: bool,
+    },
+
+    /// Remove files from the store that no packet refers to
+    Gc {
+        /// Report unreferenced files without removing them
+        #[arg(long)]
+        dry_run: bool,
+        /// Only remove files older than this many seconds (default: one day)
+        #[arg(long, value_name = "SECONDS")]
+        grace_period: Option<u64>,
+    },
+
+    /// Manage locations
+    Location {
+        #[command(subcommand)]
+        command: LocationCommand,
+    },
+
+    /// Pull packets, along with everything they depend on, from a location
+    Pull {
+        location: String,
+        /// Only pull packets matching this query (default: all packets
+        /// available at the location)
+        #[arg(long)]
+        query: Option<String>,
+    },
+
+    /// Push packets matching a query, along with everything they depend
+    /// on, to a location
+    Push { location: String, query: String },
+
+    /// Copy a packet's files out of the root into a directory
+    Export {
+        id: String,
+        /// The directory to copy the files into
+        #[arg(long)]
+        dest: PathBuf,
+        /// Only copy files whose paths match this glob
+        #[arg(long)]
+        glob: Option<String>,
+    },
+
+    /// Print a shell completion script
+    Completions { shell: Shell },
+}
+
+#[derive(Subcommand, Debug)]
+enum LocationCommand {
+    /// List the configured locations
+    List,
+    /// Add a location
+    Add {
+        name: String,
+        #[command(subcommand)]
+        kind: NewLocation,
+    },
+    /// Remove a location
+    Remove { name: String },
+    /// Rename a location
+    Rename { old: String, new: String },
+}
+
+#[derive(Subcommand, Debug)]
+enum NewLocation {
+    /// Another outpack root on disk
+    Path { path: PathBuf },
+    /// Another outpack root, served by outpack_server
+    Http { url: String },
+}
+
+// Values that parse as JSON numbers, booleans or strings are used as
+// such (so `--this year=2020` gives a number); anything else is taken
+// as a literal string.
+fn parse_binding(arg: &str) -> Result<(String, Value), String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid binding '{}', expected key=value", arg))?;
+    let value = match serde_json::from_str(value) {
+        Ok(value @ (Value::Number(_) | Value::Bool(_) | Value::String(_))) => value,
+        _ => Value::String(String::from(value)),
     };
-    Some(matches.opt_str("r").unwrap())
+    Ok((String::from(key), value))
+}
+
+fn root_str(root: &Path) -> Result<&str, Box<dyn Error>> {
+    root.to_str()
+        .ok_or_else(|| format!("Root path '{}' is not valid unicode", root.display()).into())
+}
+
+fn find_root(root: Option<PathBuf>) -> Result<PathBuf, Box<dyn Error>> {
+    match root {
+        Some(root) => Ok(root),
+        None => Ok(outpack::root::find_root(&env::current_dir()?)?),
+    }
+}
+
+fn location(root: &str, command: LocationCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        LocationCommand::List => {
+            for location in outpack::config::read_config(root)?.location {
+                match location.kind {
+                    LocationKind::Local => println!("{}\tlocal", location.name),
+                    LocationKind::Path { path } => println!("{}\tpath\t{}", location.name, path),
+                    LocationKind::Http { url } => println!("{}\thttp\t{}", location.name, url),
+                    LocationKind::Other { kind, args } => {
+                        println!("{}\t{}\t{}", location.name, kind, args)
+                    }
+                }
+            }
+        }
+        LocationCommand::Add { name, kind } => {
+            let kind = match kind {
+                NewLocation::Path { path } => {
+                    // Relative paths on the command line are relative
+                    // to the working directory, not the root, so make
+                    // the stored path absolute.
+                    let path = env::current_dir()?.join(path);
+                    LocationKind::Path {
+                        path: root_str(&path)?.to_string(),
+                    }
+                }
+                NewLocation::Http { url } => LocationKind::Http { url },
+            };
+            outpack::location::add_location(root, Location::new(&name, kind))?;
+        }
+        LocationCommand::Remove { name } => outpack::location::remove_location(root, &name)?,
+        LocationCommand::Rename { old, new } => {
+            outpack::location::rename_location(root, &old, &new)?
+        }
+    }
+    Ok(())
 }
 
-fn main() {
-    let args = env::args().collect::<Vec<_>>();
-    let root = parse_args(&args);
-    if let Some(root_path) = root {
-        let _cfg = outpack::config::read_config(&root_path).unwrap_or_else(|error| {
-            panic!("Could not open outpack root at {}: {:?}", root_path, error);
-        });
-        println!("Root '{}' was opened successfully", root_path);
+fn run(cli: Cli) -> Result<ExitCode, Box<dyn Error>> {
+    // Commands that do not act on an existing root.
+    match cli.command {
+        Command::Init {
+            path,
+            path_archive,
+            use_file_store,
+            require_complete_tree,
+        } => {
+            let path = match path.or(cli.root) {
+                Some(path) => path,
+                None => env::current_dir()?,
+            };
+            outpack::init::outpack_init(
+                root_str(&path)?,
+                path_archive,
+                use_file_store,
+                require_complete_tree,
+            )?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Query {
+            ref query,
+            parse_only: true,
+            ..
+        } => {
+            println!("{:?}", outpack::query::parse_query(query)?);
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "outpack", &mut io::stdout());
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => {}
+    }
+
+    let root = find_root(cli.root)?;
+    let root = root_str(&root)?;
+    match cli.command {
+        Command::Query {
+            query,
+            this,
+            environment,
+            ..
+        } => {
+            let context = QueryContext {
+                this: this.into_iter().collect::<HashMap<_, _>>(),
+                environment: environment.into_iter().collect::<HashMap<_, _>>(),
+            };
+            println!("{}", outpack::query::run_query(root, &query, &context)?);
+        }
+        Command::Serve { auth, read_only } => {
+            let auth = match auth {
+                Some(path) => outpack::auth::Auth::read(&path)?,
+                None => outpack::auth::Auth::none(),
+            };
+            let options = outpack::api::ServerOptions { auth, read_only };
+            let api = outpack::api::api_with_options(root, options)?;
+            rocket::execute(api.launch())?;
+        }
+        Command::List => {
+            for packet in outpack::index::read_packets(root)?.iter() {
+                println!("{}\t{}", packet.id, packet.name);
+            }
+        }
+        Command::Show { id } => {
+            println!("{}", outpack::metadata::get_metadata_text(root, &id)?);
+        }
+        Command::Files { id } => {
+            for file in outpack::metadata::get_packet(root, &id)?.files {
+                println!("{}\t{}\t{}", file.path, file.size, file.hash);
+            }
+        }
+        Command::Verify { schema } => {
+            let options = VerifyOptions { schema };
+            let report = outpack::verify::verify_with_options(root, &options)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Gc {
+            dry_run,
+            grace_period,
+        } => {
+            let mut options = GcOptions {
+                dry_run,
+                ..Default::default()
+            };
+            if let Some(seconds) = grace_period {
+                options.grace_period = Duration::from_secs(seconds);
+            }
+            let report = collect_garbage(root, &options)?;
+            println!(
+                "Found {} unreferenced files ({} bytes)",
+                report.unreferenced_files, report.unreferenced_size
+            );
+            let verb = if dry_run { "Would remove" } else { "Removed" };
+            println!(
+                "{} {} files ({} bytes)",
+                verb, report.removed_files, report.removed_size
+            );
+            println!("{} {} abandoned uploads", verb, report.abandoned_uploads);
+        }
+        Command::Location { command } => location(root, command)?,
+        Command::Pull { location, query } => {
+            let report = outpack::pull::pull(root, &location, query.as_deref())?;
+            println!("Pulled metadata for {} packets", report.metadata);
+            println!(
+                "Unpacked {} packets ({} files)",
+                report.packets.len(),
+                report.files
+            );
+        }
+        Command::Push { location, query } => {
+            let report = outpack::push::push(root, &location, &query)?;
+            println!(
+                "Pushed {} packets ({} files)",
+                report.packets.len(),
+                report.files
+            );
+        }
+        Command::Export { id, dest, glob } => {
+            let files = outpack::export::export_packet(root, &id, &dest, glob.as_deref())?;
+            println!("Exported {} files to {}", files.len(), dest.display());
+        }
+        Command::Init { .. } | Command::Completions { .. } => unreachable!(),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn can_parse_bindings() {
+        assert_eq!(
+            parse_binding("year=2020"),
+            Ok((String::from("year"), Value::from(2020)))
+        );
+        assert_eq!(
+            parse_binding("flag=true"),
+            Ok((String::from("flag"), Value::from(true)))
+        );
+        assert_eq!(
+            parse_binding("region=north"),
+            Ok((String::from("region"), Value::from("north")))
+        );
+        assert_eq!(
+            parse_binding(r#"region="2020""#),
+            Ok((String::from("region"), Value::from("2020")))
+        );
+        assert_eq!(
+            parse_binding("expr=a=b"),
+            Ok((String::from("expr"), Value::from("a=b")))
+        );
+        assert!(parse_binding("region").is_err());
+    }
+
+    #[test]
+    fn root_is_global() {
+        let cli = Cli::parse_from(["outpack", "list", "--root", "path"]);
+        assert_eq!(cli.root, Some(PathBuf::from("path")));
+        let cli = Cli::parse_from(["outpack", "-r", "path", "gc", "--dry-run"]);
+        assert_eq!(cli.root, Some(PathBuf::from("path")));
     }
 }
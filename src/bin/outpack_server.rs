@@ -1,15 +1,36 @@
 use getopts::Options;
+use outpack::api::ServerOptions;
+use outpack::auth::Auth;
 use std::env;
+use std::path::Path;
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn parse_args(args: &[String]) -> Option<String> {
+#[derive(Debug, PartialEq)]
+struct Args {
+    root: String,
+    auth: Option<String>,
+    read_only: bool,
+}
+
+fn parse_args(args: &[String]) -> Option<Args> {
     let program = args[0].clone();
     let mut opts = Options::new();
     opts.reqopt("r", "root", "outpack root path (required)", ".");
+    opts.optopt(
+        "a",
+        "auth",
+        "auth config, listing tokens and/or a JWT public key",
+        "auth.json",
+    );
+    opts.optflag(
+        "",
+        "read-only",
+        "reject every request that would change the repository",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -17,12 +38,25 @@ fn parse_args(args: &[String]) -> Option<String> {
             panic!("{}", f.to_string())
         }
     };
-    Some(matches.opt_str("r").unwrap())
+    Some(Args {
+        root: matches.opt_str("r").unwrap(),
+        auth: matches.opt_str("a"),
+        read_only: matches.opt_present("read-only"),
+    })
 }
 
 #[allow(unused_must_use)]
-async fn start_app(root_path: &str) -> Result<(), rocket::Error> {
-    match outpack::api::api(root_path) {
+async fn start_app(
+    root_path: &str,
+    auth_path: Option<&str>,
+    read_only: bool,
+) -> Result<(), rocket::Error> {
+    let auth = match auth_path {
+        Some(path) => Auth::read(Path::new(path))
+            .unwrap_or_else(|e| panic!("Failed to read auth config '{}': {}", path, e)),
+        None => Auth::none(),
+    };
+    match outpack::api::api_with_options(root_path, ServerOptions { auth, read_only }) {
         Err(error) => {
             panic!("{}", error);
         }
@@ -37,9 +71,8 @@ async fn start_app(root_path: &str) -> Result<(), rocket::Error> {
 #[allow(unused_must_use)]
 async fn main() -> Result<(), rocket::Error> {
     let args = env::args().collect::<Vec<_>>();
-    let root = parse_args(&args);
-    if let Some(root_path) = root {
-        start_app(&root_path).await;
+    if let Some(args) = parse_args(&args) {
+        start_app(&args.root, args.auth.as_deref(), args.read_only).await;
     }
     Ok(())
 }
@@ -50,21 +83,33 @@ mod tests {
 
     #[test]
     fn can_parse_args() {
-        let root = parse_args(&[
+        let args = parse_args(&[
             String::from("program"),
             String::from("--root"),
             String::from("test"),
         ])
         .unwrap();
-        assert_eq!(root, "test");
+        assert_eq!(args.root, "test");
+        assert_eq!(args.auth, None);
+        assert!(!args.read_only);
 
-        let root = parse_args(&[
+        let args = parse_args(&[
             String::from("program"),
             String::from("-r"),
             String::from("test"),
+            String::from("--auth"),
+            String::from("auth.json"),
+            String::from("--read-only"),
         ])
         .unwrap();
-        assert_eq!(root, "test");
+        assert_eq!(
+            args,
+            Args {
+                root: String::from("test"),
+                auth: Some(String::from("auth.json")),
+                read_only: true
+            }
+        );
     }
 
     #[test]
@@ -77,6 +122,6 @@ mod tests {
     #[should_panic]
     #[allow(unused_must_use)]
     async fn panics_if_outpack_not_found() {
-        start_app("badpath").await;
+        start_app("badpath", None, false).await;
     }
 }
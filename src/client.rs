@@ -0,0 +1,377 @@
+//! A client for the HTTP API served by `outpack_server`.
+//!
+//! Every route has a typed method here; failures, whether reported by
+//! the server or from failing to reach it at all, are returned as an
+//! [`OutpackError`] with the same `error` and `detail` the server sent.
+
+use reqwest::blocking::{Body, RequestBuilder, Response};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+use crate::api::ApiRoot;
+use crate::hash;
+use crate::location::LocationEntry;
+use crate::metadata::PackitPacket;
+use crate::query::QueryContext;
+use crate::responses::{FailResponse, OutpackError, SuccessResponse};
+use crate::upload::UploadSession;
+
+pub type ClientResult<T> = Result<T, OutpackError>;
+
+/// The size of the chunks that [`Client::upload_file_in_chunks`] sends.
+pub const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+// How many times a chunk is sent before giving up on the upload.
+const UPLOAD_CHUNK_ATTEMPTS: usize = 3;
+
+pub struct Client {
+    url: String,
+    token: Option<String>,
+    client: reqwest::blocking::Client,
+}
+
+#[derive(Serialize)]
+struct MissingPackets<'a> {
+    ids: &'a [String],
+    unpacked: bool,
+}
+
+#[derive(Serialize)]
+struct MissingFiles<'a> {
+    hashes: &'a [String],
+}
+
+#[derive(Serialize)]
+struct Query<'a> {
+    query: &'a str,
+    include_metadata: bool,
+    this: &'a HashMap<String, Value>,
+    environment: &'a HashMap<String, Value>,
+}
+
+fn client_error(e: reqwest::Error) -> OutpackError {
+    OutpackError::from(io::Error::new(ErrorKind::Other, e.to_string()))
+}
+
+// Turn an unsuccessful response into the error the server reported,
+// or, if the body is not an outpack error (e.g., from a proxy in front
+// of the server), one describing the status.
+fn response_error(response: Response) -> OutpackError {
+    let status = response.status();
+    let url = response.url().to_string();
+    let kind = match status {
+        StatusCode::NOT_FOUND => ErrorKind::NotFound,
+        StatusCode::BAD_REQUEST => ErrorKind::InvalidInput,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Other,
+    };
+    let reported = response
+        .json::<FailResponse>()
+        .ok()
+        .and_then(|body| body.errors)
+        .and_then(|errors| errors.into_iter().next());
+    match reported {
+        Some(error) => OutpackError {
+            kind: Some(kind),
+            ..error
+        },
+        None => OutpackError::from(io::Error::new(
+            kind,
+            format!("Request to '{}' failed with status {}", url, status),
+        )),
+    }
+}
+
+impl Client {
+    /// A client for the server at `url`, e.g., `http://localhost:8000`.
+    pub fn new(url: &str) -> Client {
+        Client {
+            url: String::from(url.trim_end_matches('/')),
+            token: None,
+            client: reqwest::blocking::Client::new(),
+        }
+    }
+
+    /// Send `token` as a bearer token with every request, for servers
+    /// that require authentication.
+    pub fn with_token(mut self, token: &str) -> Client {
+        self.token = Some(String::from(token));
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn authorise(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn send(&self, request: RequestBuilder) -> ClientResult<Response> {
+        let response = self.authorise(request).send().map_err(client_error)?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(response_error(response))
+        }
+    }
+
+    fn get(&self, path: &str) -> ClientResult<Response> {
+        self.send(self.client.get(format!("{}{}", self.url, path)))
+    }
+
+    fn get_data<T: DeserializeOwned>(&self, path: &str) -> ClientResult<T> {
+        self.send_data(self.client.get(format!("{}{}", self.url, path)))
+    }
+
+    fn send_data<T: DeserializeOwned>(&self, request: RequestBuilder) -> ClientResult<T> {
+        let body: SuccessResponse<T> = self.send(request)?.json().map_err(client_error)?;
+        Ok(body.data)
+    }
+
+    fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> ClientResult<T> {
+        self.send_data(self.client.post(format!("{}{}", self.url, path)).json(body))
+    }
+
+    fn post_body(&self, path: &str, content_type: &str, body: Body) -> ClientResult<()> {
+        let request = self
+            .client
+            .post(format!("{}{}", self.url, path))
+            .header(CONTENT_TYPE, content_type)
+            .body(body);
+        self.send(request)?;
+        Ok(())
+    }
+
+    /// `GET /`
+    pub fn root(&self) -> ClientResult<ApiRoot> {
+        self.get_data("/")
+    }
+
+    /// `GET /checksum`, using the server's hash algorithm unless `alg`
+    /// is given.
+    pub fn checksum(&self, alg: Option<&str>) -> ClientResult<String> {
+        match alg {
+            Some(alg) => self.get_data(&format!("/checksum?alg={}", alg)),
+            None => self.get_data("/checksum"),
+        }
+    }
+
+    /// `GET /metadata/list`
+    pub fn list_location_metadata(&self) -> ClientResult<Vec<LocationEntry>> {
+        self.get_data("/metadata/list")
+    }
+
+    /// `GET /packit/metadata`, optionally only for packets that the
+    /// server learned about since the unix time `known_since`.
+    pub fn packit_metadata(&self, known_since: Option<f64>) -> ClientResult<Vec<PackitPacket>> {
+        match known_since {
+            Some(time) => self.get_data(&format!("/packit/metadata?known_since={}", time)),
+            None => self.get_data("/packit/metadata"),
+        }
+    }
+
+    /// `GET /metadata/<id>/json`
+    pub fn metadata_json(&self, id: &str) -> ClientResult<Value> {
+        self.get_data(&format!("/metadata/{}/json", id))
+    }
+
+    /// `GET /metadata/<id>/text`: the metadata exactly as stored on the
+    /// server, so that it can be checked against its hash.
+    pub fn metadata_text(&self, id: &str) -> ClientResult<String> {
+        self.get(&format!("/metadata/{}/text", id))?
+            .text()
+            .map_err(client_error)
+    }
+
+    /// `GET /file/<hash>`, as a stream. The contents are not checked
+    /// against the hash; see [`Client::download_file`] for that.
+    pub fn file(&self, hash: &str) -> ClientResult<impl Read + Send> {
+        self.get(&format!("/file/{}", hash))
+    }
+
+    /// `GET /file/<hash>`, written to `dest`. The file only appears at
+    /// `dest` once its contents have been checked against the hash.
+    pub fn download_file(&self, hash: &str, dest: &Path) -> ClientResult<()> {
+        let expected: hash::Hash = hash.parse()?;
+        let mut response = self.file(hash)?;
+        let dir = match dest.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)?;
+        let found = hash::copy_and_hash(&mut response, &mut file, expected.algorithm)?;
+        hash::validate_hash(&found, &expected)?;
+        file.persist(dest).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// `POST /packets/missing`: those of `ids` the server does not have
+    /// metadata for or, if `unpacked` is true, has not unpacked.
+    pub fn missing_packets(&self, ids: &[String], unpacked: bool) -> ClientResult<Vec<String>> {
+        self.post_json("/packets/missing", &MissingPackets { ids, unpacked })
+    }
+
+    /// `POST /files/missing`: those of `hashes` not in the server's store.
+    pub fn missing_files(&self, hashes: &[String]) -> ClientResult<Vec<String>> {
+        self.post_json("/files/missing", &MissingFiles { hashes })
+    }
+
+    /// `POST /packets/query`, returning the ids of matching packets.
+    pub fn query(&self, query: &str, context: &QueryContext) -> ClientResult<Vec<String>> {
+        let body = Query {
+            query,
+            include_metadata: false,
+            this: &context.this,
+            environment: &context.environment,
+        };
+        self.post_json("/packets/query", &body)
+    }
+
+    /// `POST /packets/query`, returning the metadata of matching packets.
+    pub fn query_metadata(
+        &self,
+        query: &str,
+        context: &QueryContext,
+    ) -> ClientResult<Vec<PackitPacket>> {
+        let body = Query {
+            query,
+            include_metadata: true,
+            this: &context.this,
+            environment: &context.environment,
+        };
+        self.post_json("/packets/query", &body)
+    }
+
+    /// `POST /file/<hash>`, streaming the contents from `contents`.
+    pub fn upload_file<R: Read + Send + 'static>(
+        &self,
+        hash: &str,
+        contents: R,
+    ) -> ClientResult<()> {
+        self.post_body(
+            &format!("/file/{}", hash),
+            "application/octet-stream",
+            Body::new(contents),
+        )
+    }
+
+    /// `POST /packet/<hash>`; the packet's files and dependencies must
+    /// already be on the server.
+    pub fn upload_packet(&self, hash: &str, metadata: &str) -> ClientResult<()> {
+        self.post_body(
+            &format!("/packet/{}", hash),
+            "text/plain",
+            Body::from(metadata.to_string()),
+        )
+    }
+
+    /// `POST /upload/<hash>`: start a session for uploading a file in
+    /// chunks.
+    pub fn start_upload(&self, hash: &str) -> ClientResult<UploadSession> {
+        self.send_data(self.client.post(format!("{}/upload/{}", self.url, hash)))
+    }
+
+    /// `GET /upload/<id>`, including which bytes have been received.
+    pub fn upload_status(&self, id: &str) -> ClientResult<UploadSession> {
+        self.get_data(&format!("/upload/{}", id))
+    }
+
+    /// `PUT /upload/<id>?offset=<offset>`
+    pub fn upload_chunk(
+        &self,
+        id: &str,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> ClientResult<UploadSession> {
+        self.send_data(self.chunk_request(id, offset, data))
+    }
+
+    fn chunk_request(&self, id: &str, offset: u64, data: Vec<u8>) -> RequestBuilder {
+        let url = format!("{}/upload/{}?offset={}", self.url, id, offset);
+        self.client
+            .put(url)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(data)
+    }
+
+    // Send a chunk, saying along with any failure whether it is worth
+    // sending again: only if the server couldn't be reached or failed
+    // itself, and not if it rejected the chunk.
+    fn try_upload_chunk(
+        &self,
+        id: &str,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), (OutpackError, bool)> {
+        let request = self.authorise(self.chunk_request(id, offset, data));
+        let response = request.send().map_err(|e| (client_error(e), true))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err((response_error(response), status.is_server_error()))
+        }
+    }
+
+    /// `POST /upload/<id>/finish`: move the uploaded file into the
+    /// server's store, once every byte has been received.
+    pub fn finish_upload(&self, id: &str) -> ClientResult<()> {
+        self.send_data(
+            self.client
+                .post(format!("{}/upload/{}/finish", self.url, id)),
+        )
+    }
+
+    /// `DELETE /upload/<id>`
+    pub fn abort_upload(&self, id: &str) -> ClientResult<()> {
+        self.send_data(self.client.delete(format!("{}/upload/{}", self.url, id)))
+    }
+
+    /// Upload a file through an upload session, `chunk_size` bytes at a
+    /// time, so that a dropped connection only costs a chunk rather than
+    /// the whole file: each chunk is tried a few times before giving up,
+    /// unless the server rejects it.
+    pub fn upload_file_in_chunks<R: Read>(
+        &self,
+        hash: &str,
+        mut contents: R,
+        chunk_size: usize,
+    ) -> ClientResult<()> {
+        let session = self.start_upload(hash)?;
+        let mut offset = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            (&mut contents)
+                .take(chunk_size as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let len = chunk.len() as u64;
+            let mut attempt = 1;
+            while let Err((e, retry)) = self.try_upload_chunk(&session.id, offset, chunk.clone()) {
+                if !retry || attempt == UPLOAD_CHUNK_ATTEMPTS {
+                    return Err(e);
+                }
+                attempt += 1;
+            }
+            offset += len;
+        }
+        self.finish_upload(&session.id)
+    }
+}
@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use jsonschema::{Draft, JSONSchema};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fs;
 use std::io::Error;
 use std::path::Path;
@@ -6,13 +8,149 @@ use std::result::Result;
 
 use crate::hash::HashAlgorithm;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Location {
-    // Practically, doing anything with locations (therefore needing
-    // access to the "type" and "args" fields) is going to require we
-    // know how to deserialise into a union type; for example
-    // https://stackoverflow.com/q/66964692
     pub name: String,
+    // Stored alongside the name as "type" and "args" fields, e.g.,
+    // {"name": "origin", "type": "path", "args": {"path": "../other"}}
+    pub kind: LocationKind,
+    /// Any arguments other than those `kind` uses, which are kept so
+    /// that they are written back as they were.
+    pub extra_args: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocationKind {
+    /// The repository itself.
+    Local,
+    /// Another outpack root on disk.
+    Path { path: String },
+    /// Another outpack root, served by outpack_server.
+    Http { url: String },
+    /// A type of location that we don't support, but which another
+    /// outpack implementation may, kept exactly as it was written.
+    Other {
+        kind: String,
+        args: serde_json::Value,
+    },
+}
+
+// A location as it is written in the config.
+#[derive(Serialize, Deserialize)]
+struct LocationFields {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    args: serde_json::Value,
+}
+
+impl Serialize for Location {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut args = serde_json::Map::new();
+        let kind = match &self.kind {
+            LocationKind::Local => "local",
+            LocationKind::Path { path } => {
+                args.insert(String::from("path"), path.clone().into());
+                "path"
+            }
+            LocationKind::Http { url } => {
+                args.insert(String::from("url"), url.clone().into());
+                "http"
+            }
+            LocationKind::Other { kind, args } => {
+                return LocationFields {
+                    name: self.name.clone(),
+                    kind: kind.clone(),
+                    args: args.clone(),
+                }
+                .serialize(serializer);
+            }
+        };
+        for (key, value) in &self.extra_args {
+            args.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        LocationFields {
+            name: self.name.clone(),
+            kind: String::from(kind),
+            args: serde_json::Value::Object(args),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Location {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Location, D::Error> {
+        let fields = LocationFields::deserialize(deserializer)?;
+        let mut args = match (fields.kind.as_str(), fields.args) {
+            ("local" | "path" | "http", serde_json::Value::Object(args)) => args,
+            // An empty list is how some other outpack implementations
+            // write locations that take no arguments.
+            ("local" | "path" | "http", serde_json::Value::Array(args)) if args.is_empty() => {
+                serde_json::Map::new()
+            }
+            ("local" | "path" | "http", _) => {
+                return Err(serde::de::Error::custom("expected args to be an object"))
+            }
+            (_, args) => {
+                return Ok(Location {
+                    name: fields.name,
+                    kind: LocationKind::Other {
+                        kind: fields.kind,
+                        args,
+                    },
+                    extra_args: serde_json::Map::new(),
+                })
+            }
+        };
+        let mut take = |key: &'static str| match args.remove(key) {
+            Some(serde_json::Value::String(value)) => Ok(value),
+            Some(_) => Err(serde::de::Error::custom(format!(
+                "expected `{}` to be a string",
+                key
+            ))),
+            None => Err(serde::de::Error::missing_field(key)),
+        };
+        let kind = match fields.kind.as_str() {
+            "path" => LocationKind::Path {
+                path: take("path")?,
+            },
+            "http" => LocationKind::Http { url: take("url")? },
+            _ => LocationKind::Local,
+        };
+        Ok(Location {
+            name: fields.name,
+            kind,
+            extra_args: args,
+        })
+    }
+}
+
+impl Location {
+    pub fn new(name: &str, kind: LocationKind) -> Location {
+        Location {
+            name: String::from(name),
+            kind,
+            extra_args: serde_json::Map::new(),
+        }
+    }
+
+    pub fn local() -> Location {
+        Location::new(LOCAL, LocationKind::Local)
+    }
+}
+
+/// The name of the location that refers to the repository itself.
+pub const LOCAL: &str = "local";
+
+lazy_static! {
+    static ref CONFIG_SCHEMA: JSONSchema = {
+        let schema = serde_json::from_str(include_str!("../schema/outpack/config.json"))
+            .expect("Valid config schema");
+        JSONSchema::options()
+            .with_draft(Draft::Draft7)
+            .compile(&schema)
+            .expect("Valid config schema")
+    };
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -21,12 +159,30 @@ pub struct Core {
     pub path_archive: Option<String>,
     pub use_file_store: bool,
     pub require_complete_tree: bool,
+    /// Settings we don't know about, kept so that they are written back.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Settings for `outpack_server`, which the outpack schema allows as
+/// an implementation-specific addition to the config.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct ServerConfig {
+    /// Reject every request that would change the repository.
+    #[serde(default)]
+    pub read_only: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Config {
     pub core: Core,
     pub location: Vec<Location>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerConfig>,
+    /// Anything else in the config, e.g. its `schema_version` or settings
+    /// for other outpack implementations, kept so that it is written back.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Config {
@@ -47,9 +203,15 @@ impl Config {
             path_archive,
             use_file_store,
             require_complete_tree,
+            extra: serde_json::Map::new(),
         };
-        let location: Vec<Location> = Vec::new();
-        Ok(Config { core, location })
+        let location = vec![Location::local()];
+        Ok(Config {
+            core,
+            location,
+            server: None,
+            extra: serde_json::Map::new(),
+        })
     }
 }
 
@@ -60,7 +222,24 @@ pub fn read_config(root_path: &str) -> Result<Config, Error> {
     Ok(config)
 }
 
+/// Check a configuration against the outpack config schema, reporting
+/// every violation found.
+pub fn validate_config(config: &Config) -> Result<(), Error> {
+    let value = serde_json::to_value(config)?;
+    if let Err(errors) = CONFIG_SCHEMA.validate(&value) {
+        let errors = errors
+            .map(|e| format!("{} (at '{}')", e, e.instance_path))
+            .collect::<Vec<String>>();
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid configuration: {}", errors.join("; ")),
+        ));
+    }
+    Ok(())
+}
+
 pub fn write_config(config: &Config, root_path: &str) -> Result<(), Error> {
+    validate_config(config)?;
     // assume .outpack exists
     let path_config = Path::new(root_path).join(".outpack").join("config.json");
     fs::File::create(&path_config)?;
@@ -94,6 +273,131 @@ mod tests {
         assert_eq!(read_config(path_str).unwrap(), cfg);
     }
 
+    #[test]
+    fn can_read_server_config() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path_str = tmp.path().to_str().unwrap();
+        fs::create_dir_all(tmp.path().join(".outpack")).unwrap();
+        let mut cfg = Config::new(None, true, true).unwrap();
+        write_config(&cfg, path_str).unwrap();
+        assert_eq!(read_config(path_str).unwrap().server, None);
+
+        cfg.server = Some(ServerConfig { read_only: true });
+        write_config(&cfg, path_str).unwrap();
+        assert_eq!(read_config(path_str).unwrap(), cfg);
+    }
+
+    #[test]
+    fn locations_are_round_tripped() {
+        let mut cfg = Config::new(None, true, true).unwrap();
+        cfg.location.push(Location::new(
+            "origin",
+            LocationKind::Path {
+                path: String::from("../other"),
+            },
+        ));
+        cfg.location.push(Location::new(
+            "server",
+            LocationKind::Http {
+                url: String::from("http://example.com"),
+            },
+        ));
+        let json = serde_json::to_value(&cfg.location).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"name": "local", "type": "local", "args": {}},
+                {"name": "origin", "type": "path", "args": {"path": "../other"}},
+                {"name": "server", "type": "http", "args": {"url": "http://example.com"}}
+            ])
+        );
+
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path_str = tmp.path().to_str().unwrap();
+        fs::create_dir_all(tmp.path().join(".outpack")).unwrap();
+        write_config(&cfg, path_str).unwrap();
+        assert_eq!(read_config(path_str).unwrap(), cfg);
+    }
+
+    #[test]
+    fn can_read_location_types() {
+        let location: Location =
+            serde_json::from_str(r#"{"name": "local", "type": "local", "args": []}"#).unwrap();
+        assert_eq!(location, Location::local());
+        let res =
+            serde_json::from_str::<Location>(r#"{"name": "origin", "type": "path", "args": {}}"#);
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .contains("missing field `path`"));
+        let res =
+            serde_json::from_str::<Location>(r#"{"name": "origin", "type": "path", "args": 1}"#);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn unknown_location_types_and_args_are_kept() {
+        let json = serde_json::json!([
+            {"name": "s3", "type": "custom", "args": {"bucket": "b", "n": [1]}},
+            {"name": "origin", "type": "path", "args": {"path": "../other", "ssh": true}}
+        ]);
+        let locations: Vec<Location> = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(
+            locations[0].kind,
+            LocationKind::Other {
+                kind: String::from("custom"),
+                args: serde_json::json!({"bucket": "b", "n": [1]}),
+            }
+        );
+        assert_eq!(
+            locations[1].kind,
+            LocationKind::Path {
+                path: String::from("../other")
+            }
+        );
+        assert_eq!(locations[1].extra_args["ssh"], true);
+        assert_eq!(serde_json::to_value(&locations).unwrap(), json);
+    }
+
+    #[test]
+    fn unknown_fields_are_written_back() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path_str = tmp.path().to_str().unwrap();
+        fs::create_dir_all(tmp.path().join(".outpack")).unwrap();
+        let text = fs::read_to_string("tests/example/.outpack/config.json").unwrap();
+        let mut json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        json["orderly"] = serde_json::json!({"minimum_version": "1.99.0"});
+        let config_path = tmp.path().join(".outpack").join("config.json");
+        fs::write(&config_path, json.to_string()).unwrap();
+
+        let cfg = read_config(path_str).unwrap();
+        write_config(&cfg, path_str).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(written["schema_version"], "0.0.1");
+        assert_eq!(written["orderly"], json["orderly"]);
+        assert_eq!(written["location"][1], json["location"][1]);
+    }
+
+    #[test]
+    fn config_is_validated_against_schema() {
+        let mut cfg = Config::new(None, true, true).unwrap();
+        assert!(validate_config(&cfg).is_ok());
+        cfg.core.path_archive = Some(String::from("archive"));
+        assert!(validate_config(&cfg).is_ok());
+
+        // Our types cannot represent a config that breaks the schema,
+        // so check the schema itself catches what we expect.
+        let bad = serde_json::json!({"core": {"use_file_store": true}, "location": [{}]});
+        let errors = CONFIG_SCHEMA
+            .validate(&bad)
+            .unwrap_err()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert!(errors.contains(&String::from("\"path_archive\" is a required property")));
+        assert!(errors.contains(&String::from("\"name\" is a required property")));
+    }
+
     #[test]
     fn need_some_storage() {
         let cfg = Config::new(None, false, false);
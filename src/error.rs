@@ -0,0 +1,217 @@
+//! Errors with a stable code, so that callers (and clients of the
+//! server) can tell failures apart without matching on their messages.
+//!
+//! Most of the crate works in terms of `io::Error`, so an `Error` is
+//! carried inside one; `code_of` recovers the code again, and anything
+//! without one is classified by its `io::ErrorKind`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::hash::{HashError, HashErrorKind};
+use crate::query::QueryError;
+
+// Each code is listed once, with the string it goes by, and the enum,
+// its serde names, `as_str` and `ALL_CODES` are all made from that.
+macro_rules! error_codes {
+    ($($code:ident => $name:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+        pub enum ErrorCode {
+            $(
+                #[serde(rename = $name)]
+                $code,
+            )*
+        }
+
+        const ALL_CODES: &[ErrorCode] = &[$(ErrorCode::$code,)*];
+
+        impl ErrorCode {
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(ErrorCode::$code => $name,)*
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    HashMismatch => "HASH_MISMATCH",
+    InvalidHash => "INVALID_HASH",
+    PacketNotFound => "PACKET_NOT_FOUND",
+    FileNotFound => "FILE_NOT_FOUND",
+    MissingDependencies => "MISSING_DEPENDENCIES",
+    MissingFiles => "MISSING_FILES",
+    InvalidPacketId => "INVALID_PACKET_ID",
+    InvalidMetadata => "INVALID_METADATA",
+    QueryParseError => "QUERY_PARSE_ERROR",
+    QueryEvalError => "QUERY_EVAL_ERROR",
+    BadRequest => "BAD_REQUEST",
+    NotFound => "NOT_FOUND",
+    Unauthorized => "UNAUTHORIZED",
+    Forbidden => "FORBIDDEN",
+    UnknownError => "UNKNOWN_ERROR",
+}
+
+impl ErrorCode {
+    /// The closest `io::ErrorKind`, used when the error has to travel
+    /// as an `io::Error`.
+    pub fn io_kind(&self) -> io::ErrorKind {
+        match self {
+            ErrorCode::PacketNotFound | ErrorCode::FileNotFound | ErrorCode::NotFound => {
+                io::ErrorKind::NotFound
+            }
+            ErrorCode::Unauthorized | ErrorCode::Forbidden => io::ErrorKind::PermissionDenied,
+            ErrorCode::UnknownError => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidInput,
+        }
+    }
+
+    /// The code for an error that was not raised with one.
+    pub fn from_kind(kind: io::ErrorKind) -> ErrorCode {
+        match kind {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => ErrorCode::BadRequest,
+            io::ErrorKind::PermissionDenied => ErrorCode::Forbidden,
+            _ => ErrorCode::UnknownError,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ErrorCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ErrorCode, ()> {
+        ALL_CODES
+            .iter()
+            .find(|code| code.as_str() == s)
+            .copied()
+            .ok_or(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub detail: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> Error {
+        Error {
+            code,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.detail)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(e.code.io_kind(), e)
+    }
+}
+
+impl From<HashError> for Error {
+    fn from(e: HashError) -> Self {
+        let code = match e.kind {
+            HashErrorKind::HashesDontMatch => ErrorCode::HashMismatch,
+            HashErrorKind::FileReadFailed => ErrorCode::UnknownError,
+            HashErrorKind::InvalidHashFormat
+            | HashErrorKind::InvalidHashAlgorithm
+            | HashErrorKind::InvalidExpectedHash => ErrorCode::InvalidHash,
+        };
+        Error::new(code, e.explanation)
+    }
+}
+
+impl From<HashError> for io::Error {
+    fn from(e: HashError) -> Self {
+        io::Error::from(Error::from(e))
+    }
+}
+
+impl From<QueryError> for Error {
+    fn from(e: QueryError) -> Self {
+        let code = match e {
+            QueryError::ParseError(_) => ErrorCode::QueryParseError,
+            QueryError::EvalError(_) => ErrorCode::QueryEvalError,
+        };
+        Error::new(code, e.to_string())
+    }
+}
+
+/// The code an `io::Error` was raised with, if any.
+pub fn code_of(e: &io::Error) -> Option<ErrorCode> {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<Error>())
+        .map(|inner| inner.code)
+}
+
+/// The code an `io::Error` was raised with, or else the one that best
+/// fits its kind.
+pub fn error_code(e: &io::Error) -> ErrorCode {
+    code_of(e).unwrap_or_else(|| ErrorCode::from_kind(e.kind()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash;
+
+    #[test]
+    fn codes_round_trip_through_strings() {
+        for &code in ALL_CODES {
+            assert_eq!(code.as_str().parse::<ErrorCode>(), Ok(code));
+            assert_eq!(
+                serde_json::to_value(code).unwrap(),
+                serde_json::Value::String(code.to_string())
+            );
+        }
+        assert!("invalid input".parse::<ErrorCode>().is_err());
+    }
+
+    #[test]
+    fn code_survives_conversion_to_io_error() {
+        let e = io::Error::from(Error::new(ErrorCode::PacketNotFound, "no such packet"));
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert_eq!(e.to_string(), "no such packet");
+        assert_eq!(code_of(&e), Some(ErrorCode::PacketNotFound));
+    }
+
+    #[test]
+    fn plain_io_errors_are_classified_by_kind() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_eq!(code_of(&e), None);
+        assert_eq!(error_code(&e), ErrorCode::NotFound);
+        let e = io::Error::new(io::ErrorKind::InvalidData, "bad");
+        assert_eq!(error_code(&e), ErrorCode::UnknownError);
+    }
+
+    #[test]
+    fn hash_errors_have_codes() {
+        let e = io::Error::from("md5:zz".parse::<Hash>().unwrap_err());
+        assert_eq!(code_of(&e), Some(ErrorCode::InvalidHash));
+        let e = io::Error::from(
+            crate::hash::validate_hash_data(b"data", "md5:00000000000000000000000000000000")
+                .unwrap_err(),
+        );
+        assert_eq!(code_of(&e), Some(ErrorCode::HashMismatch));
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,306 @@
+//! Getting a packet's files back out of a root, either onto disk or as
+//! a single archive, laid out by their paths in the packet's metadata.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+use tempfile::NamedTempFile;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::config;
+use crate::error::{Error, ErrorCode};
+use crate::metadata::{self, Packet, PacketFile};
+use crate::store::packet_file_path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<ArchiveFormat> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            _ => Err(Error::new(
+                ErrorCode::BadRequest,
+                format!(
+                    "Unsupported archive format '{}', expected 'zip' or 'tar.gz'",
+                    s
+                ),
+            )
+            .into()),
+        }
+    }
+}
+
+// A packet's metadata, provided it is unpacked, so its files are here.
+fn unpacked_packet(root: &str, id: &str) -> io::Result<Packet> {
+    let packet = metadata::get_packet(root, id)?;
+    if !metadata::get_missing_ids(root, &[String::from(id)], Some(true))?.is_empty() {
+        return Err(Error::new(
+            ErrorCode::PacketNotFound,
+            format!("Packet '{}' is not unpacked", id),
+        )
+        .into());
+    }
+    Ok(packet)
+}
+
+// A packet's files, with where each is kept in the root, optionally
+// only those whose paths match `glob`.
+fn packet_files(
+    root: &str,
+    id: &str,
+    glob: Option<&str>,
+) -> io::Result<(Packet, Vec<(PacketFile, PathBuf)>)> {
+    let packet = unpacked_packet(root, id)?;
+    let pattern = match glob {
+        Some(glob) => Some(glob::Pattern::new(glob).map_err(|e| {
+            Error::new(
+                ErrorCode::BadRequest,
+                format!("Invalid glob '{}': {}", glob, e),
+            )
+        })?),
+        None => None,
+    };
+    let core = config::read_config(root)?.core;
+    let mut files = Vec::new();
+    for file in &packet.files {
+        if pattern.as_ref().map_or(true, |p| p.matches(&file.path)) {
+            let path = packet_file_path(root, &core, &packet, file)?;
+            files.push((file.clone(), path));
+        }
+    }
+    Ok((packet, files))
+}
+
+/// Find a file in a packet by its path, returning it along with where
+/// it is kept in the root.
+pub fn find_packet_file(root: &str, id: &str, path: &str) -> io::Result<(PacketFile, PathBuf)> {
+    let packet = unpacked_packet(root, id)?;
+    let file = packet.file(path).ok_or_else(|| {
+        Error::new(
+            ErrorCode::FileNotFound,
+            format!("Packet '{}' has no file '{}'", id, path),
+        )
+    })?;
+    let core = config::read_config(root)?.core;
+    let location = packet_file_path(root, &core, &packet, file)?;
+    Ok((file.clone(), location))
+}
+
+/// Write an archive of a packet's files (or those matching `glob`).
+pub fn write_archive<W: Write + Seek>(
+    root: &str,
+    id: &str,
+    format: ArchiveFormat,
+    glob: Option<&str>,
+    writer: W,
+) -> io::Result<()> {
+    let (_, files) = packet_files(root, id, glob)?;
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new(writer);
+            for (file, path) in files {
+                let options = FileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(file.size as u64 >= u32::MAX as u64);
+                zip.start_file(&file.path, options)?;
+                io::copy(&mut fs::File::open(path)?, &mut zip)?;
+            }
+            zip.finish()?;
+        }
+        ArchiveFormat::TarGz => {
+            let mut tar = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            for (file, path) in files {
+                tar.append_path_with_name(path, &file.path)?;
+            }
+            tar.into_inner()?.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a packet's files (or those matching `glob`) into `dest`, laid
+/// out by their paths in the metadata, returning the paths copied.
+pub fn export_packet(
+    root: &str,
+    id: &str,
+    dest: &Path,
+    glob: Option<&str>,
+) -> io::Result<Vec<String>> {
+    let (_, files) = packet_files(root, id, glob)?;
+    let mut exported = Vec::new();
+    for (file, path) in files {
+        let target = dest.join(&file.path);
+        let dir = target.parent().unwrap();
+        fs::create_dir_all(dir)?;
+        // Copy to a temporary file first, so that an interrupted export
+        // does not leave truncated files behind.
+        let mut temp = NamedTempFile::new_in(dir)?;
+        io::copy(&mut fs::File::open(path)?, &mut temp)?;
+        temp.persist(&target).map_err(|e| e.error)?;
+        exported.push(file.path);
+    }
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::tests::{create_packet, get_empty_outpack_root};
+    use std::io::Read;
+
+    const ID: &str = "20230101-000000-00000001";
+
+    fn create_root() -> PathBuf {
+        let root = get_empty_outpack_root(None, true, true);
+        create_packet(
+            &root,
+            "data",
+            ID,
+            &[
+                ("data.csv", "a,b\n1,2\n"),
+                ("plots/a.png", "not really a png"),
+                ("plots/b.png", "nor this"),
+            ],
+            &[],
+        );
+        root
+    }
+
+    #[test]
+    fn can_parse_format() {
+        assert_eq!("zip".parse::<ArchiveFormat>().unwrap(), ArchiveFormat::Zip);
+        assert_eq!(
+            "tar.gz".parse::<ArchiveFormat>().unwrap(),
+            ArchiveFormat::TarGz
+        );
+        let err = "rar".parse::<ArchiveFormat>().unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::BadRequest));
+        assert_eq!(
+            err.to_string(),
+            "Unsupported archive format 'rar', expected 'zip' or 'tar.gz'"
+        );
+    }
+
+    #[test]
+    fn can_export_packet() {
+        let root = create_root();
+        let dest = tempfile::tempdir().unwrap();
+        let files = export_packet(root.to_str().unwrap(), ID, dest.path(), None).unwrap();
+        assert_eq!(files, ["data.csv", "plots/a.png", "plots/b.png"]);
+        assert_eq!(
+            fs::read_to_string(dest.path().join("plots/a.png")).unwrap(),
+            "not really a png"
+        );
+
+        let dest = tempfile::tempdir().unwrap();
+        let files =
+            export_packet(root.to_str().unwrap(), ID, dest.path(), Some("plots/*")).unwrap();
+        assert_eq!(files, ["plots/a.png", "plots/b.png"]);
+        assert!(!dest.path().join("data.csv").exists());
+    }
+
+    #[test]
+    fn can_write_zip_archive() {
+        let root = create_root();
+        let mut file = tempfile::tempfile().unwrap();
+        write_archive(
+            root.to_str().unwrap(),
+            ID,
+            ArchiveFormat::Zip,
+            None,
+            &mut file,
+        )
+        .unwrap();
+        let mut zip = zip::ZipArchive::new(file).unwrap();
+        assert_eq!(zip.len(), 3);
+        let mut contents = String::new();
+        zip.by_name("data.csv")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "a,b\n1,2\n");
+    }
+
+    #[test]
+    fn can_write_tar_gz_archive() {
+        let root = create_root();
+        let mut file = tempfile::tempfile().unwrap();
+        let root = root.to_str().unwrap();
+        write_archive(root, ID, ArchiveFormat::TarGz, Some("*.csv"), &mut file).unwrap();
+        file.rewind().unwrap();
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let paths: Vec<String> = tar
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(paths, ["data.csv"]);
+    }
+
+    #[test]
+    fn can_find_packet_file() {
+        let root = create_root();
+        let root = root.to_str().unwrap();
+        let (file, path) = find_packet_file(root, ID, "plots/b.png").unwrap();
+        assert_eq!(file.size, 8);
+        assert_eq!(fs::read_to_string(path).unwrap(), "nor this");
+        assert_eq!(
+            find_packet_file(root, ID, "plots").unwrap_err().to_string(),
+            format!("Packet '{}' has no file 'plots'", ID)
+        );
+    }
+
+    #[test]
+    fn packet_must_be_unpacked() {
+        let root = create_root();
+        let local = root.join(".outpack/location/local").join(ID);
+        fs::remove_file(local).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        let root = root.to_str().unwrap();
+        let err = export_packet(root, ID, dest.path(), None).unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::PacketNotFound));
+        assert_eq!(err.to_string(), format!("Packet '{}' is not unpacked", ID));
+        let err = find_packet_file(root, ID, "data.csv").unwrap_err();
+        assert_eq!(err.to_string(), format!("Packet '{}' is not unpacked", ID));
+    }
+
+    #[test]
+    fn file_paths_must_stay_within_destination() {
+        let root = get_empty_outpack_root(None, true, true);
+        create_packet(&root, "data", ID, &[("../escape.txt", "x")], &[]);
+        let dest = tempfile::tempdir().unwrap();
+        let err = export_packet(root.to_str().unwrap(), ID, dest.path(), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Packet '{}' has a file with invalid path 'data/../escape.txt'",
+                ID
+            )
+        );
+        let err = find_packet_file(root.to_str().unwrap(), ID, "../escape.txt").unwrap_err();
+        assert_eq!(
+            crate::error::code_of(&err),
+            Some(ErrorCode::InvalidMetadata)
+        );
+    }
+}
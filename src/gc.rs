@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+
+use crate::error::{Error, ErrorCode};
+use crate::{index, store, upload};
+
+/// Files younger than this are never removed, as they may belong to a
+/// packet whose metadata has not been uploaded yet.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    pub dry_run: bool,
+    pub grace_period: Duration,
+}
+
+impl Default for GcOptions {
+    fn default() -> GcOptions {
+        GcOptions {
+            dry_run: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct GcReport {
+    /// Number and total size in bytes of files in the store that are
+    /// not referenced by any packet.
+    pub unreferenced_files: usize,
+    pub unreferenced_size: u64,
+    /// Number and total size in bytes of unreferenced files that are
+    /// older than the grace period, and so were (or, in a dry run,
+    /// would have been) removed.
+    pub removed_files: usize,
+    pub removed_size: u64,
+    /// Number of upload sessions not used within the grace period, and
+    /// so removed (or, in a dry run, that would have been).
+    pub abandoned_uploads: usize,
+}
+
+pub fn get_referenced_hashes(root: &str) -> io::Result<HashSet<String>> {
+    let index = index::get_packet_index(root)?;
+    // We can't tell which files a packet we can't read refers to, so
+    // must not guess that they are unused.
+    if !index.unreadable().is_empty() {
+        let problems: Vec<&str> = index.unreadable().values().map(|p| p.as_str()).collect();
+        return Err(Error::new(
+            ErrorCode::InvalidMetadata,
+            format!(
+                "Can't tell which files are in use, as metadata can't be read:\n{}",
+                problems.join("\n")
+            ),
+        )
+        .into());
+    }
+    Ok(index
+        .packets
+        .iter()
+        .flat_map(|packet| packet.files.iter().map(|f| f.hash.clone()))
+        .collect())
+}
+
+/// Find files in the store that are not referenced by the metadata of
+/// any packet, and remove those older than the grace period, along with
+/// any upload sessions abandoned for that long.
+pub fn collect_garbage(root: &str, options: &GcOptions) -> io::Result<GcReport> {
+    let referenced = get_referenced_hashes(root)?;
+    let now = SystemTime::now();
+    let mut report = GcReport::default();
+
+    for hash in store::enumerate_files(root)? {
+        if referenced.contains(&hash) {
+            continue;
+        }
+        // A file that has gone, or that we cannot look at, is left for
+        // another run rather than stopping this one.
+        let path = store::file_path(root, &hash)?;
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        report.unreferenced_files += 1;
+        report.unreferenced_size += metadata.len();
+
+        // A modification time in the future (or none at all) counts as
+        // recent.
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or(Duration::ZERO);
+        if age < options.grace_period {
+            continue;
+        }
+        if !options.dry_run {
+            if fs::remove_file(&path).is_err() {
+                continue;
+            }
+            // Tidy up the prefix directory if that was its last file;
+            // this fails harmlessly if it is not yet empty.
+            let _ = fs::remove_dir(path.parent().unwrap());
+        }
+        report.removed_files += 1;
+        report.removed_size += metadata.len();
+    }
+
+    report.abandoned_uploads = upload::clean_sessions(root, options.grace_period, options.dry_run)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::test_utils::tests::get_temp_outpack_root;
+
+    // The example store holds a single file that no packet refers to.
+    const ORPHAN: &str = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+
+    fn now_options(dry_run: bool) -> GcOptions {
+        GcOptions {
+            dry_run,
+            grace_period: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_without_removing() {
+        let root = get_temp_outpack_root();
+        let root = root.to_str().unwrap();
+        let report = collect_garbage(root, &now_options(true)).unwrap();
+        assert_eq!(report.unreferenced_files, 1);
+        assert_eq!(report.unreferenced_size, 115);
+        assert_eq!(report.removed_files, 1);
+        assert_eq!(report.removed_size, 115);
+        assert!(store::file_exists(root, ORPHAN).unwrap());
+    }
+
+    #[test]
+    fn removes_unreferenced_files() {
+        let root = get_temp_outpack_root();
+        let root = root.to_str().unwrap();
+        let report = collect_garbage(root, &now_options(false)).unwrap();
+        assert_eq!(report.removed_files, 1);
+        assert!(!store::file_exists(root, ORPHAN).unwrap());
+
+        let report = collect_garbage(root, &now_options(false)).unwrap();
+        assert_eq!(report, GcReport::default());
+    }
+
+    #[test]
+    fn refuses_to_run_with_unreadable_metadata() {
+        let root = get_temp_outpack_root();
+        let id = "20180818-164043-7cdcde4b";
+        fs::write(root.join(".outpack").join("metadata").join(id), "{").unwrap();
+        let root = root.to_str().unwrap();
+        let err = collect_garbage(root, &now_options(false)).unwrap_err();
+        assert!(err.to_string().contains(id));
+        assert!(store::file_exists(root, ORPHAN).unwrap());
+    }
+
+    #[test]
+    fn keeps_files_within_grace_period() {
+        let root = get_temp_outpack_root();
+        let root = root.to_str().unwrap();
+        let data = "Testing 123.";
+        let hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256).to_string();
+        let path = store::file_path(root, &hash).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+
+        // Only the file unpacked from the example, which keeps its
+        // original modification time, is old enough to go.
+        let report = collect_garbage(root, &GcOptions::default()).unwrap();
+        assert_eq!(report.unreferenced_files, 2);
+        assert_eq!(report.removed_files, 1);
+        assert!(!store::file_exists(root, ORPHAN).unwrap());
+        assert!(store::file_exists(root, &hash).unwrap());
+    }
+
+    #[test]
+    fn keeps_referenced_files() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        let metadata = format!(
+            r#"{{"schema_version": "0.1.1", "name": "data", "id": "20230427-150828-68772cee",
+                "time": {{"start": 1682608108.4139, "end": 1682608108.4309}},
+                "parameters": null,
+                "files": [{{"path": "data.csv", "size": 51, "hash": "{}"}}],
+                "depends": [], "custom": null, "git": null}}"#,
+            ORPHAN
+        );
+        fs::write(
+            root.join(".outpack")
+                .join("metadata")
+                .join("20230427-150828-68772cee"),
+            metadata,
+        )
+        .unwrap();
+
+        let report = collect_garbage(root_str, &now_options(false)).unwrap();
+        assert_eq!(report, GcReport::default());
+        assert!(store::file_exists(root_str, ORPHAN).unwrap());
+    }
+
+    #[test]
+    fn ignores_files_not_named_for_a_hash() {
+        let root = get_temp_outpack_root();
+        let root = root.to_str().unwrap();
+        let prefix = store::file_path(root, ORPHAN).unwrap();
+        let prefix = prefix.parent().unwrap();
+        let stray = prefix.join(".tmpAbc123");
+        fs::write(&stray, "partial").unwrap();
+        fs::create_dir_all(prefix.join("subdir")).unwrap();
+
+        let report = collect_garbage(root, &now_options(false)).unwrap();
+        assert_eq!(report.unreferenced_files, 1);
+        assert_eq!(report.removed_files, 1);
+        assert!(stray.exists());
+    }
+
+    #[test]
+    fn removes_abandoned_uploads() {
+        let root = get_temp_outpack_root();
+        let root = root.to_str().unwrap();
+        let session = upload::start_session(root, ORPHAN).unwrap();
+
+        let report = collect_garbage(root, &GcOptions::default()).unwrap();
+        assert_eq!(report.abandoned_uploads, 0);
+        let report = collect_garbage(root, &now_options(true)).unwrap();
+        assert_eq!(report.abandoned_uploads, 1);
+        assert!(upload::get_session(root, &session.id).is_ok());
+        let report = collect_garbage(root, &now_options(false)).unwrap();
+        assert_eq!(report.abandoned_uploads, 1);
+        assert!(upload::get_session(root, &session.id).is_err());
+    }
+}
@@ -4,6 +4,9 @@ use serde::{Deserialize, Serialize};
 use sha1::Digest;
 use std::fmt;
 use std::fmt::LowerHex;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
 #[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
@@ -49,11 +52,6 @@ impl From<std::io::Error> for HashError {
     }
 }
 
-// Helper for the reverse, this is not pretty and will go away later.
-pub fn hash_error_to_io_error(e: HashError) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.explanation.clone())
-}
-
 impl fmt::Display for HashAlgorithm {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let s = match self {
@@ -117,20 +115,94 @@ fn hex_string<T: LowerHex>(digest: T) -> String {
     format!("{:x}", digest)
 }
 
+/// Incremental hashing, so that large files can be hashed without
+/// holding them in memory.
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha384(sha2::Sha384),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Sha384 => Hasher::Sha384(sha2::Sha384::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(h) => h.consume(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha384(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Hash {
+        let (algorithm, value) = match self {
+            Hasher::Md5(h) => (HashAlgorithm::Md5, hex_string(h.compute())),
+            Hasher::Sha1(h) => (HashAlgorithm::Sha1, hex_string(h.finalize())),
+            Hasher::Sha256(h) => (HashAlgorithm::Sha256, hex_string(h.finalize())),
+            Hasher::Sha384(h) => (HashAlgorithm::Sha384, hex_string(h.finalize())),
+            Hasher::Sha512(h) => (HashAlgorithm::Sha512, hex_string(h.finalize())),
+        };
+        Hash { algorithm, value }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 pub fn hash_data(data: &[u8], algorithm: HashAlgorithm) -> Hash {
-    let value: String = match algorithm {
-        HashAlgorithm::Md5 => hex_string(md5::compute(data)),
-        HashAlgorithm::Sha1 => hex_string(sha1::Sha1::new().chain_update(data).finalize()),
-        HashAlgorithm::Sha256 => hex_string(sha2::Sha256::new().chain_update(data).finalize()),
-        HashAlgorithm::Sha384 => hex_string(sha2::Sha384::new().chain_update(data).finalize()),
-        HashAlgorithm::Sha512 => hex_string(sha2::Sha512::new().chain_update(data).finalize()),
-    };
-    Hash { algorithm, value }
+    let mut hasher = Hasher::new(algorithm);
+    hasher.update(data);
+    hasher.finalize()
 }
 
-pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<Hash, std::io::Error> {
-    let bytes = std::fs::read(path)?;
-    Ok(hash_data(&bytes, algorithm))
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<Hash, io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Hasher::new(algorithm);
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+/// Copy everything from `reader` to `writer`, hashing the data on the
+/// way through, so that it only needs to be read once.
+pub fn copy_and_hash<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    algorithm: HashAlgorithm,
+) -> io::Result<Hash> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+    }
+    writer.flush()?;
+    Ok(hasher.finalize())
 }
 
 pub fn validate_hash(found: &Hash, expected: &Hash) -> Result<(), HashError> {
@@ -149,7 +221,6 @@ pub fn validate_hash_data(data: &[u8], expected: &str) -> Result<(), HashError>
     validate_hash(&hash_data(data, expected.algorithm), &expected)
 }
 
-// This is not yet a streaming hash, which can be done!
 pub fn validate_hash_file(path: &Path, expected: &str) -> Result<(), HashError> {
     let expected: Hash = expected.parse()?;
     validate_hash(&hash_file(path, expected.algorithm)?, &expected)
@@ -283,6 +354,38 @@ mod tests {
             )));
     }
 
+    #[test]
+    fn incremental_hash_matches_hash_of_data() {
+        let data = "Hello World!".repeat(1000);
+        for algorithm in [
+            HashAlgorithm::Md5,
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ] {
+            let mut hasher = Hasher::new(algorithm);
+            for chunk in data.as_bytes().chunks(7) {
+                hasher.update(chunk);
+            }
+            assert_eq!(
+                hasher.finalize(),
+                hash_data(data.as_bytes(), algorithm),
+                "{}",
+                algorithm
+            );
+        }
+    }
+
+    #[test]
+    fn can_copy_and_hash() {
+        let data = "Hello World!".repeat(10000);
+        let mut copy = Vec::new();
+        let hash = copy_and_hash(&mut data.as_bytes(), &mut copy, HashAlgorithm::Sha256).unwrap();
+        assert_eq!(copy, data.as_bytes());
+        assert_eq!(hash, hash_data(data.as_bytes(), HashAlgorithm::Sha256));
+    }
+
     #[test]
     fn can_hash_file() {
         use std::io::Write;
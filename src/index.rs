@@ -1,19 +1,345 @@
-use crate::metadata::{get_metadata_from_date, Packet};
-use std::io;
+use crate::metadata::{read_metadata_file, Packet};
+use crate::utils;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::{fs, io};
+use tempfile::NamedTempFile;
+
+// Bump this whenever the layout of the on-disk index changes; an index
+// written with any other version is discarded and rebuilt from scratch.
+const INDEX_VERSION: u32 = 2;
+
+lazy_static! {
+    // The last index read for each root, keyed by root path, so that we
+    // only need to go to disk for packets that have changed. The lock is
+    // only held to swap snapshots in and out, never while reading files.
+    static ref INDEX_CACHE: Mutex<HashMap<PathBuf, Arc<CachedIndex>>> = Mutex::new(HashMap::new());
+}
+
+// What we know about a metadata file when we read it; if either differs
+// the file has been rewritten and must be read again.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct FileStamp {
+    modified: SystemTime,
+    size: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileStamp {
+            modified: metadata.modified()?,
+            size: metadata.len(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    #[serde(flatten)]
+    stamp: FileStamp,
+    metadata: Packet,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    packets: Vec<IndexEntry>,
+}
+
+struct CachedIndex {
+    stamps: HashMap<String, FileStamp>,
+    index: Arc<Index>,
+}
 
 #[derive(Clone)]
 pub struct Index {
-    pub packets: Vec<Packet>,
+    pub packets: Arc<Vec<Packet>>,
+    // The dependency graph, stored in both directions and keyed by
+    // packet id, so that it can be walked either way cheaply.
+    dependencies: HashMap<String, Vec<String>>,
+    dependents: HashMap<String, Vec<String>>,
+    // Ids of the packets containing each file, keyed by hash.
+    files: HashMap<String, Vec<String>>,
+    unreadable: BTreeMap<String, String>,
+}
+
+impl Index {
+    pub fn new(packets: impl Into<Arc<Vec<Packet>>>) -> Index {
+        let packets = packets.into();
+        let mut dependencies: HashMap<String, Vec<String>> = HashMap::new();
+        let mut dependents: HashMap<String, Vec<String>> = HashMap::new();
+        let mut files: HashMap<String, Vec<String>> = HashMap::new();
+        for packet in packets.iter() {
+            for file in &packet.files {
+                files
+                    .entry(file.hash.clone())
+                    .or_default()
+                    .push(packet.id.clone());
+            }
+            for dependency in &packet.depends {
+                dependencies
+                    .entry(packet.id.clone())
+                    .or_default()
+                    .push(dependency.packet.clone());
+                dependents
+                    .entry(dependency.packet.clone())
+                    .or_default()
+                    .push(packet.id.clone());
+            }
+        }
+        Index {
+            packets,
+            dependencies,
+            dependents,
+            files,
+            unreadable: BTreeMap::new(),
+        }
+    }
+
+    /// Ids of the packets that the packet `id` depends on directly.
+    pub fn dependencies(&self, id: &str) -> &[String] {
+        self.dependencies.get(id).map_or(&[], |ids| ids.as_slice())
+    }
+
+    /// Ids of the packets that depend directly on the packet `id`.
+    pub fn dependents(&self, id: &str) -> &[String] {
+        self.dependents.get(id).map_or(&[], |ids| ids.as_slice())
+    }
+
+    /// An index of only the packets for which `keep` is true, as though
+    /// the others were not there at all.
+    pub fn restrict(&self, keep: impl Fn(&Packet) -> bool) -> Index {
+        Index::new(
+            self.packets
+                .iter()
+                .filter(|packet| keep(packet))
+                .cloned()
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Packets whose metadata could not be read, which are left out of
+    /// the index, along with why, keyed by id.
+    pub fn unreadable(&self) -> &BTreeMap<String, String> {
+        &self.unreadable
+    }
+
+    /// Ids of the packets that contain a file with `hash`.
+    pub fn packets_with_file(&self, hash: &str) -> &[String] {
+        self.files.get(hash).map_or(&[], |ids| ids.as_slice())
+    }
+
+    pub fn packet(&self, id: &str) -> Option<&Packet> {
+        self.packets
+            .binary_search_by(|packet| packet.id.as_str().cmp(id))
+            .ok()
+            .map(|i| &self.packets[i])
+    }
+}
+
+fn index_path(root_path: &Path) -> PathBuf {
+    root_path
+        .join(".outpack")
+        .join("index")
+        .join("packets.json")
+}
+
+fn read_index_file(root_path: &Path) -> Vec<IndexEntry> {
+    // A missing, unreadable or out-of-date index is not an error: we
+    // just start again and build it from the metadata directory.
+    fs::File::open(index_path(root_path))
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, IndexFile>(io::BufReader::new(file)).ok())
+        .filter(|index| index.version == INDEX_VERSION)
+        .map(|index| index.packets)
+        .unwrap_or_default()
+}
+
+fn write_index_file(root_path: &Path, cached: &CachedIndex) -> io::Result<()> {
+    let path = index_path(root_path);
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let index = IndexFile {
+        version: INDEX_VERSION,
+        packets: cached
+            .index
+            .packets
+            .iter()
+            .map(|packet| IndexEntry {
+                stamp: cached.stamps[&packet.id],
+                metadata: packet.clone(),
+            })
+            .collect(),
+    };
+    let mut file = NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, &index)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn save_index(root_path: &Path, cached: &CachedIndex) {
+    // The index is only a cache of the metadata directory, so failing to
+    // write it (e.g., a root mounted read-only) should not stop us from
+    // serving packets; we will simply try again next time.
+    let _ = write_index_file(root_path, cached);
+}
+
+fn load_index_file(root_path: &Path) -> CachedIndex {
+    let mut entries = read_index_file(root_path);
+    entries.sort_by(|a, b| a.metadata.id.cmp(&b.metadata.id));
+    let stamps = entries
+        .iter()
+        .map(|entry| (entry.metadata.id.clone(), entry.stamp))
+        .collect();
+    let packets: Vec<Packet> = entries.into_iter().map(|entry| entry.metadata).collect();
+    CachedIndex {
+        stamps,
+        index: Arc::new(Index::new(packets)),
+    }
+}
+
+// Compare `previous` with the metadata directory, returning a new index
+// if any packet has been added, removed or rewritten since. Packets that
+// are unchanged are reused rather than read again, as is `added`, a
+// packet that the caller has just written. One packet with metadata we
+// cannot read is left out, rather than taking the whole index with it.
+fn refresh(
+    root_path: &Path,
+    previous: &CachedIndex,
+    added: Option<&Packet>,
+) -> io::Result<Option<CachedIndex>> {
+    let metadata_path = root_path.join(".outpack").join("metadata");
+    let mut on_disk = fs::read_dir(&metadata_path)?
+        .filter_map(|e| e.ok())
+        .filter(|e| utils::is_packet(&e.file_name()))
+        .filter_map(|e| {
+            // A file removed since we listed the directory is skipped,
+            // as though we had never seen it.
+            let stamp = FileStamp::of(&e.path()).ok()?;
+            Some((e.file_name().into_string().ok()?, stamp))
+        })
+        .collect::<Vec<(String, FileStamp)>>();
+
+    let unchanged = on_disk.len() == previous.stamps.len()
+        && on_disk
+            .iter()
+            .all(|(id, stamp)| previous.stamps.get(id) == Some(stamp));
+    if unchanged {
+        return Ok(None);
+    }
+
+    on_disk.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut packets = Vec::with_capacity(on_disk.len());
+    let mut unreadable = BTreeMap::new();
+    for (id, stamp) in &on_disk {
+        let same = previous.stamps.get(id) == Some(stamp);
+        if let Some(problem) = previous.index.unreadable.get(id).filter(|_| same) {
+            unreadable.insert(id.clone(), problem.clone());
+            continue;
+        }
+        let known = previous.index.packet(id).filter(|_| same);
+        let packet = match (known, added) {
+            (Some(packet), _) => packet.clone(),
+            (None, Some(packet)) if &packet.id == id => packet.clone(),
+            (None, _) => {
+                let path = metadata_path.join(id);
+                match read_metadata_file(&path) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        let problem = format!(
+                            "Failed to read metadata for packet '{}' from '{}': {}",
+                            id,
+                            path.display(),
+                            e
+                        );
+                        unreadable.insert(id.clone(), problem);
+                        continue;
+                    }
+                }
+            }
+        };
+        packets.push(packet);
+    }
+    let mut index = Index::new(packets);
+    index.unreadable = unreadable;
+    Ok(Some(CachedIndex {
+        stamps: on_disk.into_iter().collect(),
+        index: Arc::new(index),
+    }))
+}
+
+fn update(root_path: &str, added: Option<&Packet>) -> io::Result<Arc<Index>> {
+    let root_path = Path::new(root_path);
+    let cached = INDEX_CACHE.lock().unwrap().get(root_path).cloned();
+    let previous = match cached {
+        Some(cached) => cached,
+        None => Arc::new(load_index_file(root_path)),
+    };
+    let current = match refresh(root_path, &previous, added)? {
+        Some(current) => {
+            save_index(root_path, &current);
+            Arc::new(current)
+        }
+        None => previous,
+    };
+    let index = current.index.clone();
+    INDEX_CACHE
+        .lock()
+        .unwrap()
+        .insert(root_path.to_path_buf(), current);
+    Ok(index)
 }
 
-pub fn get_packet_index(root_path: &str) -> io::Result<Index> {
-    let packets = get_metadata_from_date(root_path, None)?;
-    Ok(Index { packets })
+/// All packets in the root's metadata directory, sorted by id.
+///
+/// These are read from the index stored under `.outpack/index`, which is
+/// checked against the metadata directory and brought up to date first.
+pub fn read_packets(root_path: &str) -> io::Result<Arc<Vec<Packet>>> {
+    Ok(update(root_path, None)?.packets.clone())
+}
+
+/// Ids of all packets in the root's metadata directory, sorted.
+pub fn read_packet_ids(root_path: &str) -> io::Result<Vec<String>> {
+    Ok(read_packets(root_path)?
+        .iter()
+        .map(|packet| packet.id.clone())
+        .collect())
+}
+
+/// Record a newly imported packet in the index, without reading its
+/// metadata back from disk.
+pub fn add_packet(root_path: &str, packet: &Packet) {
+    // As with saving, the index will catch up on the next read if this
+    // fails.
+    let _ = update(root_path, Some(packet));
+}
+
+pub fn get_packet_index(root_path: &str) -> io::Result<Arc<Index>> {
+    update(root_path, None)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::tests::get_temp_outpack_root;
+
+    fn copy_packet(root: &Path, from: &str, to: &str) {
+        let metadata = root.join(".outpack").join("metadata");
+        let text = fs::read_to_string(metadata.join(from)).unwrap();
+        fs::write(metadata.join(to), text.replace(from, to)).unwrap();
+    }
+
+    fn read_index_ids(root: &Path) -> Vec<String> {
+        read_index_file(root)
+            .into_iter()
+            .map(|entry| entry.metadata.id)
+            .collect()
+    }
 
     #[test]
     fn can_get_packet_index() {
@@ -29,4 +355,168 @@ mod tests {
         assert_eq!(ids[2], "20180220-095832-16a4bbed");
         assert_eq!(ids[3], "20180818-164043-7cdcde4b");
     }
+
+    #[test]
+    fn index_includes_dependency_graph() {
+        let index = get_packet_index("tests/example").unwrap();
+        assert_eq!(
+            index.dependencies("20180220-095832-16a4bbed"),
+            &["20170818-164043-7cdcde4b"]
+        );
+        assert_eq!(index.dependents("20180220-095832-16a4bbed").len(), 0);
+        assert_eq!(index.dependents("20170818-164043-7cdcde4b").len(), 4);
+        assert_eq!(index.dependencies("unknown").len(), 0);
+    }
+
+    #[test]
+    fn index_finds_packets_by_file() {
+        let index = get_packet_index("tests/example").unwrap();
+        let hash = "sha256:8916131d0bebabc5ab098ae3a34b03389768e1ef15acba74ea265deddeba579f";
+        assert_eq!(index.packets_with_file(hash).len(), 4);
+        let restricted = index.restrict(|packet| packet.name == "modup-201707-params1");
+        assert_eq!(
+            restricted.packets_with_file(hash),
+            &["20180220-095832-16a4bbed"]
+        );
+        assert_eq!(index.packets_with_file("sha256:abc").len(), 0);
+    }
+
+    #[test]
+    fn index_is_written_to_disk() {
+        let root = get_temp_outpack_root();
+        assert!(!index_path(&root).exists());
+        let ids = read_packet_ids(root.to_str().unwrap()).unwrap();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(read_index_ids(&root), ids);
+    }
+
+    #[test]
+    fn index_picks_up_new_and_removed_metadata() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        read_packet_ids(root_str).unwrap();
+
+        copy_packet(
+            &root,
+            "20180818-164043-7cdcde4b",
+            "20190101-000000-00000000",
+        );
+        fs::remove_file(
+            root.join(".outpack")
+                .join("metadata")
+                .join("20170818-164830-33e0ab01"),
+        )
+        .unwrap();
+
+        let expected = vec![
+            "20170818-164847-7574883b",
+            "20180220-095832-16a4bbed",
+            "20180818-164043-7cdcde4b",
+            "20190101-000000-00000000",
+        ];
+        assert_eq!(read_packet_ids(root_str).unwrap(), expected);
+        assert_eq!(read_index_ids(&root), expected);
+        let index = get_packet_index(root_str).unwrap();
+        assert_eq!(index.packets[3].id, "20190101-000000-00000000");
+    }
+
+    #[test]
+    fn stale_index_on_disk_is_rebuilt() {
+        let root = get_temp_outpack_root();
+        let packets = read_packets(root.to_str().unwrap()).unwrap();
+        let entries = packets[..2]
+            .iter()
+            .map(|packet| IndexEntry {
+                stamp: FileStamp::of(&root.join(".outpack").join("metadata").join(&packet.id))
+                    .unwrap(),
+                metadata: packet.clone(),
+            })
+            .collect();
+        let index = IndexFile {
+            version: INDEX_VERSION,
+            packets: entries,
+        };
+        fs::write(index_path(&root), serde_json::to_string(&index).unwrap()).unwrap();
+
+        // Forget the in-memory index, as a fresh process would.
+        INDEX_CACHE.lock().unwrap().remove(&root);
+        let ids = read_packet_ids(root.to_str().unwrap()).unwrap();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(read_index_ids(&root).len(), 4);
+    }
+
+    #[test]
+    fn index_with_other_version_is_ignored() {
+        let root = get_temp_outpack_root();
+        let path = index_path(&root);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, r#"{"version": 0, "packets": []}"#).unwrap();
+        assert_eq!(read_index_file(&root).len(), 0);
+        assert_eq!(read_packet_ids(root.to_str().unwrap()).unwrap().len(), 4);
+        assert_eq!(read_index_ids(&root).len(), 4);
+
+        fs::write(&path, "not json").unwrap();
+        assert_eq!(read_index_file(&root).len(), 0);
+    }
+
+    #[test]
+    fn index_picks_up_rewritten_metadata() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        let id = "20180818-164043-7cdcde4b";
+        let before = read_packets(root_str).unwrap();
+        assert_eq!(before[3].name, "modup-201707-queries1");
+
+        let path = root.join(".outpack").join("metadata").join(id);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("modup-201707-queries1", "renamed")).unwrap();
+
+        let after = read_packets(root_str).unwrap();
+        assert_eq!(after[3].name, "renamed");
+        assert_eq!(read_index_file(&root)[3].metadata.name, "renamed");
+        // Readers holding the old snapshot are unaffected.
+        assert_eq!(before[3].name, "modup-201707-queries1");
+    }
+
+    #[test]
+    fn unreadable_metadata_is_left_out() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        let id = "20180818-164043-7cdcde4b";
+        let path = root.join(".outpack").join("metadata").join(id);
+        fs::write(&path, "{").unwrap();
+
+        let index = get_packet_index(root_str).unwrap();
+        assert_eq!(index.packets.len(), 3);
+        assert!(index.packet(id).is_none());
+        let problem = &index.unreadable()[id];
+        assert!(problem.starts_with(&format!(
+            "Failed to read metadata for packet '{}' from '{}'",
+            id,
+            path.display()
+        )));
+        let err = crate::metadata::get_packet(root_str, id).unwrap_err();
+        assert_eq!(&err.to_string(), problem);
+        // Still unreadable, without reading it again
+        let again = get_packet_index(root_str).unwrap();
+        assert!(Arc::ptr_eq(&index, &again));
+
+        copy_packet(&root, "20180220-095832-16a4bbed", id);
+        let index = get_packet_index(root_str).unwrap();
+        assert_eq!(index.packets.len(), 4);
+        assert!(index.unreadable().is_empty());
+    }
+
+    #[test]
+    fn unchanged_index_is_shared() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        let first = get_packet_index(root_str).unwrap();
+        let second = get_packet_index(root_str).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(
+            &read_packets(root_str).unwrap(),
+            &first.packets
+        ));
+    }
 }
@@ -1,14 +1,25 @@
 pub mod api;
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
+pub mod error;
+pub mod export;
+pub mod gc;
 pub mod index;
 pub mod init;
+pub mod location;
+pub mod metadata;
+pub mod pull;
+pub mod push;
 pub mod query;
+pub mod responses;
+pub mod root;
+pub mod upload;
+pub mod verify;
 
 mod hash;
-mod location;
-mod metadata;
 mod outpack_file;
-mod responses;
 mod store;
 mod test_utils;
 mod utils;
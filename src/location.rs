@@ -1,4 +1,4 @@
-use crate::config::Location;
+use crate::config::{Location, LocationKind};
 use crate::utils::time_as_num;
 use cached::cached_result;
 use cached::instant::SystemTime;
@@ -98,6 +98,115 @@ pub fn mark_packet_known(
     Ok(())
 }
 
+fn check_location_kind(root: &str, kind: &LocationKind) -> io::Result<()> {
+    match kind {
+        LocationKind::Local => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            String::from("Cannot add a location of type 'local'"),
+        )),
+        LocationKind::Path { path } => {
+            // Relative paths are taken relative to the root.
+            let path = Path::new(root).join(path);
+            match path.to_str().map(config::read_config) {
+                Some(Ok(_)) => Ok(()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' is not an outpack root", path.display()),
+                )),
+            }
+        }
+        LocationKind::Http { url } => {
+            if url.starts_with("http://") || url.starts_with("https://") {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid url '{}', expected http:// or https://", url),
+                ))
+            }
+        }
+        LocationKind::Other { kind, .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported location type '{}'", kind),
+        )),
+    }
+}
+
+fn find_location(locations: &[Location], name: &str) -> io::Result<usize> {
+    locations
+        .iter()
+        .position(|l| l.name == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No location with name '{}'", name),
+            )
+        })
+}
+
+/// The configured location called `name`.
+pub fn get_location(root: &str, name: &str) -> io::Result<Location> {
+    let mut locations = config::read_config(root)?.location;
+    let i = find_location(&locations, name)?;
+    Ok(locations.swap_remove(i))
+}
+
+fn check_new_name(locations: &[Location], name: &str) -> io::Result<()> {
+    if locations.iter().any(|l| l.name == name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("A location with name '{}' already exists", name),
+        ));
+    }
+    Ok(())
+}
+
+fn check_not_local(name: &str, action: &str) -> io::Result<()> {
+    if name == config::LOCAL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot {} the '{}' location", action, config::LOCAL),
+        ));
+    }
+    Ok(())
+}
+
+pub fn add_location(root: &str, location: Location) -> io::Result<()> {
+    let mut config = config::read_config(root)?;
+    check_new_name(&config.location, &location.name)?;
+    check_location_kind(root, &location.kind)?;
+    config.location.push(location);
+    config::write_config(&config, root)
+}
+
+/// Remove a location, along with the record of which packets it has.
+pub fn remove_location(root: &str, name: &str) -> io::Result<()> {
+    check_not_local(name, "remove")?;
+    let mut config = config::read_config(root)?;
+    let i = find_location(&config.location, name)?;
+    config.location.remove(i);
+    config::write_config(&config, root)?;
+    let path = Path::new(root).join(".outpack").join("location").join(name);
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    Ok(())
+}
+
+pub fn rename_location(root: &str, old: &str, new: &str) -> io::Result<()> {
+    check_not_local(old, "rename")?;
+    let mut config = config::read_config(root)?;
+    let i = find_location(&config.location, old)?;
+    check_new_name(&config.location, new)?;
+    config.location[i].name = String::from(new);
+    config::write_config(&config, root)?;
+    let path = Path::new(root).join(".outpack").join("location");
+    if path.join(old).exists() {
+        fs::rename(path.join(old), path.join(new))?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -185,4 +294,89 @@ mod tests {
         // time known should still be the time it was first added at
         assert_eq!(res.time, time_as_num(now));
     }
+
+    fn path_location(name: &str, path: &str) -> Location {
+        Location::new(
+            name,
+            LocationKind::Path {
+                path: String::from(path),
+            },
+        )
+    }
+
+    fn location_names(root: &str) -> Vec<String> {
+        config::read_config(root)
+            .unwrap()
+            .location
+            .into_iter()
+            .map(|l| l.name)
+            .collect()
+    }
+
+    #[test]
+    fn can_add_rename_and_remove_locations() {
+        let root = get_temp_outpack_root();
+        let other = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+
+        add_location(root_str, path_location("origin", other.to_str().unwrap())).unwrap();
+        assert_eq!(location_names(root_str), ["local", "another", "origin"]);
+
+        rename_location(root_str, "another", "elsewhere").unwrap();
+        assert_eq!(location_names(root_str), ["local", "elsewhere", "origin"]);
+        let path = root.join(".outpack").join("location");
+        assert!(!path.join("another").exists());
+        assert_eq!(read_location(path.join("elsewhere")).unwrap().len(), 3);
+
+        remove_location(root_str, "elsewhere").unwrap();
+        assert_eq!(location_names(root_str), ["local", "origin"]);
+        assert!(!path.join("elsewhere").exists());
+        assert_eq!(read_locations(root_str).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn location_changes_are_validated() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+
+        let err = |res: io::Result<()>| res.unwrap_err().to_string();
+        assert_eq!(
+            err(add_location(root_str, path_location("another", root_str))),
+            "A location with name 'another' already exists"
+        );
+        assert_eq!(
+            err(add_location(
+                root_str,
+                path_location("origin", "/nonexistent")
+            )),
+            "'/nonexistent' is not an outpack root"
+        );
+        let http = Location::new(
+            "server",
+            LocationKind::Http {
+                url: String::from("example.com"),
+            },
+        );
+        assert_eq!(
+            err(add_location(root_str, http)),
+            "Invalid url 'example.com', expected http:// or https://"
+        );
+        assert_eq!(
+            err(remove_location(root_str, "local")),
+            "Cannot remove the 'local' location"
+        );
+        assert_eq!(
+            err(rename_location(root_str, "local", "other")),
+            "Cannot rename the 'local' location"
+        );
+        assert_eq!(
+            err(rename_location(root_str, "another", "local")),
+            "A location with name 'local' already exists"
+        );
+        assert_eq!(
+            err(remove_location(root_str, "missing")),
+            "No location with name 'missing'"
+        );
+        assert_eq!(location_names(root_str), ["local", "another"]);
+    }
 }
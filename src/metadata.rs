@@ -1,17 +1,20 @@
+use crate::error::{Error, ErrorCode};
 use crate::location::read_locations;
 use crate::utils::is_packet_str;
-use crate::{location, store};
-use cached::cached_result;
+use crate::{index, location, store};
+use jsonschema::{Draft, JSONSchema, SchemaResolver, SchemaResolverError};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::SystemTime;
 use std::{fs, io};
+use url::Url;
 
 use super::config;
 use super::hash;
-use super::utils;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PackitPacket {
@@ -21,7 +24,7 @@ pub struct PackitPacket {
     pub parameters: Option<HashMap<String, serde_json::Value>>,
 }
 
-impl PackitPacket {
+impl From<&Packet> for PackitPacket {
     fn from(packet: &Packet) -> PackitPacket {
         PackitPacket {
             id: packet.id.to_string(),
@@ -32,14 +35,97 @@ impl PackitPacket {
     }
 }
 
+/// A packet's metadata, as described by `schema/outpack/metadata.json`.
+///
+/// Fields that the schema does not describe (e.g. orderly's `script`)
+/// are kept in `extra`, so that metadata read into a `Packet` can be
+/// written back out without losing anything.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Packet {
+    /// Not required by the schema, so `None` if the metadata has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<String>,
     pub id: String,
     pub name: String,
-    pub custom: Option<serde_json::Value>,
     pub parameters: Option<HashMap<String, serde_json::Value>>,
+    /// As with `schema_version`, `None` if the metadata has none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<PacketTime>,
     pub files: Vec<PacketFile>,
     pub depends: Vec<PacketDependency>,
+    pub custom: Option<serde_json::Value>,
+    /// `None` if the metadata has no `git` at all, which is kept apart
+    /// from an explicit `null` so that each is written back as it was.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub git: Option<Option<GitInfo>>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Packet {
+    /// When the packet was started, in seconds since 1970-01-01, if
+    /// recorded.
+    pub fn start_time(&self) -> Option<f64> {
+        self.time.as_ref().map(|time| time.start)
+    }
+
+    /// When the packet was completed, in seconds since 1970-01-01, if
+    /// recorded.
+    pub fn end_time(&self) -> Option<f64> {
+        self.time.as_ref().map(|time| time.end)
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&serde_json::Value> {
+        self.parameters.as_ref()?.get(name)
+    }
+
+    /// The custom metadata recorded by an application, e.g. `orderly`.
+    pub fn custom(&self, application: &str) -> Option<&serde_json::Value> {
+        self.custom.as_ref()?.get(application)
+    }
+
+    fn git_info(&self) -> Option<&GitInfo> {
+        self.git.as_ref()?.as_ref()
+    }
+
+    pub fn git_sha(&self) -> Option<&str> {
+        self.git_info()?.sha.as_deref()
+    }
+
+    pub fn git_branch(&self) -> Option<&str> {
+        self.git_info()?.branch.as_deref()
+    }
+
+    /// The urls of the git remotes, if known.
+    pub fn git_url(&self) -> &[String] {
+        self.git_info()
+            .and_then(|git| git.url.as_deref())
+            .unwrap_or_default()
+    }
+
+    pub fn file(&self, path: &str) -> Option<&PacketFile> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    pub fn dependency(&self, id: &str) -> Option<&PacketDependency> {
+        self.depends
+            .iter()
+            .find(|dependency| dependency.packet == id)
+    }
+}
+
+// For a field that is `Some` whenever it is present, even as `null`;
+// with `#[serde(default)]` an absent field is then `None`.
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 impl PartialEq for Packet {
@@ -56,32 +142,74 @@ impl std::hash::Hash for Packet {
     }
 }
 
+/// When a packet was run, in seconds since 1970-01-01. Implementations
+/// may record other times alongside the start and end, which are kept
+/// in `extra`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PacketTime {
+    pub start: f64,
+    pub end: f64,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The state of the git repository a packet was run in. Every field
+/// is optional, and those that were absent stay absent when written
+/// back out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GitInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PacketFile {
-    path: String,
-    hash: String,
-    size: usize,
+    pub path: String,
+    pub hash: String,
+    pub size: usize,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PacketDependency {
-    packet: String,
-    files: Vec<DependencyFile>,
+    pub packet: String,
+    /// The query that found the packet used. This is `null` in some
+    /// older metadata, from before queries were recorded.
+    pub query: Option<String>,
+    pub files: Vec<DependencyFile>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
+impl PacketDependency {
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+}
+
+/// A file used from a dependency: `there` in the upstream packet was
+/// copied to `here` in this one.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DependencyFile {
-    here: String,
-    there: String,
+    pub here: String,
+    pub there: String,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
-cached_result! {
-    METADATA_CACHE: cached::UnboundCache<PathBuf, Packet> = cached::UnboundCache::new();
-    fn read_metadata(path: PathBuf) -> io::Result<Packet> = {
-        let file = fs::File::open(path)?;
-        let packet: Packet = serde_json::from_reader(file)?;
-        Ok(packet)
-    }
+/// Read a metadata file directly. Most callers want `get_packet`, which
+/// goes through the index instead.
+pub fn read_metadata_file(path: &Path) -> io::Result<Packet> {
+    let file = fs::File::open(path)?;
+    let packet: Packet = serde_json::from_reader(file)?;
+    Ok(packet)
 }
 
 fn get_path(root_path: &str, id: &str) -> PathBuf {
@@ -94,50 +222,80 @@ fn get_path(root_path: &str, id: &str) -> PathBuf {
 fn get_metadata_file(root_path: &str, id: &str) -> io::Result<PathBuf> {
     let path = get_path(root_path, id);
     if !path.exists() {
-        Err(io::Error::new(
-            io::ErrorKind::NotFound,
+        Err(Error::new(
+            ErrorCode::PacketNotFound,
             format!("packet with id '{}' does not exist", id),
-        ))
+        )
+        .into())
     } else {
         Ok(path)
     }
 }
 
+// All packets, for listings that must not quietly leave any out: the
+// index skips metadata it cannot read, but here that is an error.
+fn read_all_packets(root_path: &str) -> io::Result<Arc<Vec<Packet>>> {
+    let index = index::get_packet_index(root_path)?;
+    if let Some(problem) = index.unreadable().values().next() {
+        return Err(Error::new(ErrorCode::UnknownError, problem.clone()).into());
+    }
+    Ok(index.packets.clone())
+}
+
 pub fn get_packit_metadata_from_date(
     root_path: &str,
     from: Option<f64>,
 ) -> io::Result<Vec<PackitPacket>> {
-    let packets = get_metadata_from_date(root_path, from)?;
-    Ok(packets.iter().map(PackitPacket::from).collect())
+    let packets = read_all_packets(root_path)?;
+    Ok(packets_from_date(root_path, &packets, from)?
+        .into_iter()
+        .map(PackitPacket::from)
+        .collect())
 }
 
 pub fn get_metadata_from_date(root_path: &str, from: Option<f64>) -> io::Result<Vec<Packet>> {
-    let path = Path::new(root_path).join(".outpack").join("metadata");
-
-    let packets = fs::read_dir(path)?
-        .filter_map(|e| e.ok())
-        .filter(|e| utils::is_packet(&e.file_name()));
+    let packets = read_all_packets(root_path)?;
+    Ok(packets_from_date(root_path, &packets, from)?
+        .into_iter()
+        .cloned()
+        .collect())
+}
 
-    let mut packets = match from {
-        None => packets
-            .map(|entry| read_metadata(entry.path()))
-            .collect::<io::Result<Vec<Packet>>>()?,
+fn packets_from_date<'a>(
+    root_path: &str,
+    packets: &'a [Packet],
+    from: Option<f64>,
+) -> io::Result<Vec<&'a Packet>> {
+    match from {
+        None => Ok(packets.iter().collect()),
         Some(time) => {
             let location_meta = read_locations(root_path)?;
-            packets
-                .filter(|entry| {
+            Ok(packets
+                .iter()
+                .filter(|packet| {
                     location_meta
                         .iter()
-                        .find(|&e| e.packet == entry.file_name().into_string().unwrap())
-                        .map_or(false, |e| e.time > time)
+                        .find(|&e| e.packet == packet.id)
+                        .is_some_and(|e| e.time > time)
                 })
-                .map(|entry| read_metadata(entry.path()))
-                .collect::<io::Result<Vec<Packet>>>()?
+                .collect())
         }
-    };
+    }
+}
 
-    packets.sort_by(|a, b| a.id.cmp(&b.id));
-    Ok(packets)
+pub fn get_packet(root_path: &str, id: &str) -> io::Result<Packet> {
+    let index = index::get_packet_index(root_path)?;
+    if let Some(packet) = index.packet(id) {
+        return Ok(packet.clone());
+    }
+    let error = match index.unreadable().get(id) {
+        Some(problem) => Error::new(ErrorCode::UnknownError, problem.clone()),
+        None => Error::new(
+            ErrorCode::PacketNotFound,
+            format!("packet with id '{}' does not exist", id),
+        ),
+    };
+    Err(error.into())
 }
 
 pub fn get_metadata_by_id(root_path: &str, id: &str) -> io::Result<serde_json::Value> {
@@ -158,23 +316,33 @@ fn get_sorted_id_string(mut ids: Vec<String>) -> String {
 }
 
 pub fn get_ids_digest(root_path: &str, alg_name: Option<String>) -> io::Result<String> {
+    get_digest_of_ids(root_path, get_ids(root_path, None)?, alg_name)
+}
+
+/// The digest that `get_ids_digest` would give for a root holding only
+/// the packets `ids`.
+pub fn get_digest_of_ids(
+    root_path: &str,
+    ids: Vec<String>,
+    alg_name: Option<String>,
+) -> io::Result<String> {
     let hash_algorithm = match alg_name {
         None => config::read_config(root_path)?.core.hash_algorithm,
-        Some(name) => hash::HashAlgorithm::from_str(&name).map_err(hash::hash_error_to_io_error)?,
+        Some(name) => hash::HashAlgorithm::from_str(&name)?,
     };
 
-    let ids = get_ids(root_path, None)?;
     let id_string = get_sorted_id_string(ids);
     Ok(hash::hash_data(id_string.as_bytes(), hash_algorithm).to_string())
 }
 
 pub fn get_ids(root_path: &str, unpacked: Option<bool>) -> io::Result<Vec<String>> {
-    let path = Path::new(root_path).join(".outpack");
-    let path = if unpacked.is_some_and(|x| x) {
-        path.join("location").join("local")
-    } else {
-        path.join("metadata")
-    };
+    if !unpacked.is_some_and(|x| x) {
+        return index::read_packet_ids(root_path);
+    }
+    let path = Path::new(root_path)
+        .join(".outpack")
+        .join("location")
+        .join("local");
     Ok(fs::read_dir(path)?
         .filter_map(|r| r.ok())
         .map(|e| e.file_name().into_string())
@@ -187,10 +355,11 @@ pub fn get_valid_id(id: &String) -> io::Result<String> {
     if is_packet_str(&s) {
         Ok(s)
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
+        Err(Error::new(
+            ErrorCode::InvalidPacketId,
             format!("Invalid packet id '{}'", id),
-        ))
+        )
+        .into())
     }
 }
 
@@ -216,14 +385,15 @@ fn check_missing_files(root: &str, packet: &Packet) -> Result<(), io::Error> {
 
     let missing_files = store::get_missing_files(root, &files)?;
     if !missing_files.is_empty() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
+        return Err(Error::new(
+            ErrorCode::MissingFiles,
             format!(
                 "Can't import metadata for {}, as files missing: \n {}",
                 packet.id,
                 missing_files.join(",")
             ),
-        ));
+        )
+        .into());
     }
     Ok(())
 }
@@ -237,37 +407,138 @@ fn check_missing_dependencies(root: &str, packet: &Packet) -> Result<(), io::Err
 
     let missing_packets = get_missing_ids(root, &deps, Some(true))?;
     if !missing_packets.is_empty() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
+        return Err(Error::new(
+            ErrorCode::MissingDependencies,
             format!(
                 "Can't import metadata for {}, as dependencies missing: \n {}",
                 packet.id,
                 missing_packets.join(",")
             ),
-        ));
+        )
+        .into());
     }
     Ok(())
 }
 
-pub fn add_metadata(root: &str, data: &str, hash: &hash::Hash) -> io::Result<()> {
-    let packet: Packet = serde_json::from_str(data)?;
-    let hash_str = hash.to_string();
+// The schemas that metadata.json refers to, all of which are bundled
+// with the crate so that validation never needs to go looking for them.
+struct BundledSchemaResolver;
+
+impl SchemaResolver for BundledSchemaResolver {
+    fn resolve(
+        &self,
+        _root_schema: &serde_json::Value,
+        url: &Url,
+        original_reference: &str,
+    ) -> Result<Arc<serde_json::Value>, SchemaResolverError> {
+        let schema = match original_reference {
+            "packet-id.json" => include_str!("../schema/outpack/packet-id.json"),
+            "hash.json" => include_str!("../schema/outpack/hash.json"),
+            "git.json" => include_str!("../schema/outpack/git.json"),
+            _ => {
+                return Err(SchemaResolverError::msg(format!(
+                    "Unknown schema '{}'",
+                    url
+                )))
+            }
+        };
+        Ok(Arc::new(serde_json::from_str(schema)?))
+    }
+}
 
-    hash::validate_hash_data(data.as_bytes(), &hash_str).map_err(hash::hash_error_to_io_error)?;
-    check_missing_files(root, &packet)?;
-    check_missing_dependencies(root, &packet)?;
+lazy_static! {
+    static ref METADATA_SCHEMA: JSONSchema = {
+        let schema = serde_json::from_str(include_str!("../schema/outpack/metadata.json"))
+            .expect("Valid metadata schema");
+        JSONSchema::options()
+            .with_draft(Draft::Draft7)
+            .with_resolver(BundledSchemaResolver)
+            .compile(&schema)
+            .expect("Valid metadata schema")
+    };
+}
+
+/// Check metadata against the outpack metadata schema, returning every
+/// way in which it does not conform, each prefixed by where in the
+/// metadata the problem is.
+pub fn validate_metadata(metadata: &serde_json::Value) -> Result<(), Vec<String>> {
+    METADATA_SCHEMA.validate(metadata).map_err(|errors| {
+        errors
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                let path = if path.is_empty() { "/" } else { &path };
+                format!("{}: {}", path, e)
+            })
+            .collect()
+    })
+}
+
+// Parse metadata, checking that it is JSON that matches the schema.
+fn parse_metadata(data: &str) -> io::Result<Packet> {
+    let invalid = |detail: String| Error::new(ErrorCode::InvalidMetadata, detail);
+    let value: serde_json::Value =
+        serde_json::from_str(data).map_err(|e| invalid(format!("Invalid metadata: {}", e)))?;
+    validate_metadata(&value).map_err(|errors| {
+        invalid(format!(
+            "Invalid metadata, which does not match the schema:\n{}",
+            errors.join("\n")
+        ))
+    })?;
+    let packet =
+        serde_json::from_value(value).map_err(|e| invalid(format!("Invalid metadata: {}", e)))?;
+    Ok(packet)
+}
 
+// Write out checked metadata, unless we have it already, and index it.
+fn write_metadata(root: &str, packet: &Packet, data: &str) -> io::Result<()> {
     let path = get_path(root, &packet.id);
 
     if !path.exists() {
         fs::File::create(&path)?;
         fs::write(path, data)?;
     }
+    index::add_packet(root, packet);
+    Ok(())
+}
+
+pub fn add_metadata(root: &str, data: &str, hash: &hash::Hash) -> io::Result<()> {
+    let packet = parse_metadata(data)?;
+    let hash_str = hash.to_string();
+
+    hash::validate_hash_data(data.as_bytes(), &hash_str)?;
+    check_missing_files(root, &packet)?;
+    check_missing_dependencies(root, &packet)?;
+
+    write_metadata(root, &packet, data)?;
     let time = SystemTime::now();
     location::mark_packet_known(&packet.id, "local", &hash_str, time, root)?;
     Ok(())
 }
 
+/// Import the metadata of packet `id` as held at some other location.
+/// Unlike `add_metadata`, the packet's files and dependencies need not
+/// be present yet, but the metadata must still match the schema and be
+/// for the packet it claims to be.
+pub fn import_metadata(root: &str, id: &str, data: &str) -> io::Result<Packet> {
+    if !is_packet_str(id) {
+        return Err(Error::new(
+            ErrorCode::InvalidPacketId,
+            format!("Invalid packet id '{}'", id),
+        )
+        .into());
+    }
+    let packet = parse_metadata(data)?;
+    if packet.id != id {
+        return Err(Error::new(
+            ErrorCode::InvalidMetadata,
+            format!("Metadata for '{}' is for packet '{}'", id, packet.id),
+        )
+        .into());
+    }
+    write_metadata(root, &packet, data)?;
+    Ok(packet)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -296,6 +567,10 @@ mod tests {
     #[test]
     fn can_get_packet() {
         let _packet = get_metadata_by_id("tests/example", "20180818-164043-7cdcde4b").unwrap();
+        let packet = get_packet("tests/example", "20180818-164043-7cdcde4b").unwrap();
+        assert_eq!(packet.name, "modup-201707-queries1");
+        let res = get_packet("tests/example", "20180818-164043-7cdcde4a");
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
     }
 
     #[test]
@@ -417,6 +692,8 @@ mod tests {
                                 "end": 1682608108.4309
                               },
                               "parameters": null,
+                              "custom": null,
+                              "git": null,
                               "files": [
                                {
                                   "path": "data.csv",
@@ -425,7 +702,8 @@ mod tests {
                                 }],
                               "depends": [{
                                   "packet": "20170818-164847-7574883b",
-                                  "files": []
+                                  "query": "latest",
+                                  "files": [{"here": "input.csv", "there": "data.csv"}]
                               }],
                               "script": [
                                 "orderly.R"
@@ -438,6 +716,11 @@ mod tests {
         let packet = get_metadata_by_id(root_path, "20230427-150828-68772cee").unwrap();
         let expected: Value = serde_json::from_str(data).unwrap();
         assert_eq!(packet, expected);
+
+        let ids = get_ids(root_path, None).unwrap();
+        assert!(ids.contains(&String::from("20230427-150828-68772cee")));
+        let index = fs::read_to_string(root.join(".outpack/index/packets.json")).unwrap();
+        assert!(index.contains("20230427-150828-68772cee"));
     }
 
     #[test]
@@ -451,6 +734,8 @@ mod tests {
                                 "end": 1682608108.4309
                               },
                               "parameters": null,
+                              "custom": null,
+                              "git": null,
                               "files": [],
                               "depends": [],
                               "script": [
@@ -478,6 +763,8 @@ mod tests {
                                 "end": 1682608108.4309
                               },
                               "parameters": null,
+                              "custom": null,
+                              "git": null,
                               "files": [],
                               "depends": [],
                               "script": [
@@ -515,6 +802,8 @@ mod tests {
                                 "end": 1682608108.4309
                               },
                               "parameters": null,
+                              "custom": null,
+                              "git": null,
                               "files": [
                                 {
                                   "path": "data.csv",
@@ -546,10 +835,13 @@ mod tests {
                                 "end": 1682608108.4309
                               },
                               "parameters": null,
+                              "custom": null,
+                              "git": null,
                               "files": [],
                               "depends": [{
                                 "packet": "20230427-150828-68772cea",
-                                "files": []
+                                "query": "latest",
+                                "files": [{"here": "input.csv", "there": "data.csv"}]
                               }],
                               "script": [
                                 "orderly.R"
@@ -562,4 +854,133 @@ mod tests {
         assert_eq!(res.unwrap_err().to_string(),
                    "Can't import metadata for 20230427-150828-68772cee, as dependencies missing: \n 20230427-150828-68772cea");
     }
+
+    fn valid_metadata() -> Value {
+        serde_json::json!({
+            "schema_version": "0.1.1",
+            "name": "data",
+            "id": "20230427-150828-68772cee",
+            "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+            "parameters": {"a": 1},
+            "files": [],
+            "depends": [],
+            "custom": null,
+            "git": null
+        })
+    }
+
+    #[test]
+    fn can_validate_metadata() {
+        let mut metadata = valid_metadata();
+        assert_eq!(validate_metadata(&metadata), Ok(()));
+
+        metadata.as_object_mut().unwrap().remove("time");
+        metadata["parameters"] = serde_json::json!({"a": [1, 2]});
+        metadata["files"] = serde_json::json!([{"path": "data.csv", "hash": "md5:00"}]);
+        let errors = validate_metadata(&metadata).unwrap_err();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors.contains(&String::from("/: \"time\" is a required property")));
+        assert!(errors.iter().any(|e| e.starts_with("/parameters: ")));
+        assert!(errors.iter().any(|e| e.starts_with("/files/0/hash: ")));
+        assert!(errors.contains(&String::from("/files/0: \"size\" is a required property")));
+    }
+
+    #[test]
+    fn cannot_add_metadata_that_does_not_match_schema() {
+        let mut metadata = valid_metadata();
+        metadata.as_object_mut().unwrap().remove("time");
+        let data = metadata.to_string();
+        let hash = hash::hash_data(data.as_bytes(), hash::HashAlgorithm::Sha256);
+        let root = get_temp_outpack_root();
+        let root_path = root.to_str().unwrap();
+        let err = add_metadata(root_path, &data, &hash).unwrap_err();
+        assert_eq!(
+            crate::error::code_of(&err),
+            Some(ErrorCode::InvalidMetadata)
+        );
+        assert_eq!(
+            err.to_string(),
+            "Invalid metadata, which does not match the schema:\n/: \"time\" is a required property"
+        );
+        assert!(!get_ids(root_path, None)
+            .unwrap()
+            .contains(&String::from("20230427-150828-68772cee")));
+    }
+
+    #[test]
+    fn metadata_round_trips_without_loss() {
+        for entry in fs::read_dir("tests/example/.outpack/metadata").unwrap() {
+            let text = fs::read_to_string(entry.unwrap().path()).unwrap();
+            let original: Value = serde_json::from_str(&text).unwrap();
+            let packet: Packet = serde_json::from_str(&text).unwrap();
+            assert_eq!(serde_json::to_value(&packet).unwrap(), original);
+        }
+
+        let mut metadata = valid_metadata();
+        metadata["script"] = serde_json::json!(["orderly.R"]);
+        metadata["time"]["elapsed"] = serde_json::json!(0.017);
+        metadata["git"] = serde_json::json!({"sha": "abc123", "remote": "origin"});
+        metadata["files"] = serde_json::json!([
+            {"path": "data.csv", "size": 1, "hash": "md5:00", "role": "artefact"}
+        ]);
+        let packet: Packet = serde_json::from_value(metadata.clone()).unwrap();
+        assert_eq!(packet.extra["script"], serde_json::json!(["orderly.R"]));
+        assert_eq!(packet.files[0].extra["role"], "artefact");
+        assert_eq!(serde_json::to_value(&packet).unwrap(), metadata);
+
+        // A missing git is not the same as a null one.
+        metadata["git"] = Value::Null;
+        let packet: Packet = serde_json::from_value(metadata.clone()).unwrap();
+        assert_eq!(packet.git, Some(None));
+        assert_eq!(serde_json::to_value(&packet).unwrap(), metadata);
+        metadata.as_object_mut().unwrap().remove("git");
+        let packet: Packet = serde_json::from_value(metadata.clone()).unwrap();
+        assert_eq!(packet.git, None);
+        assert_eq!(serde_json::to_value(&packet).unwrap(), metadata);
+
+        // Nor does the schema require a schema version or times.
+        metadata.as_object_mut().unwrap().remove("schema_version");
+        metadata.as_object_mut().unwrap().remove("time");
+        let packet: Packet = serde_json::from_value(metadata.clone()).unwrap();
+        assert_eq!(packet.schema_version, None);
+        assert_eq!(packet.start_time(), None);
+        assert_eq!(serde_json::to_value(&packet).unwrap(), metadata);
+    }
+
+    #[test]
+    fn can_read_typed_metadata() {
+        let packet = get_packet("tests/example", "20170818-164847-7574883b").unwrap();
+        assert_eq!(packet.schema_version.as_deref(), Some("0.0.1"));
+        assert_eq!(packet.start_time(), Some(1503074938.2232));
+        assert_eq!(packet.end_time(), Some(1503074938.2232));
+        assert_eq!(packet.git_sha(), None);
+        assert!(packet.git_url().is_empty());
+        assert_eq!(packet.file("script.R").unwrap().size, 48);
+        let dependency = &packet.depends[0];
+        assert_eq!(dependency.query(), None);
+        assert!(!dependency.files.is_empty());
+
+        let mut metadata = valid_metadata();
+        metadata["git"] = serde_json::json!({
+            "sha": "5ca6e2ba", "branch": "main", "url": ["https://example.com/repo"]
+        });
+        metadata["depends"] = serde_json::json!([{
+            "packet": "20230427-150828-00000000",
+            "query": "latest(name == \"upstream\")",
+            "files": [{"here": "input.csv", "there": "data.csv"}]
+        }]);
+        metadata["custom"] = serde_json::json!({"orderly": {"role": []}});
+        let packet: Packet = serde_json::from_value(metadata).unwrap();
+        assert_eq!(packet.git_sha(), Some("5ca6e2ba"));
+        assert_eq!(packet.git_branch(), Some("main"));
+        assert_eq!(packet.git_url(), ["https://example.com/repo"]);
+        assert_eq!(packet.parameter("a"), Some(&serde_json::json!(1)));
+        assert_eq!(
+            packet.custom("orderly"),
+            Some(&serde_json::json!({"role": []}))
+        );
+        let dependency = packet.dependency("20230427-150828-00000000").unwrap();
+        assert_eq!(dependency.query(), Some("latest(name == \"upstream\")"));
+        assert_eq!(dependency.files[0].there, "data.csv");
+    }
 }
@@ -1,30 +1,266 @@
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
+use rocket::request::{FromRequest, Outcome};
 use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 use std::io;
-use std::io::ErrorKind;
-use std::path::Path;
+use std::io::{Cursor, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
 
-use rocket::response::{Responder, Result};
+use rocket::response::{Responder, Response, Result};
 use rocket::Request;
 
+use crate::error::{Error, ErrorCode};
+
+/// The headers of a request for a file that affect what is sent back.
+pub struct FileRequest {
+    range: Option<String>,
+    if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for FileRequest {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let headers = request.headers();
+        Outcome::Success(FileRequest {
+            range: headers.get_one("Range").map(String::from),
+            if_none_match: headers.get_one("If-None-Match").map(String::from),
+        })
+    }
+}
+
+/// An inclusive range of bytes within a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// No usable Range header; the whole file is sent.
+    All,
+    /// None of the requested ranges overlap the file.
+    Unsatisfiable,
+    Some(Vec<ByteRange>),
+}
+
+/// The most ranges we will serve from one request; asking for more gets
+/// the whole file, which is cheaper than building a huge multipart body.
+const MAX_RANGES: usize = 16;
+
+/// Resolve a `Range` header against a file of `size` bytes. As the spec
+/// requires, a header we cannot parse is ignored rather than rejected.
+/// Overlapping and adjacent ranges are merged, in order of position.
+pub fn parse_range(header: &str, size: u64) -> Ranges {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return Ranges::All,
+    };
+    if specs.split(',').count() > MAX_RANGES {
+        return Ranges::All;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim) {
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ranges::All,
+        };
+        let range = match (start.parse::<u64>(), end) {
+            // "-n": the last n bytes
+            (Err(_), _) if start.is_empty() => match end.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) if size > 0 => Some(ByteRange {
+                    start: size.saturating_sub(n),
+                    end: size - 1,
+                }),
+                Ok(_) => None,
+                Err(_) => return Ranges::All,
+            },
+            (Err(_), _) => return Ranges::All,
+            // "n-": from byte n to the end
+            (Ok(start), "") => Some(ByteRange {
+                start,
+                end: size.saturating_sub(1),
+            }),
+            (Ok(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => Some(ByteRange {
+                    start,
+                    end: end.min(size.saturating_sub(1)),
+                }),
+                _ => return Ranges::All,
+            },
+        };
+        if let Some(range) = range.filter(|r| r.start < size) {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        Ranges::Unsatisfiable
+    } else {
+        Ranges::Some(merge_ranges(ranges))
+    }
+}
+
+fn merge_ranges(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+// Whether an If-None-Match header matches our etag. Files never change,
+// so a weak match is as good as a strong one.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+enum FileBody {
+    Full(File),
+    NotModified,
+    Unsatisfiable,
+    Partial(ByteRange, Reader),
+    Multipart {
+        boundary: String,
+        length: u64,
+        reader: Reader,
+    },
+}
+
 pub struct OutpackFile {
     hash: String,
-    file: File,
+    filename: String,
+    content_type: ContentType,
     size: u64,
+    body: FileBody,
+}
+
+async fn open_range(path: &Path, range: &ByteRange) -> io::Result<Reader> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(Box::new(file.take(range.len())))
 }
 
 impl OutpackFile {
-    pub async fn open<P: AsRef<Path>>(hash: String, path: P) -> io::Result<OutpackFile> {
-        let file = File::open(path.as_ref())
-            .await
-            .map_err(|e| match e.kind() {
-                ErrorKind::NotFound => {
-                    io::Error::new(ErrorKind::NotFound, format!("hash '{}' not found", hash))
-                }
-                _ => e,
-            })?;
+    /// Open a file to serve in response to a request, taking account of
+    /// the request's `Range` and `If-None-Match` headers.
+    pub async fn open<P: AsRef<Path>>(
+        hash: String,
+        path: P,
+        request: FileRequest,
+    ) -> io::Result<OutpackFile> {
+        let filename = hash.clone();
+        OutpackFile::open_named(hash, path, filename, request).await
+    }
+
+    /// As [`OutpackFile::open`], but for a file known by a name, which
+    /// is used in place of the hash for the `Content-Disposition` and
+    /// to guess the content type.
+    pub async fn open_named<P: AsRef<Path>>(
+        hash: String,
+        path: P,
+        filename: String,
+        request: FileRequest,
+    ) -> io::Result<OutpackFile> {
+        let content_type = Path::new(&filename)
+            .extension()
+            .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+            .unwrap_or(ContentType::Binary);
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let file = File::open(&path).await.map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::new(
+                ErrorCode::FileNotFound,
+                format!("hash '{}' not found", hash),
+            )
+            .into(),
+            _ => e,
+        })?;
         let size = file.metadata().await?.len();
-        Ok(OutpackFile { hash, file, size })
+
+        let etag = format!("\"{}\"", hash);
+        if let Some(header) = &request.if_none_match {
+            if etag_matches(header, &etag) {
+                return Ok(OutpackFile {
+                    hash,
+                    filename,
+                    content_type,
+                    size,
+                    body: FileBody::NotModified,
+                });
+            }
+        }
+
+        let ranges = match &request.range {
+            Some(header) => parse_range(header, size),
+            None => Ranges::All,
+        };
+        let body = match ranges {
+            Ranges::All => FileBody::Full(file),
+            Ranges::Unsatisfiable => FileBody::Unsatisfiable,
+            Ranges::Some(ranges) if ranges.len() == 1 => {
+                FileBody::Partial(ranges[0], open_range(&path, &ranges[0]).await?)
+            }
+            Ranges::Some(ranges) => {
+                // The digest can't appear in the file it is the hash of,
+                // so makes a safe boundary.
+                let boundary = hash
+                    .split_once(':')
+                    .map_or(&hash[..], |(_, d)| d)
+                    .to_string();
+                let mut length = 0;
+                let mut reader: Reader = Box::new(Cursor::new(Vec::new()));
+                for (i, range) in ranges.iter().enumerate() {
+                    let head = format!(
+                        "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                        if i == 0 { "" } else { "\r\n" },
+                        boundary,
+                        content_type,
+                        range.content_range(size)
+                    );
+                    length += head.len() as u64 + range.len();
+                    let part = open_range(&path, range).await?;
+                    reader = Box::new(reader.chain(Cursor::new(head.into_bytes())).chain(part));
+                }
+                let tail = format!("\r\n--{}--\r\n", boundary);
+                length += tail.len() as u64;
+                reader = Box::new(reader.chain(Cursor::new(tail.into_bytes())));
+                FileBody::Multipart {
+                    boundary,
+                    length,
+                    reader,
+                }
+            }
+        };
+        Ok(OutpackFile {
+            hash,
+            filename,
+            content_type,
+            size,
+            body,
+        })
     }
 }
 
@@ -32,14 +268,164 @@ impl<'r> Responder<'r, 'static> for OutpackFile {
     fn respond_to(self, request: &'r Request<'_>) -> Result<'static> {
         use rocket::http::hyper::header::*;
 
-        let content_type = ContentType::Binary.to_string();
-        let content_disposition = format!("attachment; filename=\"{}\"", self.hash);
+        let content_type = self.content_type.to_string();
+        let content_disposition = format!(
+            "attachment; filename=\"{}\"",
+            self.filename.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        let etag = format!("\"{}\"", self.hash);
 
-        let mut response = self.file.respond_to(request)?;
-        response.set_raw_header(CONTENT_TYPE.as_str(), content_type);
+        let mut response = match self.body {
+            FileBody::Full(file) => {
+                let mut response = file.respond_to(request)?;
+                response.set_raw_header(CONTENT_TYPE.as_str(), content_type);
+                response.set_raw_header(CONTENT_LENGTH.as_str(), self.size.to_string());
+                response
+            }
+            FileBody::NotModified => Response::build().status(Status::NotModified).finalize(),
+            FileBody::Unsatisfiable => Response::build()
+                .status(Status::RangeNotSatisfiable)
+                .raw_header(CONTENT_RANGE.as_str(), format!("bytes */{}", self.size))
+                .finalize(),
+            FileBody::Partial(range, reader) => Response::build()
+                .status(Status::PartialContent)
+                .raw_header(CONTENT_TYPE.as_str(), content_type)
+                .raw_header(CONTENT_RANGE.as_str(), range.content_range(self.size))
+                .raw_header(CONTENT_LENGTH.as_str(), range.len().to_string())
+                .streamed_body(reader)
+                .finalize(),
+            FileBody::Multipart {
+                boundary,
+                length,
+                reader,
+            } => Response::build()
+                .status(Status::PartialContent)
+                .raw_header(
+                    CONTENT_TYPE.as_str(),
+                    format!("multipart/byteranges; boundary={}", boundary),
+                )
+                .raw_header(CONTENT_LENGTH.as_str(), length.to_string())
+                .streamed_body(reader)
+                .finalize(),
+        };
         response.set_raw_header(CONTENT_DISPOSITION.as_str(), content_disposition);
-        response.set_raw_header(CONTENT_LENGTH.as_str(), self.size.to_string());
+        response.set_raw_header(ACCEPT_RANGES.as_str(), "bytes");
+        response.set_raw_header(ETAG.as_str(), etag);
+        Ok(response)
+    }
+}
+
+/// An archive of a packet's files, built in a temporary file.
+pub struct PacketArchive {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub file: File,
+}
 
+impl<'r> Responder<'r, 'static> for PacketArchive {
+    fn respond_to(self, request: &'r Request<'_>) -> Result<'static> {
+        use rocket::http::hyper::header::*;
+
+        let mut response = self.file.respond_to(request)?;
+        response.set_header(self.content_type);
+        response.set_raw_header(
+            CONTENT_DISPOSITION.as_str(),
+            format!("attachment; filename=\"{}\"", self.filename),
+        );
         Ok(response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn can_parse_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-9", 100),
+            Ranges::Some(vec![range(0, 9)])
+        );
+        assert_eq!(
+            parse_range("bytes=90-", 100),
+            Ranges::Some(vec![range(90, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=-10", 100),
+            Ranges::Some(vec![range(90, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=-200", 100),
+            Ranges::Some(vec![range(0, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=50-200", 100),
+            Ranges::Some(vec![range(50, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-0, 10-19", 100),
+            Ranges::Some(vec![range(0, 0), range(10, 19)])
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(
+            parse_range("bytes=10-19, 0-4, 15-29", 100),
+            Ranges::Some(vec![range(0, 4), range(10, 29)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-4, 5-9, -10", 100),
+            Ranges::Some(vec![range(0, 9), range(90, 99)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-49, 10-19", 100),
+            Ranges::Some(vec![range(0, 49)])
+        );
+    }
+
+    #[test]
+    fn too_many_ranges_gets_whole_file() {
+        let specs: Vec<String> = (0..16).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        let header = format!("bytes={}", specs.join(","));
+        assert!(matches!(parse_range(&header, 100), Ranges::Some(r) if r.len() == 16));
+        let header = format!("{},40-40", header);
+        assert_eq!(parse_range(&header, 100), Ranges::All);
+    }
+
+    #[test]
+    fn ranges_outside_file_are_unsatisfiable() {
+        assert_eq!(parse_range("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=100-200", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Ranges::Unsatisfiable);
+        // Unsatisfiable parts are dropped if any others overlap.
+        assert_eq!(
+            parse_range("bytes=0-9, 200-", 100),
+            Ranges::Some(vec![range(0, 9)])
+        );
+    }
+
+    #[test]
+    fn invalid_ranges_are_ignored() {
+        assert_eq!(parse_range("items=0-9", 100), Ranges::All);
+        assert_eq!(parse_range("bytes=9-0", 100), Ranges::All);
+        assert_eq!(parse_range("bytes=a-b", 100), Ranges::All);
+        assert_eq!(parse_range("bytes=10", 100), Ranges::All);
+        assert_eq!(parse_range("bytes=0-9, x", 100), Ranges::All);
+    }
+
+    #[test]
+    fn can_match_etags() {
+        let etag = "\"sha256:abc\"";
+        assert!(etag_matches("\"sha256:abc\"", etag));
+        assert!(etag_matches("W/\"sha256:abc\"", etag));
+        assert!(etag_matches("\"md5:def\", \"sha256:abc\"", etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"md5:def\"", etag));
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fs, io};
+use tempfile::NamedTempFile;
+
+use crate::config::{self, LocationKind};
+use crate::error::{Error, ErrorCode};
+use crate::location::{self, LocationEntry};
+use crate::metadata::{self, Packet, PacketFile};
+use crate::query::{self, QueryContext};
+use crate::utils::is_packet_str;
+use crate::{hash, index, store};
+
+/// Somewhere packets can be pulled from.
+pub trait LocationDriver {
+    /// The packets that the location has unpacked.
+    fn list(&self) -> io::Result<Vec<LocationEntry>>;
+    /// The metadata of a packet, exactly as stored, so that it can be
+    /// checked against the hash in its location entry.
+    fn metadata(&self, id: &str) -> io::Result<String>;
+    /// The contents of one of a packet's files.
+    fn fetch_file(&self, packet: &Packet, file: &PacketFile) -> io::Result<Box<dyn Read + Send>>;
+}
+
+/// Another outpack root on disk.
+pub struct PathDriver {
+    pub(crate) root: String,
+}
+
+impl PathDriver {
+    pub fn new(root: &str) -> io::Result<PathDriver> {
+        config::read_config(root).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not an outpack root", root),
+            )
+        })?;
+        Ok(PathDriver {
+            root: String::from(root),
+        })
+    }
+}
+
+impl LocationDriver for PathDriver {
+    fn list(&self) -> io::Result<Vec<LocationEntry>> {
+        let path = Path::new(&self.root)
+            .join(".outpack")
+            .join("location")
+            .join(config::LOCAL);
+        location::read_location(path)
+    }
+
+    fn metadata(&self, id: &str) -> io::Result<String> {
+        metadata::get_metadata_text(&self.root, id)
+    }
+
+    fn fetch_file(&self, packet: &Packet, file: &PacketFile) -> io::Result<Box<dyn Read + Send>> {
+        let core = config::read_config(&self.root)?.core;
+        let path = store::packet_file_path(&self.root, &core, packet, file)?;
+        Ok(Box::new(fs::File::open(path)?))
+    }
+}
+
+/// Another outpack root, served by outpack_server.
+#[cfg(feature = "client")]
+pub struct HttpDriver {
+    pub(crate) client: crate::client::Client,
+}
+
+#[cfg(feature = "client")]
+impl HttpDriver {
+    pub fn new(url: &str) -> HttpDriver {
+        HttpDriver {
+            client: crate::client::Client::new(url),
+        }
+    }
+}
+
+#[cfg(feature = "client")]
+impl LocationDriver for HttpDriver {
+    fn list(&self) -> io::Result<Vec<LocationEntry>> {
+        // The server lists what every one of its locations knows about,
+        // so a packet may appear more than once.
+        let mut seen = HashSet::new();
+        Ok(self
+            .client
+            .list_location_metadata()?
+            .into_iter()
+            .filter(|entry| seen.insert(entry.packet.clone()))
+            .collect())
+    }
+
+    fn metadata(&self, id: &str) -> io::Result<String> {
+        Ok(self.client.metadata_text(id)?)
+    }
+
+    fn fetch_file(&self, _packet: &Packet, file: &PacketFile) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.client.file(&file.hash)?))
+    }
+}
+
+#[cfg(feature = "client")]
+pub(crate) fn http_driver(url: &str) -> io::Result<HttpDriver> {
+    Ok(HttpDriver::new(url))
+}
+
+#[cfg(not(feature = "client"))]
+pub(crate) fn http_driver(url: &str) -> io::Result<PathDriver> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Cannot use http location '{}', as outpack was built without the 'client' feature",
+            url
+        ),
+    ))
+}
+
+pub fn location_driver(root: &str, name: &str) -> io::Result<Box<dyn LocationDriver>> {
+    match location::get_location(root, name)?.kind {
+        LocationKind::Local => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot pull from the '{}' location", config::LOCAL),
+        )),
+        LocationKind::Path { path } => {
+            // Relative paths are taken relative to the root.
+            let path = Path::new(root).join(path);
+            Ok(Box::new(PathDriver::new(&path.to_string_lossy())?))
+        }
+        LocationKind::Http { url } => Ok(Box::new(http_driver(&url)?)),
+        LocationKind::Other { kind, .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Cannot pull from '{}', as locations of type '{}' are not supported",
+                name, kind
+            ),
+        )),
+    }
+}
+
+// Copy a file into the archive, checking its hash on the way.
+fn put_archive_file<R: Read>(path: &Path, reader: &mut R, hash: &str) -> io::Result<()> {
+    let expected: hash::Hash = hash.parse()?;
+    let dir = path.parent().unwrap();
+    fs::create_dir_all(dir)?;
+    let mut dest = NamedTempFile::new_in(dir)?;
+    let found = hash::copy_and_hash(reader, &mut dest, expected.algorithm)?;
+    hash::validate_hash(&found, &expected)?;
+    dest.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn location_path(root: &str, name: &str) -> PathBuf {
+    Path::new(root).join(".outpack").join("location").join(name)
+}
+
+pub(crate) fn known_at(root: &str, name: &str) -> io::Result<HashMap<String, LocationEntry>> {
+    let path = location_path(root, name);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(location::read_location(path)?
+        .into_iter()
+        .map(|entry| (entry.packet.clone(), entry))
+        .collect())
+}
+
+/// Fetch metadata for every packet the location has that we have not
+/// seen before, and record that the location has them. Returns the
+/// number of packets newly known at the location.
+pub fn pull_metadata(root: &str, location_name: &str) -> io::Result<usize> {
+    let driver = location_driver(root, location_name)?;
+    pull_metadata_from(root, location_name, driver.as_ref())
+}
+
+fn pull_metadata_from(
+    root: &str,
+    location_name: &str,
+    driver: &dyn LocationDriver,
+) -> io::Result<usize> {
+    let known = known_at(root, location_name)?;
+    let metadata_path = Path::new(root).join(".outpack").join("metadata");
+    let mut count = 0;
+    for entry in driver.list()? {
+        // The location is not trusted to give us ids that are safe to
+        // use as file names.
+        if !is_packet_str(&entry.packet) {
+            return Err(Error::new(
+                ErrorCode::InvalidPacketId,
+                format!(
+                    "Location '{}' lists invalid packet id '{}'",
+                    location_name, entry.packet
+                ),
+            )
+            .into());
+        }
+        if known.contains_key(&entry.packet) {
+            continue;
+        }
+        let path = metadata_path.join(&entry.packet);
+        if !path.exists() {
+            let text = driver.metadata(&entry.packet)?;
+            hash::validate_hash_data(text.as_bytes(), &entry.hash).map_err(|e| {
+                Error::new(
+                    ErrorCode::HashMismatch,
+                    format!(
+                        "Metadata for '{}' from '{}' does not match its hash: {}",
+                        entry.packet, location_name, e.explanation
+                    ),
+                )
+            })?;
+            metadata::import_metadata(root, &entry.packet, &text)?;
+        }
+        location::mark_packet_known(
+            &entry.packet,
+            location_name,
+            &entry.hash,
+            SystemTime::now(),
+            root,
+        )?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PullReport {
+    /// Number of packets newly known at the location.
+    pub metadata: usize,
+    /// Ids of the packets unpacked, in the order they were unpacked.
+    pub packets: Vec<String>,
+    /// Number of files downloaded.
+    pub files: usize,
+}
+
+// Work out which packets must be unpacked so that all of `wanted`, and
+// everything they depend on, are present locally. Dependencies come
+// before the packets that use them.
+fn plan_pull(
+    wanted: Vec<String>,
+    unpacked: &HashSet<String>,
+    available: &HashMap<String, LocationEntry>,
+    packets: &HashMap<String, Packet>,
+    location_name: &str,
+) -> io::Result<Vec<String>> {
+    fn visit(
+        id: &str,
+        unpacked: &HashSet<String>,
+        packets: &HashMap<String, Packet>,
+        seen: &mut HashSet<String>,
+        order: &mut Vec<String>,
+        missing: &mut Vec<String>,
+    ) {
+        if unpacked.contains(id) || !seen.insert(id.to_string()) {
+            return;
+        }
+        match packets.get(id) {
+            Some(packet) => {
+                for dependency in &packet.depends {
+                    visit(&dependency.packet, unpacked, packets, seen, order, missing);
+                }
+                order.push(id.to_string());
+            }
+            None => missing.push(id.to_string()),
+        }
+    }
+
+    let mut seen = HashSet::new();
+    let mut order = Vec::new();
+    let mut missing = Vec::new();
+    for id in &wanted {
+        visit(id, unpacked, packets, &mut seen, &mut order, &mut missing);
+    }
+    missing.extend(
+        order
+            .iter()
+            .filter(|id| !available.contains_key(*id))
+            .cloned(),
+    );
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(Error::new(
+            ErrorCode::PacketNotFound,
+            format!(
+                "Can't pull from '{}', as packets are not available there: \n {}",
+                location_name,
+                missing.join(",")
+            ),
+        )
+        .into());
+    }
+    Ok(order)
+}
+
+/// Pull packets from a location: first all new metadata, and then the
+/// files of every packet matching `query` (or every packet the location
+/// has, if no query is given), along with their dependencies. A packet
+/// is only marked as unpacked once everything it depends on is.
+pub fn pull(root: &str, location_name: &str, query: Option<&str>) -> io::Result<PullReport> {
+    let driver = location_driver(root, location_name)?;
+    pull_from(root, location_name, driver.as_ref(), query)
+}
+
+fn pull_from(
+    root: &str,
+    location_name: &str,
+    driver: &dyn LocationDriver,
+    query: Option<&str>,
+) -> io::Result<PullReport> {
+    let mut report = PullReport {
+        metadata: pull_metadata_from(root, location_name, driver)?,
+        ..Default::default()
+    };
+
+    let available = known_at(root, location_name)?;
+    let unpacked: HashSet<String> = metadata::get_ids(root, Some(true))?.into_iter().collect();
+    let packets: HashMap<String, Packet> = index::read_packets(root)?
+        .iter()
+        .map(|packet| (packet.id.clone(), packet.clone()))
+        .collect();
+
+    let mut wanted = match query {
+        None => available.keys().cloned().collect::<Vec<String>>(),
+        Some(query) => query::find_packets(root, query, &QueryContext::default())
+            .map_err(Error::from)?
+            .into_iter()
+            .map(|packet| packet.id)
+            .filter(|id| available.contains_key(id))
+            .collect(),
+    };
+    wanted.sort();
+
+    let core = config::read_config(root)?.core;
+    for id in plan_pull(wanted, &unpacked, &available, &packets, location_name)? {
+        let packet = &packets[&id];
+        for file in &packet.files {
+            report.files += pull_file(root, &core, driver, packet, file)?;
+        }
+        location::mark_packet_known(
+            &id,
+            config::LOCAL,
+            &available[&id].hash,
+            SystemTime::now(),
+            root,
+        )?;
+        report.packets.push(id);
+    }
+    Ok(report)
+}
+
+// Put one of a packet's files wherever this root keeps them, returning
+// the number of files that had to be downloaded.
+fn pull_file(
+    root: &str,
+    core: &config::Core,
+    driver: &dyn LocationDriver,
+    packet: &Packet,
+    file: &PacketFile,
+) -> io::Result<usize> {
+    let mut downloaded = 0;
+    if core.use_file_store && !store::file_exists(root, &file.hash)? {
+        store::put_from_reader(root, &mut driver.fetch_file(packet, file)?, &file.hash)?;
+        downloaded += 1;
+    }
+    if let Some(path_archive) = &core.path_archive {
+        let dest = store::archive_file_path(root, path_archive, packet, file)?;
+        if core.use_file_store {
+            let mut source = fs::File::open(store::file_path(root, &file.hash)?)?;
+            put_archive_file(&dest, &mut source, &file.hash)?;
+        } else {
+            put_archive_file(&dest, &mut driver.fetch_file(packet, file)?, &file.hash)?;
+            downloaded += 1;
+        }
+    }
+    Ok(downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Location;
+    use crate::test_utils::tests::{create_packet, get_empty_outpack_root};
+
+    const A: &str = "20230101-000000-00000001";
+    const B: &str = "20230101-000000-00000002";
+    const C: &str = "20230101-000000-00000003";
+
+    // A source root holding a <- b, and c on its own, along with an
+    // empty root with the source as location "origin".
+    fn create_roots(path_archive: Option<&str>, use_file_store: bool) -> (PathBuf, PathBuf) {
+        let src = get_empty_outpack_root(None, true, true);
+        create_packet(&src, "a", A, &[("data.csv", "a,b\n1,2\n")], &[]);
+        create_packet(&src, "b", B, &[("out.csv", "x\n3\n")], &[A]);
+        create_packet(&src, "c", C, &[("data.csv", "a,b\n1,2\n")], &[]);
+
+        let dest = get_empty_outpack_root(path_archive, use_file_store, true);
+        let origin = Location::new(
+            "origin",
+            LocationKind::Path {
+                path: src.to_str().unwrap().to_string(),
+            },
+        );
+        location::add_location(dest.to_str().unwrap(), origin).unwrap();
+        (src, dest)
+    }
+
+    fn unpacked(root: &str) -> Vec<String> {
+        let mut ids = metadata::get_ids(root, Some(true)).unwrap();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn can_pull_metadata() {
+        let (_src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        assert_eq!(pull_metadata(dest, "origin").unwrap(), 3);
+        assert_eq!(metadata::get_ids(dest, None).unwrap(), [A, B, C]);
+        assert_eq!(known_at(dest, "origin").unwrap().len(), 3);
+        assert_eq!(unpacked(dest).len(), 0);
+
+        assert_eq!(pull_metadata(dest, "origin").unwrap(), 0);
+    }
+
+    #[test]
+    fn can_pull_everything() {
+        let (_src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        let report = pull(dest, "origin", None).unwrap();
+        assert_eq!(report.metadata, 3);
+        assert_eq!(report.packets, [A, B, C]);
+        // The two packets with data.csv share it.
+        assert_eq!(report.files, 2);
+        assert_eq!(unpacked(dest), [A, B, C]);
+        assert!(crate::verify::verify(dest).unwrap().is_ok());
+
+        let report = pull(dest, "origin", None).unwrap();
+        assert_eq!(report, PullReport::default());
+    }
+
+    #[test]
+    fn pulls_dependencies_first() {
+        let (_src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        let report = pull(dest, "origin", Some("name == \"b\"")).unwrap();
+        assert_eq!(report.packets, [A, B]);
+        assert_eq!(unpacked(dest), [A, B]);
+    }
+
+    #[test]
+    fn can_pull_into_archive() {
+        let (_src, dest) = create_roots(Some("archive"), false);
+        let dest_str = dest.to_str().unwrap();
+        let report = pull(dest_str, "origin", Some("name == \"c\"")).unwrap();
+        assert_eq!(report.packets, [C]);
+        let path = dest.join("archive").join("c").join(C).join("data.csv");
+        assert_eq!(fs::read_to_string(path).unwrap(), "a,b\n1,2\n");
+    }
+
+    #[test]
+    fn pull_fails_if_dependencies_are_unavailable() {
+        let (src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        // The source has the metadata for a, but no longer its files.
+        let src_local = src.join(".outpack").join("location").join(config::LOCAL);
+        fs::remove_file(src_local.join(A)).unwrap();
+        let err = pull(dest, "origin", Some("name == \"b\"")).unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::PacketNotFound));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Can't pull from 'origin', as packets are not available there: \n {}",
+                A
+            )
+        );
+        assert_eq!(unpacked(dest).len(), 0);
+    }
+
+    #[test]
+    fn pull_checks_metadata_hash() {
+        let (src, dest) = create_roots(None, true);
+        let path = src.join(".outpack").join("metadata").join(C);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("\"c\"", "\"d\"")).unwrap();
+        let err = pull_metadata(dest.to_str().unwrap(), "origin").unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::HashMismatch));
+        assert!(err.to_string().starts_with(&format!(
+            "Metadata for '{}' from 'origin' does not match",
+            C
+        )));
+    }
+
+    // A location that serves whatever metadata it is given, with
+    // correct hashes, regardless of what it claims to be for.
+    struct FakeDriver {
+        metadata: Vec<(String, String)>,
+    }
+
+    impl LocationDriver for FakeDriver {
+        fn list(&self) -> io::Result<Vec<LocationEntry>> {
+            Ok(self
+                .metadata
+                .iter()
+                .map(|(id, text)| LocationEntry {
+                    packet: id.clone(),
+                    time: 0.0,
+                    hash: hash::hash_data(text.as_bytes(), hash::HashAlgorithm::Sha256).to_string(),
+                })
+                .collect())
+        }
+
+        fn metadata(&self, id: &str) -> io::Result<String> {
+            let (_, text) = self.metadata.iter().find(|(x, _)| x == id).unwrap();
+            Ok(text.clone())
+        }
+
+        fn fetch_file(&self, _: &Packet, _: &PacketFile) -> io::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(io::Cursor::new(b"a,b\n1,2\n".to_vec())))
+        }
+    }
+
+    fn source_metadata(src: &Path, id: &str) -> String {
+        fs::read_to_string(src.join(".outpack").join("metadata").join(id)).unwrap()
+    }
+
+    #[test]
+    fn pull_rejects_invalid_packet_ids() {
+        let (src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        let driver = FakeDriver {
+            metadata: vec![(String::from("../../x"), source_metadata(&src, A))],
+        };
+        let res = pull_metadata_from(dest, "origin", &driver);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "Location 'origin' lists invalid packet id '../../x'"
+        );
+        assert_eq!(metadata::get_ids(dest, None).unwrap().len(), 0);
+        assert_eq!(known_at(dest, "origin").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn pull_rejects_metadata_for_another_packet() {
+        let (src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        let driver = FakeDriver {
+            metadata: vec![(String::from(C), source_metadata(&src, A))],
+        };
+        let res = pull_metadata_from(dest, "origin", &driver);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!("Metadata for '{}' is for packet '{}'", C, A)
+        );
+        assert_eq!(metadata::get_ids(dest, None).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn pull_checks_metadata_schema() {
+        let (src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        let text = source_metadata(&src, C).replace("\"custom\":null", "\"custom\":1");
+        let driver = FakeDriver {
+            metadata: vec![(String::from(C), text)],
+        };
+        let res = pull_metadata_from(dest, "origin", &driver);
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid metadata, which does not match the schema"));
+        assert_eq!(metadata::get_ids(dest, None).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn pull_keeps_files_inside_archive() {
+        let (src, dest) = create_roots(Some("archive"), false);
+        let dest_str = dest.to_str().unwrap();
+        let core = config::read_config(dest_str).unwrap().core;
+        let driver = FakeDriver { metadata: vec![] };
+        let mut packet: Packet = serde_json::from_str(&source_metadata(&src, C)).unwrap();
+        let mut file = packet.files[0].clone();
+        file.path = String::from("../../escaped.csv");
+        let res = pull_file(dest_str, &core, &driver, &packet, &file);
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            format!(
+                "Packet '{}' has a file with invalid path 'c/../../escaped.csv'",
+                C
+            )
+        );
+
+        packet.name = String::from("..");
+        let res = pull_file(dest_str, &core, &driver, &packet, &packet.files[0]);
+        assert!(res.is_err());
+        assert!(!dest.join("escaped.csv").exists());
+        assert!(!dest.join("archive").join(C).exists());
+    }
+
+    #[test]
+    fn cannot_pull_from_local_or_unknown_locations() {
+        let (_src, dest) = create_roots(None, true);
+        let dest = dest.to_str().unwrap();
+        assert_eq!(
+            pull(dest, "local", None).unwrap_err().to_string(),
+            "Cannot pull from the 'local' location"
+        );
+        assert_eq!(
+            pull(dest, "other", None).unwrap_err().to_string(),
+            "No location with name 'other'"
+        );
+    }
+}
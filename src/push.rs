@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[cfg(feature = "client")]
+use crate::client::UPLOAD_CHUNK_SIZE;
+use crate::config::{self, LocationKind};
+use crate::error::{Error, ErrorCode};
+use crate::location::{self, LocationEntry};
+use crate::metadata::{self, Packet};
+#[cfg(feature = "client")]
+use crate::pull::HttpDriver;
+use crate::pull::{http_driver, known_at, LocationDriver, PathDriver};
+use crate::query::{self, QueryContext};
+use crate::{index, store};
+
+/// Somewhere packets can be pushed to.
+pub trait PushDriver {
+    /// Those of `ids` that the location does not have unpacked.
+    fn missing_packets(&self, ids: &[String]) -> io::Result<Vec<String>>;
+    /// Those of `hashes` that the location does not have.
+    fn missing_files(&self, hashes: &[String]) -> io::Result<Vec<String>>;
+    fn upload_file(&self, hash: &str, size: u64, contents: Box<dyn Read + Send>) -> io::Result<()>;
+    /// Add a packet's metadata; its files and dependencies must already
+    /// be present.
+    fn upload_metadata(&self, hash: &str, text: &str) -> io::Result<()>;
+}
+
+impl PushDriver for PathDriver {
+    fn missing_packets(&self, ids: &[String]) -> io::Result<Vec<String>> {
+        metadata::get_missing_ids(&self.root, ids, Some(true))
+    }
+
+    fn missing_files(&self, hashes: &[String]) -> io::Result<Vec<String>> {
+        store::get_missing_files(&self.root, hashes)
+    }
+
+    fn upload_file(
+        &self,
+        hash: &str,
+        _size: u64,
+        mut contents: Box<dyn Read + Send>,
+    ) -> io::Result<()> {
+        store::put_from_reader(&self.root, &mut contents, hash)
+    }
+
+    fn upload_metadata(&self, hash: &str, text: &str) -> io::Result<()> {
+        let hash = hash.parse()?;
+        metadata::add_metadata(&self.root, text, &hash)
+    }
+}
+
+#[cfg(feature = "client")]
+impl PushDriver for HttpDriver {
+    fn missing_packets(&self, ids: &[String]) -> io::Result<Vec<String>> {
+        Ok(self.client.missing_packets(ids, true)?)
+    }
+
+    fn missing_files(&self, hashes: &[String]) -> io::Result<Vec<String>> {
+        Ok(self.client.missing_files(hashes)?)
+    }
+
+    fn upload_file(&self, hash: &str, size: u64, contents: Box<dyn Read + Send>) -> io::Result<()> {
+        // Large files go through an upload session, so that a flaky
+        // connection does not mean starting again from scratch.
+        if size > UPLOAD_CHUNK_SIZE as u64 {
+            Ok(self
+                .client
+                .upload_file_in_chunks(hash, contents, UPLOAD_CHUNK_SIZE)?)
+        } else {
+            Ok(self.client.upload_file(hash, contents)?)
+        }
+    }
+
+    fn upload_metadata(&self, hash: &str, text: &str) -> io::Result<()> {
+        Ok(self.client.upload_packet(hash, text)?)
+    }
+}
+
+pub fn push_driver(root: &str, name: &str) -> io::Result<Box<dyn PushDriver>> {
+    match location::get_location(root, name)?.kind {
+        LocationKind::Local => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Cannot push to the '{}' location", config::LOCAL),
+        )),
+        LocationKind::Path { path } => {
+            // Relative paths are taken relative to the root.
+            let path = Path::new(root).join(path);
+            Ok(Box::new(PathDriver::new(&path.to_string_lossy())?))
+        }
+        LocationKind::Http { url } => Ok(Box::new(http_driver(&url)?)),
+        LocationKind::Other { kind, .. } => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Cannot push to '{}', as locations of type '{}' are not supported",
+                name, kind
+            ),
+        )),
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PushReport {
+    /// Ids of the packets uploaded, in the order they were uploaded.
+    pub packets: Vec<String>,
+    /// Number of files uploaded.
+    pub files: usize,
+}
+
+// The packets in `wanted` along with everything they depend on, with
+// dependencies before the packets that use them, so that the location
+// never sees a packet before its dependencies.
+fn dependency_closure(
+    wanted: &[String],
+    unpacked: &HashMap<String, LocationEntry>,
+    packets: &HashMap<String, Packet>,
+) -> io::Result<Vec<String>> {
+    fn visit(
+        id: &str,
+        packets: &HashMap<String, Packet>,
+        seen: &mut HashSet<String>,
+        order: &mut Vec<String>,
+    ) {
+        if !seen.insert(id.to_string()) {
+            return;
+        }
+        if let Some(packet) = packets.get(id) {
+            for dependency in &packet.depends {
+                visit(&dependency.packet, packets, seen, order);
+            }
+        }
+        order.push(id.to_string());
+    }
+
+    let mut seen = HashSet::new();
+    let mut order = Vec::new();
+    for id in wanted {
+        visit(id, packets, &mut seen, &mut order);
+    }
+    let mut missing: Vec<String> = order
+        .iter()
+        .filter(|id| !unpacked.contains_key(*id))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(Error::new(
+            ErrorCode::PacketNotFound,
+            format!(
+                "Can't push, as packets are not unpacked locally: \n {}",
+                missing.join(",")
+            ),
+        )
+        .into());
+    }
+    Ok(order)
+}
+
+/// Push the packets matching `query`, along with everything they depend
+/// on, to a location. Only the packets and files that the location does
+/// not already have are uploaded: first the files, and then the metadata
+/// of each packet after that of its dependencies.
+pub fn push(root: &str, location_name: &str, query: &str) -> io::Result<PushReport> {
+    let driver = push_driver(root, location_name)?;
+    push_to(root, location_name, driver.as_ref(), query)
+}
+
+fn push_to(
+    root: &str,
+    location_name: &str,
+    driver: &dyn PushDriver,
+    query: &str,
+) -> io::Result<PushReport> {
+    let unpacked = known_at(root, config::LOCAL)?;
+    let mut wanted: Vec<String> = query::find_packets(root, query, &QueryContext::default())
+        .map_err(Error::from)?
+        .into_iter()
+        .map(|packet| packet.id)
+        .collect();
+    wanted.sort();
+
+    let packets: HashMap<String, Packet> = index::read_packets(root)?
+        .iter()
+        .map(|packet| (packet.id.clone(), packet.clone()))
+        .collect();
+    let order = dependency_closure(&wanted, &unpacked, &packets)?;
+
+    let missing: HashSet<String> = driver.missing_packets(&order)?.into_iter().collect();
+    let order = order
+        .iter()
+        .filter(|id| missing.contains(*id))
+        .map(|id| {
+            packets.get(id).ok_or_else(|| {
+                Error::new(
+                    ErrorCode::PacketNotFound,
+                    format!("Can't push '{}', as its metadata can't be read", id),
+                )
+                .into()
+            })
+        })
+        .collect::<io::Result<Vec<&Packet>>>()?;
+
+    let mut hashes: Vec<String> = order
+        .iter()
+        .flat_map(|packet| packet.files.iter().map(|file| file.hash.clone()))
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+    hashes.sort();
+    let missing_files: HashSet<String> = driver.missing_files(&hashes)?.into_iter().collect();
+
+    let mut report = PushReport::default();
+    let local = PathDriver::new(root)?;
+    let mut uploaded = HashSet::new();
+    for packet in &order {
+        for file in &packet.files {
+            if missing_files.contains(&file.hash) && uploaded.insert(file.hash.clone()) {
+                driver.upload_file(
+                    &file.hash,
+                    file.size as u64,
+                    local.fetch_file(packet, file)?,
+                )?;
+                report.files += 1;
+            }
+        }
+    }
+
+    for packet in order {
+        let hash = &unpacked[&packet.id].hash;
+        driver.upload_metadata(hash, &metadata::get_metadata_text(root, &packet.id)?)?;
+        location::mark_packet_known(&packet.id, location_name, hash, SystemTime::now(), root)?;
+        report.packets.push(packet.id.clone());
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Location;
+    use crate::test_utils::tests::{create_packet, get_empty_outpack_root};
+    use std::fs;
+    use std::path::PathBuf;
+
+    const A: &str = "20230101-000000-00000001";
+    const B: &str = "20230101-000000-00000002";
+    const C: &str = "20230101-000000-00000003";
+
+    // A root holding a <- b, and c on its own, along with an empty root
+    // that it knows as location "dest".
+    fn create_roots() -> (PathBuf, PathBuf) {
+        let src = get_empty_outpack_root(None, true, true);
+        create_packet(&src, "a", A, &[("data.csv", "a,b\n1,2\n")], &[]);
+        create_packet(&src, "b", B, &[("out.csv", "x\n3\n")], &[A]);
+        create_packet(&src, "c", C, &[("data.csv", "a,b\n1,2\n")], &[]);
+
+        let dest = get_empty_outpack_root(None, true, true);
+        let location = Location::new(
+            "dest",
+            LocationKind::Path {
+                path: dest.to_str().unwrap().to_string(),
+            },
+        );
+        location::add_location(src.to_str().unwrap(), location).unwrap();
+        (src, dest)
+    }
+
+    fn unpacked(root: &Path) -> Vec<String> {
+        let mut ids = metadata::get_ids(root.to_str().unwrap(), Some(true)).unwrap();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn pushes_dependencies_first() {
+        let (src, dest) = create_roots();
+        let src_str = src.to_str().unwrap();
+        let report = push(src_str, "dest", "name == \"b\"").unwrap();
+        assert_eq!(report.packets, [A, B]);
+        assert_eq!(report.files, 2);
+        assert_eq!(unpacked(&dest), [A, B]);
+        let mut known: Vec<String> = known_at(src_str, "dest").unwrap().into_keys().collect();
+        known.sort();
+        assert_eq!(known, [A, B]);
+        assert!(crate::verify::verify(dest.to_str().unwrap())
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn only_uploads_what_is_missing() {
+        let (src, dest) = create_roots();
+        let src = src.to_str().unwrap();
+        push(src, "dest", "name == \"a\"").unwrap();
+
+        // c's only file is shared with a, so is already there.
+        let report = push(src, "dest", "name == \"a\" || name == \"c\"").unwrap();
+        assert_eq!(report.packets, [C]);
+        assert_eq!(report.files, 0);
+        assert_eq!(unpacked(&dest), [A, C]);
+
+        let report = push(src, "dest", "name == \"c\"").unwrap();
+        assert_eq!(report, PushReport::default());
+    }
+
+    #[test]
+    fn push_fails_if_dependencies_are_not_unpacked() {
+        let (src, dest) = create_roots();
+        let local = src.join(".outpack").join("location").join(config::LOCAL);
+        fs::remove_file(local.join(A)).unwrap();
+        let err = push(src.to_str().unwrap(), "dest", "name == \"b\"").unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::PacketNotFound));
+        assert_eq!(
+            err.to_string(),
+            format!("Can't push, as packets are not unpacked locally: \n {}", A)
+        );
+
+        let err = push(src.to_str().unwrap(), "dest", "name ==").unwrap_err();
+        assert_eq!(
+            crate::error::code_of(&err),
+            Some(ErrorCode::QueryParseError)
+        );
+        assert_eq!(unpacked(&dest).len(), 0);
+    }
+
+    #[test]
+    fn push_fails_if_metadata_of_dependencies_is_missing() {
+        let (src, dest) = create_roots();
+        let metadata = src.join(".outpack").join("metadata");
+        fs::remove_file(metadata.join(A)).unwrap();
+        let err = push(src.to_str().unwrap(), "dest", "name == \"b\"").unwrap_err();
+        assert_eq!(crate::error::code_of(&err), Some(ErrorCode::PacketNotFound));
+        assert_eq!(
+            err.to_string(),
+            format!("Can't push '{}', as its metadata can't be read", A)
+        );
+        assert_eq!(unpacked(&dest).len(), 0);
+    }
+
+    #[test]
+    fn cannot_push_to_local_or_unknown_locations() {
+        let (src, _dest) = create_roots();
+        let src = src.to_str().unwrap();
+        assert_eq!(
+            push(src, "local", "name == \"a\"").unwrap_err().to_string(),
+            "Cannot push to the 'local' location"
+        );
+        assert_eq!(
+            push(src, "other", "name == \"a\"").unwrap_err().to_string(),
+            "No location with name 'other'"
+        );
+    }
+}
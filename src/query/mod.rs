@@ -7,29 +7,55 @@ mod test_utils_query;
 
 extern crate pest;
 
-use crate::index::get_packet_index;
+use crate::index::{get_packet_index, Index};
+use crate::metadata::Packet;
 use crate::query::query_eval::eval_query;
 use crate::query::query_format::format_query_result;
 use crate::query::query_parse::Rule;
+use pest::error::LineColLocation;
 use std::fmt;
+use std::sync::Arc;
 
 pub use crate::query::query_parse::parse_query;
+pub use crate::query::query_types::QueryContext;
 
-pub fn run_query(root: &str, query: &str) -> Result<String, QueryError> {
-    let index = match get_packet_index(root) {
-        Ok(index) => index,
-        Err(e) => {
-            return Err(QueryError::EvalError(format!(
-                "Could not build outpack index from root at {}: {:?}",
-                root, e
-            )))
-        }
-    };
+pub fn run_query(root: &str, query: &str, context: &QueryContext) -> Result<String, QueryError> {
+    let index = get_index(root)?;
     let parsed = parse_query(query)?;
-    let result = eval_query(&index, parsed);
+    let result = eval_query(&index, parsed, context);
     format_query_result(result)
 }
 
+/// Evaluate a query against the packets in the root, returning the matching
+/// packets rather than a formatted string.
+pub fn find_packets(
+    root: &str,
+    query: &str,
+    context: &QueryContext,
+) -> Result<Vec<Packet>, QueryError> {
+    find_packets_in(&*get_index(root)?, query, context)
+}
+
+/// As `find_packets`, but against a given index.
+pub fn find_packets_in(
+    index: &Index,
+    query: &str,
+    context: &QueryContext,
+) -> Result<Vec<Packet>, QueryError> {
+    let parsed = parse_query(query)?;
+    let packets = eval_query(index, parsed, context)?;
+    Ok(packets.into_iter().cloned().collect())
+}
+
+fn get_index(root: &str) -> Result<Arc<Index>, QueryError> {
+    get_packet_index(root).map_err(|e| {
+        QueryError::EvalError(format!(
+            "Could not build outpack index from root at {}: {:?}",
+            root, e
+        ))
+    })
+}
+
 #[derive(Debug, Clone)]
 // Results with QueryError are at least as large as the QueryError variant. The compiler
 // will need to reserve that much memory every time it is used. We want to keep this as
@@ -40,6 +66,19 @@ pub enum QueryError {
     EvalError(String),
 }
 
+impl QueryError {
+    /// The (line, column) in the query at which parsing failed, if known.
+    pub fn position(&self) -> Option<(usize, usize)> {
+        match self {
+            QueryError::ParseError(err) => match err.line_col {
+                LineColLocation::Pos(pos) => Some(pos),
+                LineColLocation::Span(start, _) => Some(start),
+            },
+            QueryError::EvalError(_) => None,
+        }
+    }
+}
+
 impl fmt::Display for QueryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -48,3 +87,5 @@ impl fmt::Display for QueryError {
         }
     }
 }
+
+impl std::error::Error for QueryError {}
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use serde_json::value::Value as JsonValue;
 
@@ -7,23 +7,34 @@ use crate::metadata::Packet;
 use crate::query::query_types::*;
 use crate::query::QueryError;
 
-pub fn eval_query<'a>(index: &'a Index, query: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
+pub fn eval_query<'a>(
+    index: &'a Index,
+    query: QueryNode,
+    context: &QueryContext,
+) -> Result<Vec<&'a Packet>, QueryError> {
     match query {
-        QueryNode::Latest(inner) => eval_latest(index, inner),
-        QueryNode::Single(inner) => eval_single(index, *inner),
-        QueryNode::Test(test, lhs, rhs) => eval_test(index, test, lhs, rhs),
-        QueryNode::Negation(inner) => eval_negation(index, *inner),
-        QueryNode::Brackets(inner) => eval_brackets(index, *inner),
-        QueryNode::BooleanOperator(op, lhs, rhs) => eval_boolean_op(index, op, *lhs, *rhs),
+        QueryNode::Latest(inner) => eval_latest(index, inner, context),
+        QueryNode::Single(inner) => eval_single(index, *inner, context),
+        QueryNode::Test(test, lhs, rhs) => eval_test(index, test, lhs, rhs, context),
+        QueryNode::Negation(inner) => eval_negation(index, *inner, context),
+        QueryNode::Brackets(inner) => eval_brackets(index, *inner, context),
+        QueryNode::BooleanOperator(op, lhs, rhs) => eval_boolean_op(index, op, *lhs, *rhs, context),
+        QueryNode::UsedBy(inner, depth) => {
+            eval_dependencies(index, *inner, depth, Index::dependencies, context)
+        }
+        QueryNode::Uses(inner, depth) => {
+            eval_dependencies(index, *inner, depth, Index::dependents, context)
+        }
     }
 }
 
 fn eval_latest<'a>(
     index: &'a Index,
     inner: Option<Box<QueryNode>>,
+    context: &QueryContext,
 ) -> Result<Vec<&'a Packet>, QueryError> {
-    if inner.is_some() {
-        let latest = eval_query(index, *inner.unwrap())?;
+    if let Some(inner) = inner {
+        let latest = eval_query(index, *inner, context)?;
         let last = latest.last();
         match last {
             Some(packet) => Ok(vec![*packet]),
@@ -38,8 +49,12 @@ fn eval_latest<'a>(
     }
 }
 
-fn eval_single<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
-    let packets = eval_query(index, inner)?;
+fn eval_single<'a>(
+    index: &'a Index,
+    inner: QueryNode,
+    context: &QueryContext,
+) -> Result<Vec<&'a Packet>, QueryError> {
+    let packets = eval_query(index, inner, context)?;
     if packets.len() != 1 {
         Err(QueryError::EvalError(format!(
             "Query found {} packets, but expected exactly one",
@@ -50,8 +65,12 @@ fn eval_single<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>
     }
 }
 
-fn eval_negation<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
-    let packets = eval_query(index, inner)?;
+fn eval_negation<'a>(
+    index: &'a Index,
+    inner: QueryNode,
+    context: &QueryContext,
+) -> Result<Vec<&'a Packet>, QueryError> {
+    let packets = eval_query(index, inner, context)?;
     Ok(index
         .packets
         .iter()
@@ -59,8 +78,43 @@ fn eval_negation<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packe
         .collect())
 }
 
-fn eval_brackets<'a>(index: &'a Index, inner: QueryNode) -> Result<Vec<&'a Packet>, QueryError> {
-    eval_query(index, inner)
+fn eval_brackets<'a>(
+    index: &'a Index,
+    inner: QueryNode,
+    context: &QueryContext,
+) -> Result<Vec<&'a Packet>, QueryError> {
+    eval_query(index, inner, context)
+}
+
+/// Walk the dependency graph outwards from the packets found by `inner`,
+/// following `edges` for at most `depth` steps. A packet found by `inner`
+/// is only in the result if it can be reached from another one, e.g.
+/// `usedby` of two packets, one using the other, includes the one used.
+fn eval_dependencies<'a>(
+    index: &'a Index,
+    inner: QueryNode,
+    depth: Option<usize>,
+    edges: fn(&'a Index, &str) -> &'a [String],
+    context: &QueryContext,
+) -> Result<Vec<&'a Packet>, QueryError> {
+    let packets = eval_query(index, inner, context)?;
+    let mut found: HashSet<&str> = HashSet::new();
+    let mut frontier: Vec<&str> = packets.iter().map(|packet| &packet.id[..]).collect();
+    let mut steps = 0;
+    while !frontier.is_empty() && depth.map_or(true, |depth| steps < depth) {
+        frontier = frontier
+            .into_iter()
+            .flat_map(|id| edges(index, id))
+            .map(|id| &id[..])
+            .filter(|id| found.insert(id))
+            .collect();
+        steps += 1;
+    }
+    Ok(index
+        .packets
+        .iter()
+        .filter(|packet| found.contains(&packet.id[..]))
+        .collect())
 }
 
 fn eval_test<'a>(
@@ -68,15 +122,18 @@ fn eval_test<'a>(
     test: Test,
     lhs: TestValue,
     rhs: TestValue,
+    context: &QueryContext,
 ) -> Result<Vec<&'a Packet>, QueryError> {
     index
         .packets
         .iter()
-        .filter_map(|packet| match lookup_filter(packet, &test, &lhs, &rhs) {
-            Ok(true) => Some(Ok(packet)),
-            Ok(false) => None,
-            Err(err) => Some(Err(err)),
-        })
+        .filter_map(
+            |packet| match lookup_filter(packet, &test, &lhs, &rhs, context) {
+                Ok(true) => Some(Ok(packet)),
+                Ok(false) => None,
+                Err(err) => Some(Err(err)),
+            },
+        )
         .collect()
 }
 
@@ -85,9 +142,23 @@ fn lookup_filter(
     test: &Test,
     lhs: &TestValue,
     rhs: &TestValue,
+    context: &QueryContext,
 ) -> Result<bool, QueryError> {
-    let lhs_literal = evaluate_test_value(packet, lhs)?;
-    let rhs_literal = evaluate_test_value(packet, rhs)?;
+    let lhs_literal = evaluate_test_value(packet, lhs, context)?;
+
+    if let (Test::In, TestValue::List(items)) = (test, rhs) {
+        if lhs_literal.is_none() {
+            return Ok(false);
+        }
+        for item in items {
+            if evaluate_test_value(packet, item, context)? == lhs_literal {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    let rhs_literal = evaluate_test_value(packet, rhs, context)?;
 
     Ok(match (test, lhs_literal, rhs_literal) {
         (test, Some(Literal::Number(l)), Some(Literal::Number(r))) => match test {
@@ -97,6 +168,21 @@ fn lookup_filter(
             Test::LessThanOrEqual => l <= r,
             Test::GreaterThan => l > r,
             Test::GreaterThanOrEqual => l >= r,
+            _ => false,
+        },
+        (test, Some(Literal::String(l)), Some(Literal::String(r))) => match test {
+            Test::Equal => l == r,
+            Test::NotEqual => l != r,
+            Test::LessThan => l < r,
+            Test::LessThanOrEqual => l <= r,
+            Test::GreaterThan => l > r,
+            Test::GreaterThanOrEqual => l >= r,
+            Test::Matches(re) => re.is_match(l),
+            Test::StartsWith => l.starts_with(r),
+            Test::EndsWith => l.ends_with(r),
+            Test::Contains => l.contains(r),
+            Test::EqualIgnoreCase => l.to_lowercase() == r.to_lowercase(),
+            Test::In => false,
         },
         (Test::Equal, Some(l), Some(r)) => l == r,
         (Test::NotEqual, Some(l), Some(r)) => l != r,
@@ -107,48 +193,94 @@ fn lookup_filter(
 fn evaluate_test_value<'a>(
     packet: &'a Packet,
     value: &'a TestValue,
+    context: &'a QueryContext,
 ) -> Result<Option<Literal<'a>>, QueryError> {
     match value {
         TestValue::Literal(value) => Ok(Some(value.clone())),
-        TestValue::Lookup(lookup) => evaluate_lookup(packet, lookup),
+        TestValue::Lookup(lookup) => evaluate_lookup(packet, lookup, context),
+        TestValue::List(_) => Err(QueryError::EvalError(
+            "A list can only be used on the right of 'in'".to_string(),
+        )),
     }
 }
 
 fn evaluate_lookup<'a>(
     packet: &'a Packet,
     lookup: &'a Lookup,
+    context: &'a QueryContext,
 ) -> Result<Option<Literal<'a>>, QueryError> {
     match lookup {
         Lookup::Packet(lookup) => Ok(packet.lookup_value(lookup)),
-        Lookup::Environment(_) => Err(QueryError::EvalError(
-            "environment lookup is not supported in this context".into(),
-        )),
-        Lookup::This(_) => Err(QueryError::EvalError(
-            "this parameters are not supported in this context".into(),
-        )),
+        Lookup::Environment(name) => {
+            lookup_context_value(&context.environment, name, "environment variable")
+        }
+        Lookup::This(name) => lookup_context_value(&context.this, name, "this parameter"),
+    }
+}
+
+/// Unlike packet lookups, where a missing value just means the packet
+/// does not match, values looked up from the context must be present
+/// and scalar; anything else is almost certainly a mistake in the
+/// query (or the values supplied alongside it).
+fn lookup_context_value<'a>(
+    values: &'a HashMap<String, JsonValue>,
+    name: &str,
+    description: &str,
+) -> Result<Option<Literal<'a>>, QueryError> {
+    let value = values.get(name).ok_or_else(|| {
+        QueryError::EvalError(format!("{} '{}' was not provided", description, name))
+    })?;
+    match json_to_literal(value) {
+        Some(literal) => Ok(Some(literal)),
+        None => Err(QueryError::EvalError(format!(
+            "{} '{}' must be a number, boolean or string",
+            description, name
+        ))),
+    }
+}
+
+fn json_to_literal(value: &JsonValue) -> Option<Literal<'_>> {
+    match value {
+        JsonValue::Number(number) => Some(Literal::Number(number.as_f64()?)),
+        JsonValue::Bool(bool) => Some(Literal::Bool(*bool)),
+        JsonValue::String(string) => Some(Literal::String(string)),
+        _ => None,
     }
 }
 
 impl Packet {
-    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal> {
+    pub fn lookup_value(&self, lookup: &PacketLookup) -> Option<Literal<'_>> {
         match lookup {
             PacketLookup::Id => Some(Literal::String(&self.id)),
             PacketLookup::Name => Some(Literal::String(&self.name)),
             PacketLookup::Parameter(param_name) => self.get_parameter(param_name),
+            PacketLookup::StartTime => self.start_time().map(Literal::Number),
+            PacketLookup::EndTime => self.end_time().map(Literal::Number),
+            PacketLookup::GitSha => self.git_sha().map(Literal::String),
+            PacketLookup::GitBranch => self.git_branch().map(Literal::String),
+            PacketLookup::Custom(path) => self.get_custom(path),
         }
     }
 
-    pub fn get_parameter(&self, param_name: &str) -> Option<Literal> {
-        if let Some(params) = &self.parameters {
-            match params.get(param_name)? {
-                JsonValue::Number(number) => Some(Literal::Number(number.as_f64()?)),
-                JsonValue::Bool(bool) => Some(Literal::Bool(*bool)),
-                JsonValue::String(string) => Some(Literal::String(string)),
-                _ => None, // Parameters must be number, bool or string
-            }
-        } else {
-            None
+    /// The value at `path` within the packet's custom metadata, where
+    /// numeric keys also index into arrays. Missing values, nulls,
+    /// objects and arrays are all treated as there being no value.
+    pub fn get_custom(&self, path: &[&str]) -> Option<Literal<'_>> {
+        let (application, keys) = path.split_first()?;
+        let mut value = self.custom(application)?;
+        for key in keys {
+            value = match value {
+                JsonValue::Object(map) => map.get(*key)?,
+                JsonValue::Array(items) => items.get(key.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
         }
+        json_to_literal(value)
+    }
+
+    pub fn get_parameter(&self, param_name: &str) -> Option<Literal<'_>> {
+        // Parameters must be number, bool or string
+        json_to_literal(self.parameter(param_name)?)
     }
 }
 
@@ -157,9 +289,10 @@ fn eval_boolean_op<'a>(
     op: Operator,
     lhs: QueryNode,
     rhs: QueryNode,
+    context: &QueryContext,
 ) -> Result<Vec<&'a Packet>, QueryError> {
-    let lhs_res = eval_query(index, lhs)?;
-    let rhs_res = eval_query(index, rhs)?;
+    let lhs_res = eval_query(index, lhs, context)?;
+    let rhs_res = eval_query(index, rhs, context)?;
     let lhs_set: HashSet<&Packet> = HashSet::from_iter(lhs_res.iter().cloned());
     let rhs_set: HashSet<&Packet> = HashSet::from_iter(rhs_res.iter().cloned());
     match op {
@@ -184,7 +317,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Id)),
             TestValue::Literal(Literal::String("20180818-164043-7cdcde4b")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let query = QueryNode::Test(
@@ -192,7 +325,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
             TestValue::Literal(Literal::String("modup-201707-queries1")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(
             res,
             vec![
@@ -207,7 +340,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Id)),
             TestValue::Literal(Literal::String("123")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
 
         let query = QueryNode::Test(
@@ -215,7 +348,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
             TestValue::Literal(Literal::String("YF")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 3);
 
         let query = QueryNode::Test(
@@ -223,7 +356,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("foo"))),
             TestValue::Literal(Literal::String("bar")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
     }
 
@@ -232,7 +365,7 @@ mod tests {
         let index = crate::index::get_packet_index("tests/example").unwrap();
 
         let query = QueryNode::Latest(None);
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let inner_query = QueryNode::Test(
@@ -241,7 +374,7 @@ mod tests {
             TestValue::Literal(Literal::String("modup-201707-queries1")),
         );
         let query = QueryNode::Latest(Some(Box::new(inner_query)));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let inner_query = QueryNode::Test(
@@ -250,7 +383,7 @@ mod tests {
             TestValue::Literal(Literal::String("123")),
         );
         let query = QueryNode::Latest(Some(Box::new(inner_query)));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
     }
 
@@ -317,9 +450,9 @@ mod tests {
             ( $( $test:expr, $lhs:expr, $rhs:expr => $result:literal )* ) => {
                 $(
                 if $result {
-                    assert!(lookup_filter(&packet, $test, $lhs, $rhs).unwrap());
+                    assert!(lookup_filter(&packet, $test, $lhs, $rhs, &QueryContext::default()).unwrap());
                 } else {
-                    assert!(!lookup_filter(&packet, $test, $lhs, $rhs).unwrap());
+                    assert!(!lookup_filter(&packet, $test, $lhs, $rhs, &QueryContext::default()).unwrap());
                 }
                 )*
             };
@@ -355,9 +488,15 @@ mod tests {
             &Test::LessThan, &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))), &TestValue::Literal(Literal::Bool(false)) => false
 
             &Test::LessThan,           &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => false
-            &Test::LessThanOrEqual,    &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => false
+            &Test::LessThanOrEqual,    &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => true
             &Test::GreaterThan,        &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => false
-            &Test::GreaterThanOrEqual, &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => false
+            &Test::GreaterThanOrEqual, &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("YF")) => true
+            &Test::LessThan,           &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("Z"))  => true
+            &Test::GreaterThan,        &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("Y"))  => true
+            &Test::StartsWith,         &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("Y"))  => true
+            &Test::EndsWith,           &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("Y"))  => false
+            &Test::Contains,           &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("F"))  => true
+            &Test::EqualIgnoreCase,    &TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))), &TestValue::Literal(Literal::String("yf")) => true
 
             &Test::Equal, &TestValue::Literal(Literal::Number(0.001)), &TestValue::Literal(Literal::Number(0.001))    => true
             &Test::Equal, &TestValue::Literal(Literal::Number(0.001)), &TestValue::Literal(Literal::Number(0.002))    => false
@@ -380,14 +519,14 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
             TestValue::Literal(Literal::String("modup-201707-params1")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
         let query = QueryNode::Test(
             Test::Equal,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(10f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let query = QueryNode::Test(
@@ -395,35 +534,35 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(10.1f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
         let query = QueryNode::Test(
             Test::GreaterThan,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(9.4f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
         let query = QueryNode::Test(
             Test::GreaterThan,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(10f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
             Test::GreaterThanOrEqual,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(10f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
         let query = QueryNode::Test(
             Test::LessThanOrEqual,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
             TestValue::Literal(Literal::Number(10f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
 
         let query = QueryNode::Test(
@@ -431,14 +570,14 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::Bool(false)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
         let query = QueryNode::Test(
             Test::NotEqual,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::Bool(true)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
     }
 
@@ -449,30 +588,16 @@ mod tests {
         let query = QueryNode::Test(
             Test::GreaterThan,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
-            TestValue::Literal(Literal::String("ABC")),
-        );
-        let res = eval_query(&index, query).unwrap();
-        assert_eq!(res.len(), 0);
-        let query = QueryNode::Test(
-            Test::LessThan,
-            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
-            TestValue::Literal(Literal::String("ABC")),
+            TestValue::Literal(Literal::Number(1.0)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
-            Test::GreaterThanOrEqual,
-            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
-            TestValue::Literal(Literal::String("YF")),
-        );
-        let res = eval_query(&index, query).unwrap();
-        assert_eq!(res.len(), 0);
-        let query = QueryNode::Test(
-            Test::LessThanOrEqual,
-            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
-            TestValue::Literal(Literal::String("YF")),
+            Test::StartsWith,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
+            TestValue::Literal(Literal::String("1")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
 
         let query = QueryNode::Test(
@@ -480,14 +605,14 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::Bool(true)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
             Test::LessThanOrEqual,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::Bool(false)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
     }
 
@@ -500,28 +625,28 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::String("TRUE")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
             Test::Equal,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::String("true")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
             Test::Equal,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::String("T")),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
         let query = QueryNode::Test(
             Test::Equal,
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("pull_data"))),
             TestValue::Literal(Literal::Number(1f64)),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_eq!(res.len(), 0);
     }
 
@@ -530,7 +655,7 @@ mod tests {
         let index = crate::index::get_packet_index("tests/example").unwrap();
 
         let query = QueryNode::Negation(Box::new(QueryNode::Latest(None)));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(
             res,
             vec![
@@ -543,7 +668,7 @@ mod tests {
         let query = QueryNode::Negation(Box::new(QueryNode::Negation(Box::new(
             QueryNode::Latest(None),
         ))));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
     }
 
@@ -552,19 +677,19 @@ mod tests {
         let index = crate::index::get_packet_index("tests/example").unwrap();
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Latest(None)));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Brackets(Box::new(
             QueryNode::Latest(None),
         ))));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let query = QueryNode::Brackets(Box::new(QueryNode::Negation(Box::new(
             QueryNode::Latest(None),
         ))));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(
             res,
             vec![
@@ -588,7 +713,7 @@ mod tests {
                 TestValue::Literal(Literal::String("modup-201707-params1")),
             )),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(
             res,
             vec!["20180818-164043-7cdcde4b", "20180220-095832-16a4bbed"],
@@ -603,7 +728,7 @@ mod tests {
                 TestValue::Literal(Literal::String("modup-201707-params1")),
             )),
         );
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
     }
 
@@ -612,13 +737,13 @@ mod tests {
         let index = crate::index::get_packet_index("tests/example").unwrap();
 
         let query = QueryNode::Single(Box::new(QueryNode::Latest(None)));
-        let res = eval_query(&index, query).unwrap();
+        let res = eval_query(&index, query, &QueryContext::default()).unwrap();
         assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
 
         let query = QueryNode::Single(Box::new(QueryNode::Negation(Box::new(QueryNode::Latest(
             None,
         )))));
-        let e = eval_query(&index, query).unwrap_err();
+        let e = eval_query(&index, query, &QueryContext::default()).unwrap_err();
         assert!(matches!(e, QueryError::EvalError(..)));
         assert!(e
             .to_string()
@@ -634,7 +759,7 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("x"))),
         );
 
-        let e = eval_query(&index, query).unwrap_err();
+        let e = eval_query(&index, query, &QueryContext::default()).unwrap_err();
         assert!(matches!(e, QueryError::EvalError(..)));
     }
 
@@ -647,7 +772,358 @@ mod tests {
             TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("x"))),
         );
 
-        let e = eval_query(&index, query).unwrap_err();
+        let e = eval_query(&index, query, &QueryContext::default()).unwrap_err();
+        assert!(matches!(e, QueryError::EvalError(..)));
+    }
+
+    #[test]
+    fn query_with_this_uses_context() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let context = QueryContext {
+            this: HashMap::from([
+                (String::from("disease"), JsonValue::from("YF")),
+                (String::from("size"), JsonValue::from(10)),
+            ]),
+            environment: HashMap::new(),
+        };
+
+        let query = QueryNode::Test(
+            Test::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("disease"))),
+            TestValue::Lookup(Lookup::This("disease")),
+        );
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(
+            res,
+            vec![
+                "20170818-164830-33e0ab01",
+                "20180220-095832-16a4bbed",
+                "20180818-164043-7cdcde4b",
+            ],
+        );
+
+        let query = QueryNode::Test(
+            Test::LessThanOrEqual,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("size"))),
+            TestValue::Lookup(Lookup::This("size")),
+        );
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+
+        let query = QueryNode::Test(
+            Test::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("x"))),
+            TestValue::Lookup(Lookup::This("x")),
+        );
+        let e = eval_query(&index, query, &context).unwrap_err();
+        assert!(matches!(e, QueryError::EvalError(..)));
+        assert!(e
+            .to_string()
+            .contains("this parameter 'x' was not provided"));
+    }
+
+    #[test]
+    fn query_with_environment_uses_context() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let context = QueryContext {
+            this: HashMap::new(),
+            environment: HashMap::from([
+                (
+                    String::from("name"),
+                    JsonValue::from("modup-201707-params1"),
+                ),
+                (String::from("list"), JsonValue::from(vec![1, 2])),
+            ]),
+        };
+
+        let query = QueryNode::Test(
+            Test::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+            TestValue::Lookup(Lookup::Environment("name")),
+        );
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+
+        let query = QueryNode::Test(
+            Test::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+            TestValue::Lookup(Lookup::Environment("list")),
+        );
+        let e = eval_query(&index, query, &context).unwrap_err();
         assert!(matches!(e, QueryError::EvalError(..)));
+        assert!(e
+            .to_string()
+            .contains("environment variable 'list' must be a number, boolean or string"));
+    }
+
+    fn dependency_index() -> Index {
+        // a <- b <- c, a <- d; e is unrelated
+        fn make_packet(id: &str, depends: &[&str]) -> Packet {
+            let depends: Vec<JsonValue> = depends
+                .iter()
+                .map(|id| serde_json::json!({ "packet": id, "files": [] }))
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "schema_version": "0.1.1",
+                "id": id,
+                "name": "example",
+                "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+                "custom": null,
+                "parameters": null,
+                "files": [],
+                "depends": depends
+            }))
+            .unwrap()
+        }
+        Index::new(vec![
+            make_packet("20230101-000000-0000000a", &[]),
+            make_packet("20230101-000000-0000000b", &["20230101-000000-0000000a"]),
+            make_packet("20230101-000000-0000000c", &["20230101-000000-0000000b"]),
+            make_packet("20230101-000000-0000000d", &["20230101-000000-0000000a"]),
+            make_packet("20230101-000000-0000000e", &[]),
+        ])
+    }
+
+    fn id_test(id: &str) -> Box<QueryNode<'_>> {
+        Box::new(QueryNode::Test(
+            Test::Equal,
+            TestValue::Lookup(Lookup::Packet(PacketLookup::Id)),
+            TestValue::Literal(Literal::String(id)),
+        ))
+    }
+
+    #[test]
+    fn query_with_usedby_works() {
+        let index = dependency_index();
+        let context = QueryContext::default();
+
+        let query = QueryNode::UsedBy(id_test("20230101-000000-0000000c"), None);
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(
+            res,
+            vec!["20230101-000000-0000000a", "20230101-000000-0000000b"],
+        );
+
+        let query = QueryNode::UsedBy(id_test("20230101-000000-0000000c"), Some(1));
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(res, vec!["20230101-000000-0000000b"]);
+
+        let query = QueryNode::UsedBy(id_test("20230101-000000-0000000a"), None);
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_eq!(res.len(), 0);
+    }
+
+    #[test]
+    fn dependencies_include_inputs_reached_from_other_inputs() {
+        let index = dependency_index();
+        let res = eval_str(
+            &index,
+            "usedby(id == '20230101-000000-0000000c' || id == '20230101-000000-0000000b')",
+        );
+        assert_packet_ids_eq(
+            res,
+            vec!["20230101-000000-0000000a", "20230101-000000-0000000b"],
+        );
+        let res = eval_str(
+            &index,
+            "uses(id == '20230101-000000-0000000a' || id == '20230101-000000-0000000b', 1)",
+        );
+        assert_packet_ids_eq(
+            res,
+            vec![
+                "20230101-000000-0000000b",
+                "20230101-000000-0000000c",
+                "20230101-000000-0000000d",
+            ],
+        );
+    }
+
+    #[test]
+    fn query_with_uses_works() {
+        let index = dependency_index();
+        let context = QueryContext::default();
+
+        let query = QueryNode::Uses(id_test("20230101-000000-0000000a"), None);
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(
+            res,
+            vec![
+                "20230101-000000-0000000b",
+                "20230101-000000-0000000c",
+                "20230101-000000-0000000d",
+            ],
+        );
+
+        let query = QueryNode::Uses(id_test("20230101-000000-0000000a"), Some(1));
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(
+            res,
+            vec!["20230101-000000-0000000b", "20230101-000000-0000000d"],
+        );
+
+        let query = QueryNode::Uses(id_test("20230101-000000-0000000e"), None);
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_eq!(res.len(), 0);
+
+        // Results are in index order, so can be combined with latest
+        let query = QueryNode::Latest(Some(Box::new(QueryNode::Uses(
+            id_test("20230101-000000-0000000a"),
+            None,
+        ))));
+        let res = eval_query(&index, query, &context).unwrap();
+        assert_packet_ids_eq(res, vec!["20230101-000000-0000000d"]);
+    }
+
+    fn eval_str<'a>(index: &'a Index, query: &str) -> Vec<&'a Packet> {
+        let query = crate::query::parse_query(query).unwrap();
+        eval_query(index, query, &QueryContext::default()).unwrap()
+    }
+
+    #[test]
+    fn can_query_time() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let res = eval_str(&index, "time:start > 1519120570");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "time:start > date('2018-01-01')");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "time:start > 2018-02-20T09:56:00Z - minutes(1)");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "time:start > now() - days(7)");
+        assert!(res.is_empty());
+        let res = eval_str(&index, "time:end < 1519120712 && time:start >= 1503074938");
+        assert_packet_ids_eq(
+            res,
+            vec![
+                "20170818-164830-33e0ab01",
+                "20170818-164847-7574883b",
+                "20180818-164043-7cdcde4b",
+            ],
+        );
+    }
+
+    #[test]
+    fn can_query_custom_metadata() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let res = eval_str(&index, "custom:orderly:displayname == 'Modified Update'");
+        assert_eq!(res.len(), 3);
+        // One packet has a null displayname, which is neither equal
+        // nor unequal to anything.
+        let res = eval_str(&index, "custom:orderly:displayname != 'Other'");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "!(custom:orderly:displayname == 'Other')");
+        assert_eq!(res.len(), 4);
+        let res = eval_str(&index, "custom:orderly:role.1.role == 'script'");
+        assert_eq!(res.len(), 4);
+        // Missing values, nulls, objects and arrays match nothing,
+        // whichever way they are compared.
+        for query in [
+            "custom:orderly:missing == 'x'",
+            "custom:orderly:missing != 'x'",
+            "custom:other:role == 'x'",
+            "custom:orderly:custom == 'x'",
+            "custom:orderly:custom != 'x'",
+            "custom:orderly:role != 'x'",
+            "custom:orderly:role.99.role != 'x'",
+            "custom:orderly:displayname.0 != 'x'",
+        ] {
+            assert!(eval_str(&index, query).is_empty(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn can_query_git() {
+        let mut packets = dependency_index().packets.to_vec();
+        let git = [
+            Some(serde_json::json!({"sha": "5ca6e2ba", "branch": "main", "url": []})),
+            Some(serde_json::json!({"sha": "0b8ce1f3", "branch": "feature"})),
+            Some(serde_json::json!({"sha": "0b8ce1f3"})),
+            None,
+            None,
+        ];
+        for (packet, git) in packets.iter_mut().zip(git) {
+            packet.git = Some(git.map(|git| serde_json::from_value(git).unwrap()));
+        }
+        let index = Index::new(packets);
+        let res = eval_str(&index, "git:branch == 'main'");
+        assert_packet_ids_eq(res, vec!["20230101-000000-0000000a"]);
+        let res = eval_str(&index, "git:branch != 'main'");
+        assert_packet_ids_eq(res, vec!["20230101-000000-0000000b"]);
+        let res = eval_str(&index, "!(git:branch == 'main')");
+        assert_eq!(res.len(), 4);
+        let res = eval_str(&index, "git:sha == '0b8ce1f3'");
+        assert_packet_ids_eq(
+            res,
+            vec!["20230101-000000-0000000b", "20230101-000000-0000000c"],
+        );
+    }
+
+    #[test]
+    fn strings_are_ordered_lexicographically() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let res = eval_str(&index, "parameter:disease > 'ABC'");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "parameter:disease < 'ABC'");
+        assert_eq!(res.len(), 0);
+        let res = eval_str(&index, "parameter:disease >= 'YF'");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "parameter:disease <= 'YF'");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "name < 'modup-201707-queries1'");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        // Ordering is by code point, so upper case sorts first
+        let res = eval_str(&index, "parameter:disease < 'a'");
+        assert_eq!(res.len(), 3);
+    }
+
+    #[test]
+    fn can_match_strings() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let res = eval_str(&index, "name =~ '^modup-.*params'");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "name =~ 'queries'");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "name =~ '^queries'");
+        assert_eq!(res.len(), 0);
+        let res = eval_str(&index, "startswith(name, 'modup-201707-p')");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "endswith(id, '7cdcde4b')");
+        assert_packet_ids_eq(res, vec!["20180818-164043-7cdcde4b"]);
+        let res = eval_str(&index, "contains(id, '-1648')");
+        assert_packet_ids_eq(
+            res,
+            vec!["20170818-164830-33e0ab01", "20170818-164847-7574883b"],
+        );
+        let res = eval_str(&index, "iequals(parameter:disease, 'yf')");
+        assert_eq!(res.len(), 3);
+        let res = eval_str(&index, "parameter:disease == 'yf'");
+        assert_eq!(res.len(), 0);
+        // String tests never match other types
+        let res = eval_str(&index, "startswith(parameter:size, '1')");
+        assert_eq!(res.len(), 0);
+        let res = eval_str(&index, "parameter:pull_data =~ 'true'");
+        assert_eq!(res.len(), 0);
+    }
+
+    #[test]
+    fn can_test_set_membership() {
+        let index = crate::index::get_packet_index("tests/example").unwrap();
+        let res = eval_str(
+            &index,
+            "id in ['20180220-095832-16a4bbed', '20180818-164043-7cdcde4b', 'other']",
+        );
+        assert_packet_ids_eq(
+            res,
+            vec!["20180220-095832-16a4bbed", "20180818-164043-7cdcde4b"],
+        );
+        let res = eval_str(&index, "parameter:size in [1, 10]");
+        assert_packet_ids_eq(res, vec!["20180220-095832-16a4bbed"]);
+        let res = eval_str(&index, "parameter:size in ['10']");
+        assert_eq!(res.len(), 0);
+        let res = eval_str(&index, "parameter:disease in []");
+        assert_eq!(res.len(), 0);
+        let res = eval_str(&index, "!(parameter:disease in ['YF'])");
+        assert_packet_ids_eq(res, vec!["20170818-164847-7574883b"]);
+        let res = eval_str(&index, "name in [name]");
+        assert_eq!(res.len(), 4);
     }
 }
@@ -1,15 +1,19 @@
+use lazy_static::lazy_static;
 use pest::iterators::{Pair, Pairs};
 use pest::pratt_parser::PrattParser;
 use pest::Parser;
 
 use crate::query::query_types::*;
 use crate::query::QueryError;
+use crate::utils::time_as_num;
+use regex::Regex;
+use std::time::SystemTime;
 
 #[derive(Parser)]
 #[grammar = "query/query.pest"]
 struct QueryParser;
 
-pub fn parse_query(query: &str) -> Result<QueryNode, QueryError> {
+pub fn parse_query(query: &str) -> Result<QueryNode<'_>, QueryError> {
     match QueryParser::parse(Rule::query, query) {
         Ok(pairs) => parse_toplevel(get_first_inner_pair(pairs.peek().unwrap())),
         Err(e) => Err(QueryError::ParseError(Box::new(e))),
@@ -100,9 +104,6 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, QueryError> {
             let infix_function = infix.next().unwrap();
             let rhs = infix.next().unwrap();
 
-            let lhs = parse_test_value(lhs);
-            let rhs = parse_test_value(rhs);
-
             let test_type: Result<Test, QueryError> = match infix_function.as_str() {
                 "==" => Ok(Test::Equal),
                 "!=" => Ok(Test::NotEqual),
@@ -110,11 +111,39 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, QueryError> {
                 "<=" => Ok(Test::LessThanOrEqual),
                 ">" => Ok(Test::GreaterThan),
                 ">=" => Ok(Test::GreaterThanOrEqual),
+                "=~" => parse_regex(rhs.clone()).map(Test::Matches),
                 _ => Err(unknown_infix_error(infix_function)),
             };
 
+            let lhs = parse_test_value(lhs)?;
+            let rhs = parse_test_value(rhs)?;
+
             Ok(QueryNode::Test(test_type?, lhs, rhs))
         }
+        Rule::stringFunc => {
+            let mut func = query.into_inner();
+            let test_type = match func.next().unwrap().as_rule() {
+                Rule::startswith => Test::StartsWith,
+                Rule::endswith => Test::EndsWith,
+                Rule::contains => Test::Contains,
+                Rule::iequals => Test::EqualIgnoreCase,
+                _ => unreachable!(),
+            };
+            let lhs = parse_test_value(func.next().unwrap())?;
+            let rhs = parse_test_value(func.next().unwrap())?;
+            Ok(QueryNode::Test(test_type, lhs, rhs))
+        }
+        Rule::inExpression => {
+            let mut expr = query.into_inner();
+            let lhs = parse_test_value(expr.next().unwrap())?;
+            let items = expr
+                .next()
+                .unwrap()
+                .into_inner()
+                .map(parse_test_value)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(QueryNode::Test(Test::In, lhs, TestValue::List(items)))
+        }
         Rule::singleVariableFunc => {
             let mut func = query.into_inner();
             let func_name = func.next().unwrap().as_str();
@@ -127,6 +156,22 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, QueryError> {
             };
             Ok(node)
         }
+        Rule::dependencyFunc => {
+            let mut func = query.into_inner();
+            let func_name = func.next().unwrap().as_str();
+            let arg = func.next().unwrap();
+            let inner = Box::new(parse_body(arg.into_inner())?);
+            let depth = match func.next() {
+                Some(depth) => Some(parse_depth(get_first_inner_pair(depth))?),
+                None => None,
+            };
+            let node = match func_name {
+                "usedby" => QueryNode::UsedBy(inner, depth),
+                "uses" => QueryNode::Uses(inner, depth),
+                _ => unreachable!(),
+            };
+            Ok(node)
+        }
         Rule::brackets => {
             let expr = query.into_inner();
             let inner = parse_body(expr.peek().unwrap().into_inner())?;
@@ -136,12 +181,89 @@ fn parse_expr(query: Pair<Rule>) -> Result<QueryNode, QueryError> {
     }
 }
 
-fn parse_test_value(value: Pair<Rule>) -> TestValue {
-    match value.as_rule() {
+fn parse_test_value(value: Pair<Rule>) -> Result<TestValue, QueryError> {
+    Ok(match value.as_rule() {
         Rule::lookup => TestValue::Lookup(parse_lookup(get_first_inner_pair(value))),
-        Rule::literal => TestValue::Literal(parse_literal(get_first_inner_pair(value))),
+        Rule::literal => TestValue::Literal(parse_literal(get_first_inner_pair(value))?),
+        _ => unreachable!(),
+    })
+}
+
+// Times are compared as numbers of seconds, so a time expression is
+// evaluated as soon as it is parsed; now() is the time of parsing.
+fn parse_time_expression(expr: Pair<Rule>) -> Result<Literal, QueryError> {
+    let mut parts = expr.into_inner();
+    let point = parts.next().unwrap();
+    let mut seconds = match point.as_rule() {
+        Rule::now => time_as_num(SystemTime::now()),
+        Rule::date => {
+            let string = get_first_inner_pair(point);
+            parse_timestamp(get_string_inner(string.clone()))
+                .ok_or_else(|| invalid_timestamp_error(string))?
+        }
+        Rule::timestamp => {
+            parse_timestamp(point.as_str()).ok_or_else(|| invalid_timestamp_error(point))?
+        }
         _ => unreachable!(),
+    };
+    for shift in parts {
+        let mut shift = shift.into_inner();
+        let sign = shift.next().unwrap();
+        let mut duration = shift.next().unwrap().into_inner();
+        let unit = match duration.next().unwrap().as_str() {
+            "seconds" => 1.0,
+            "minutes" => 60.0,
+            "hours" => 3600.0,
+            "days" => 86400.0,
+            "weeks" => 604800.0,
+            _ => unreachable!(),
+        };
+        let amount: f64 = duration.next().unwrap().as_str().parse().unwrap();
+        match sign.as_rule() {
+            Rule::plus => seconds += amount * unit,
+            Rule::minus => seconds -= amount * unit,
+            _ => unreachable!(),
+        }
+    }
+    Ok(Literal::Number(seconds))
+}
+
+/// Seconds since 1970-01-01 for an ISO 8601 date (taken as midnight
+/// UTC) or timestamp (taken as UTC, unless it has an offset).
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    lazy_static! {
+        static ref TIMESTAMP_RE: Regex = Regex::new(
+            r"^(\d{4})-(\d{2})-(\d{2})(?:T(\d{2}):(\d{2})(?::(\d{2})(\.\d+)?)?(Z|([+-])(\d{2}):(\d{2}))?)?$"
+        )
+        .expect("Valid regex");
+    }
+    let caps = TIMESTAMP_RE.captures(timestamp)?;
+    let number =
+        |i: usize| -> Option<u8> { caps.get(i).map_or(Some(0), |m| m.as_str().parse().ok()) };
+    let month = time::Month::try_from(number(2)?).ok()?;
+    let date = time::Date::from_calendar_date(caps[1].parse().ok()?, month, number(3)?).ok()?;
+    let nanos = match caps.get(7) {
+        Some(fraction) => (fraction.as_str().parse::<f64>().ok()? * 1e9).round() as u32,
+        None => 0,
+    };
+    let time = time::Time::from_hms_nano(number(4)?, number(5)?, number(6)?, nanos).ok()?;
+    let mut offset_seconds = i32::from(number(10)?) * 3600 + i32::from(number(11)?) * 60;
+    if caps.get(9).is_some_and(|sign| sign.as_str() == "-") {
+        offset_seconds = -offset_seconds;
     }
+    let offset = time::UtcOffset::from_whole_seconds(offset_seconds).ok()?;
+    let datetime = time::PrimitiveDateTime::new(date, time).assume_offset(offset);
+    Some(datetime.unix_timestamp() as f64 + f64::from(datetime.nanosecond()) / 1e9)
+}
+
+fn invalid_timestamp_error(timestamp: Pair<Rule>) -> QueryError {
+    let err = pest::error::Error::new_from_span(
+        pest::error::ErrorVariant::CustomError {
+            message: format!("Invalid date or time: {}", timestamp.as_str()),
+        },
+        timestamp.as_span(),
+    );
+    QueryError::ParseError(Box::new(err))
 }
 
 fn parse_lookup(lookup: Pair<Rule>) -> Lookup {
@@ -158,17 +280,72 @@ fn parse_lookup_packet(lookup: pest::iterators::Pair<Rule>) -> PacketLookup {
         Rule::lookupPacketId => PacketLookup::Id,
         Rule::lookupPacketName => PacketLookup::Name,
         Rule::lookupPacketParam => PacketLookup::Parameter(get_string_inner(lookup)),
+        Rule::lookupPacketTime => match get_first_inner_pair(lookup).as_rule() {
+            Rule::timeStart => PacketLookup::StartTime,
+            Rule::timeEnd => PacketLookup::EndTime,
+            _ => unreachable!(),
+        },
+        Rule::lookupPacketGit => match get_first_inner_pair(lookup).as_rule() {
+            Rule::gitSha => PacketLookup::GitSha,
+            Rule::gitBranch => PacketLookup::GitBranch,
+            _ => unreachable!(),
+        },
+        Rule::lookupPacketCustom => {
+            let path = get_first_inner_pair(lookup).as_str();
+            PacketLookup::Custom(path.split([':', '.']).collect())
+        }
         _ => unreachable!(),
     }
 }
 
-fn parse_literal(literal: Pair<Rule>) -> Literal {
-    match literal.as_rule() {
+fn parse_literal(literal: Pair<Rule>) -> Result<Literal, QueryError> {
+    Ok(match literal.as_rule() {
+        Rule::timeExpression => parse_time_expression(literal)?,
         Rule::string => Literal::String(get_string_inner(literal)),
         Rule::boolean => Literal::Bool(literal.as_str().to_lowercase().parse().unwrap()),
         Rule::number => Literal::Number(literal.as_str().parse().unwrap()),
         _ => unreachable!(),
+    })
+}
+
+fn parse_depth(depth: Pair<Rule>) -> Result<usize, QueryError> {
+    match depth.as_rule() {
+        Rule::immediate => Ok(1),
+        Rule::depth => depth.as_str().parse().map_err(|_| {
+            let err = pest::error::Error::new_from_span(
+                pest::error::ErrorVariant::CustomError {
+                    message: format!("Dependency depth is too large: {}", depth.as_str()),
+                },
+                depth.as_span(),
+            );
+            QueryError::ParseError(Box::new(err))
+        }),
+        _ => unreachable!(),
+    }
+}
+
+// The pattern is compiled once, while parsing, so it has to be written
+// out as a string rather than looked up.
+fn parse_regex(pattern: Pair<Rule>) -> Result<Regex, QueryError> {
+    let is_string = pattern.as_rule() == Rule::literal
+        && get_first_inner_pair(pattern.clone()).as_rule() == Rule::string;
+    if !is_string {
+        return Err(invalid_regex_error(
+            "Expected a string to match against".to_string(),
+            pattern,
+        ));
     }
+    let string = get_first_inner_pair(pattern.clone());
+    Regex::new(get_string_inner(string))
+        .map_err(|e| invalid_regex_error(format!("Invalid regular expression: {}", e), pattern))
+}
+
+fn invalid_regex_error(message: String, pattern: Pair<Rule>) -> QueryError {
+    let err = pest::error::Error::new_from_span(
+        pest::error::ErrorVariant::CustomError { message },
+        pattern.as_span(),
+    );
+    QueryError::ParseError(Box::new(err))
 }
 
 fn unknown_infix_error(operator: Pair<Rule>) -> QueryError {
@@ -181,7 +358,7 @@ fn unknown_infix_error(operator: Pair<Rule>) -> QueryError {
     QueryError::ParseError(Box::new(err))
 }
 
-fn get_string_inner(rule: Pair<Rule>) -> &str {
+fn get_string_inner(rule: Pair<'_, Rule>) -> &str {
     get_first_inner_pair(rule).as_str()
 }
 
@@ -192,6 +369,8 @@ fn get_first_inner_pair(rule: Pair<Rule>) -> Pair<Rule> {
 #[cfg(test)]
 mod tests {
     use crate::query::test_utils_query::tests::assert_query_node_lookup_number_eq;
+    use crate::utils::time_as_num;
+    use std::time::SystemTime;
 
     use super::*;
 
@@ -834,4 +1013,249 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn query_can_parse_dependency_funcs() {
+        let res = parse_query(r#"usedby(latest(name == "x"))"#).unwrap();
+        match res {
+            QueryNode::UsedBy(inner, None) => assert_node!(*inner, QueryNode::Latest(Some(_))),
+            _ => panic!("Invalid type,\nexpected: QueryNode::UsedBy\ngot: {:?}", res),
+        }
+
+        let res = parse_query(r#"uses(name == "x", 2)"#).unwrap();
+        match res {
+            QueryNode::Uses(inner, Some(2)) => assert_node!(
+                *inner,
+                QueryNode::Test(
+                    Test::Equal,
+                    TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+                    TestValue::Literal(Literal::String("x"))
+                )
+            ),
+            _ => panic!("Invalid type,\nexpected: QueryNode::Uses\ngot: {:?}", res),
+        }
+
+        let res = parse_query(r#"usedby(name == "x", immediate)"#).unwrap();
+        assert_node!(res, QueryNode::UsedBy(_, Some(1)));
+
+        let res = parse_query(r#"!uses(name == "x") && name == "y""#).unwrap();
+        assert_node!(
+            res,
+            QueryNode::BooleanOperator,
+            Operator::And,
+            (QueryNode::Negation, (QueryNode::Uses(_, None))),
+            (QueryNode::Test(..))
+        );
+
+        let e = parse_query("usedby()").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query(r#"uses(name == "x", 0)"#).unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query(r#"uses(name == "x", 1.5)"#).unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query(r#"uses(name == "x", 99999999999999999999999)"#).unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        assert!(e.to_string().contains("Dependency depth is too large"));
+    }
+
+    fn parse_lookup_of(query: &str) -> PacketLookup<'_> {
+        match parse_query(query).unwrap() {
+            QueryNode::Test(_, TestValue::Lookup(Lookup::Packet(lookup)), _) => lookup,
+            res => panic!("Expected a packet lookup, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn query_can_parse_metadata_lookups() {
+        assert_eq!(
+            parse_lookup_of("time:start > 1690000000"),
+            PacketLookup::StartTime
+        );
+        assert_eq!(parse_lookup_of("time:end <= 1"), PacketLookup::EndTime);
+        assert_eq!(
+            parse_lookup_of(r#"git:branch == "main""#),
+            PacketLookup::GitBranch
+        );
+        assert_eq!(parse_lookup_of("git:sha == 'abc'"), PacketLookup::GitSha);
+        assert_eq!(
+            parse_lookup_of("custom:orderly:role == 'x'"),
+            PacketLookup::Custom(vec!["orderly", "role"])
+        );
+        assert_eq!(
+            parse_lookup_of("custom:orderly.artefacts.0.description == 'x'"),
+            PacketLookup::Custom(vec!["orderly", "artefacts", "0", "description"])
+        );
+
+        let e = parse_query("time:elapsed > 1").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("git:url == 'x'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("custom: == 'x'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("custom:orderly..role == 'x'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+    }
+
+    fn parse_number_of(query: &str) -> f64 {
+        match parse_query(query).unwrap() {
+            QueryNode::Test(_, _, TestValue::Literal(Literal::Number(value))) => value,
+            res => panic!("Expected a number on the right, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn query_can_parse_dates_and_times() {
+        assert_eq!(
+            parse_number_of("time:start > date('2023-01-01')"),
+            1672531200.0
+        );
+        assert_eq!(
+            parse_number_of(r#"time:start > date("2023-01-01")"#),
+            1672531200.0
+        );
+        assert_eq!(parse_number_of("time:start > 2023-01-01"), 1672531200.0);
+        assert_eq!(
+            parse_number_of("time:start > 2023-01-01T12:30:00Z"),
+            1672576200.0
+        );
+        assert_eq!(
+            parse_number_of("time:start > 2023-01-01T12:30"),
+            1672576200.0
+        );
+        assert_eq!(
+            parse_number_of("time:start > date('2023-01-01T13:30:00+01:00')"),
+            1672576200.0
+        );
+        assert_eq!(
+            parse_number_of("time:start > 2023-01-01T12:30:00.25-00:30"),
+            1672578000.25
+        );
+        assert_eq!(
+            parse_number_of("time:start > date('2023-01-01') + days(1) - hours(1.5)"),
+            1672531200.0 + 86400.0 - 5400.0
+        );
+
+        let before = time_as_num(SystemTime::now()) - 7.0 * 86400.0;
+        let value = parse_number_of("time:start > now() - days(7)");
+        let after = time_as_num(SystemTime::now()) - 7.0 * 86400.0;
+        assert!(before <= value && value <= after);
+
+        // Times can be on either side of a test.
+        let res = parse_query("now() - weeks(2) < time:end").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                Test::LessThan,
+                TestValue::Literal(Literal::Number(_)),
+                TestValue::Lookup(Lookup::Packet(PacketLookup::EndTime))
+            )
+        );
+
+        for query in [
+            "time:start > date('2023-02-30')",
+            "time:start > date('2023-13-01')",
+            "time:start > date('yesterday')",
+            "time:start > 2023-01-01T25:00:00Z",
+        ] {
+            let e = parse_query(query).unwrap_err();
+            assert_node!(e, QueryError::ParseError(_));
+            assert!(e.to_string().contains("Invalid date or time"), "{}", e);
+        }
+        let e = parse_query("time:start > now() - 7").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("time:start > now() - days(-7)").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+    }
+
+    #[test]
+    fn query_can_parse_string_tests() {
+        let res = parse_query("name =~ '^mod_.*'").unwrap();
+        match res {
+            QueryNode::Test(Test::Matches(re), lhs, _) => {
+                assert_eq!(re.as_str(), "^mod_.*");
+                assert_eq!(lhs, TestValue::Lookup(Lookup::Packet(PacketLookup::Name)));
+            }
+            res => panic!("Expected a regex test, got: {:?}", res),
+        }
+        let res = parse_query(r#"startswith(name, "mod")"#).unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                Test::StartsWith,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Name)),
+                TestValue::Literal(Literal::String("mod"))
+            )
+        );
+        let res = parse_query("endswith(parameter:x, 'a')").unwrap();
+        assert_node!(res, QueryNode::Test(Test::EndsWith, ..));
+        let res = parse_query("contains(id, '2023')").unwrap();
+        assert_node!(res, QueryNode::Test(Test::Contains, ..));
+        let res = parse_query("iequals(parameter:disease, 'yf')").unwrap();
+        assert_node!(res, QueryNode::Test(Test::EqualIgnoreCase, ..));
+        let res = parse_query("contains(name, 'x') && !startswith(name, 'y')").unwrap();
+        assert_node!(
+            res,
+            QueryNode::BooleanOperator,
+            Operator::And,
+            (QueryNode::Test(Test::Contains, ..)),
+            (QueryNode::Negation, (QueryNode::Test(Test::StartsWith, ..)))
+        );
+
+        let e = parse_query("name =~ '(unclosed'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        assert!(
+            e.to_string().contains("Invalid regular expression"),
+            "{}",
+            e
+        );
+        let e = parse_query("name =~ this:pattern").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        assert!(e.to_string().contains("Expected a string"), "{}", e);
+        let e = parse_query("name ~~ 'x'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("startswith(name)").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+    }
+
+    #[test]
+    fn query_can_parse_set_membership() {
+        let res = parse_query("parameter:x in [1, 'a', true, this:y]").unwrap();
+        assert_node!(
+            res,
+            QueryNode::Test(
+                Test::In,
+                TestValue::Lookup(Lookup::Packet(PacketLookup::Parameter("x"))),
+                TestValue::List(_)
+            )
+        );
+        match res {
+            QueryNode::Test(_, _, TestValue::List(items)) => assert_eq!(
+                items,
+                vec![
+                    TestValue::Literal(Literal::Number(1.0)),
+                    TestValue::Literal(Literal::String("a")),
+                    TestValue::Literal(Literal::Bool(true)),
+                    TestValue::Lookup(Lookup::This("y")),
+                ]
+            ),
+            _ => unreachable!(),
+        }
+        let res = parse_query("name in []").unwrap();
+        assert_node!(res, QueryNode::Test(Test::In, _, TestValue::List(_)));
+        let res = parse_query("name in ['a'] || id == 'b'").unwrap();
+        assert_node!(
+            res,
+            QueryNode::BooleanOperator,
+            Operator::Or,
+            (QueryNode::Test(Test::In, ..)),
+            (QueryNode::Test(Test::Equal, ..))
+        );
+
+        let e = parse_query("name in 'a'").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("name in ['a',]").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+        let e = parse_query("name == ['a']").unwrap_err();
+        assert_node!(e, QueryError::ParseError(_));
+    }
 }
@@ -1,10 +1,20 @@
+use regex::Regex;
+use serde_json::value::Value as JsonValue;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub enum PacketLookup<'a> {
     Name,
     Id,
     Parameter(&'a str),
+    StartTime,
+    EndTime,
+    GitSha,
+    GitBranch,
+    // The application, followed by the keys (or array indices) leading
+    // to the value within its custom metadata.
+    Custom(Vec<&'a str>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -25,17 +35,30 @@ pub enum Literal<'a> {
 pub enum TestValue<'a> {
     Lookup(Lookup<'a>),
     Literal(Literal<'a>),
+    // Only found on the right of Test::In
+    List(Vec<TestValue<'a>>),
 }
 
+// Numbers are ordered numerically and strings lexicographically (by
+// Unicode code point); nothing else is ordered.
 impl<'a> PartialOrd for Literal<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
             (Literal::Number(num_1), Literal::Number(num_2)) => num_1.partial_cmp(num_2),
+            (Literal::String(str_1), Literal::String(str_2)) => str_1.partial_cmp(str_2),
             (_, _) => None,
         }
     }
 }
 
+/// Values that `this:` and `environment:` lookups resolve against when
+/// evaluating a query. Values must be numbers, booleans or strings.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct QueryContext {
+    pub this: HashMap<String, JsonValue>,
+    pub environment: HashMap<String, JsonValue>,
+}
+
 #[derive(Debug)]
 pub enum Test {
     Equal,
@@ -44,6 +67,15 @@ pub enum Test {
     LessThanOrEqual,
     GreaterThan,
     GreaterThanOrEqual,
+    // The rest only apply to strings, testing the left-hand value
+    // against the right.
+    Matches(Regex),
+    StartsWith,
+    EndsWith,
+    Contains,
+    EqualIgnoreCase,
+    // Whether the left-hand value equals any in the list on the right.
+    In,
 }
 
 #[derive(Debug)]
@@ -60,6 +92,10 @@ pub enum QueryNode<'a> {
     Negation(Box<QueryNode<'a>>),
     Brackets(Box<QueryNode<'a>>),
     BooleanOperator(Operator, Box<QueryNode<'a>>, Box<QueryNode<'a>>),
+    // The depth is the maximum number of steps to take through the
+    // dependency graph; None means there is no limit.
+    UsedBy(Box<QueryNode<'a>>, Option<usize>),
+    Uses(Box<QueryNode<'a>>, Option<usize>),
 }
 
 #[cfg(test)]
@@ -88,8 +124,13 @@ mod tests {
         assert!(lit_num1 <= lit_num2);
         assert!(lit_num3 > lit_num1);
 
-        // Is undefined on non-number variants
+        // Is undefined on booleans, and between different variants
         assert!(lit_bool1.partial_cmp(&lit_bool2).is_none());
         assert!(lit_bool2.partial_cmp(&lit_bool1).is_none());
+        assert!(lit_num1.partial_cmp(&lit_str1).is_none());
+
+        assert!(lit_str1 < lit_str2);
+        assert!(Literal::String("B") < Literal::String("a"));
+        assert_eq!(lit_str1.partial_cmp(&lit_str1), Some(Ordering::Equal));
     }
 }
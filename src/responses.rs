@@ -6,7 +6,9 @@ use rocket::{Request, Response};
 use std::io;
 use std::io::ErrorKind;
 
+use crate::error::{self, Error, ErrorCode};
 use crate::hash;
+use crate::query::QueryError;
 
 #[derive(Responder)]
 #[response(status = 200, content_type = "json")]
@@ -20,51 +22,127 @@ pub struct OutpackError {
     pub error: String,
     pub detail: String,
 
+    // Only present for errors that relate to a position in some user
+    // input, e.g., a query that failed to parse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<ErrorPosition>,
+
     #[serde(skip_serializing, skip_deserializing)]
     pub kind: Option<ErrorKind>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ErrorPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl OutpackError {
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> OutpackError {
+        OutpackError {
+            error: String::from(code.as_str()),
+            detail: detail.into(),
+            position: None,
+            kind: Some(code.io_kind()),
+        }
+    }
+
+    /// The code this error was reported with, if it is one we know.
+    pub fn code(&self) -> Option<ErrorCode> {
+        self.error.parse().ok()
+    }
+}
+
+/// The HTTP status used to report an error with a given code.
+pub fn status(code: ErrorCode) -> Status {
+    match code {
+        ErrorCode::PacketNotFound | ErrorCode::FileNotFound | ErrorCode::NotFound => {
+            Status::NotFound
+        }
+        ErrorCode::HashMismatch
+        | ErrorCode::InvalidHash
+        | ErrorCode::MissingDependencies
+        | ErrorCode::MissingFiles
+        | ErrorCode::InvalidPacketId
+        | ErrorCode::InvalidMetadata
+        | ErrorCode::QueryParseError
+        | ErrorCode::QueryEvalError
+        | ErrorCode::BadRequest => Status::BadRequest,
+        ErrorCode::Unauthorized => Status::Unauthorized,
+        ErrorCode::Forbidden => Status::Forbidden,
+        ErrorCode::UnknownError => Status::InternalServerError,
+    }
+}
+
+impl From<Error> for OutpackError {
+    fn from(e: Error) -> Self {
+        OutpackError::new(e.code, e.detail)
+    }
+}
+
 impl From<io::Error> for OutpackError {
     fn from(e: io::Error) -> Self {
         OutpackError {
-            error: e.kind().to_string(),
+            error: String::from(error::error_code(&e).as_str()),
             detail: e.to_string(),
+            position: None,
             kind: Some(e.kind()),
         }
     }
 }
 
+// Keeps the code, where there is one, so that an error reported by a
+// server can be told apart after passing through code that works with
+// io::Error.
+impl From<OutpackError> for io::Error {
+    fn from(e: OutpackError) -> Self {
+        match e.code() {
+            Some(code) => {
+                io::Error::new(e.kind.unwrap_or(code.io_kind()), Error::new(code, e.detail))
+            }
+            None => io::Error::new(e.kind.unwrap_or(ErrorKind::Other), e.detail),
+        }
+    }
+}
+
 impl From<hash::HashError> for OutpackError {
     fn from(e: hash::HashError) -> Self {
+        OutpackError::from(Error::from(e))
+    }
+}
+
+impl From<QueryError> for OutpackError {
+    fn from(e: QueryError) -> Self {
+        let position = e
+            .position()
+            .map(|(line, column)| ErrorPosition { line, column });
         OutpackError {
-            // later this can be sorted out better; for now keep old
-            // behaviour
-            error: std::io::ErrorKind::InvalidInput.to_string(),
-            detail: e.explanation,
-            kind: Some(std::io::ErrorKind::InvalidInput),
+            position,
+            ..OutpackError::from(Error::from(e))
         }
     }
 }
 
+// A request body that can't be read as the JSON expected is the
+// client's mistake.
 impl From<json::Error<'_>> for OutpackError {
     fn from(e: json::Error) -> Self {
         match e {
-            json::Error::Io(err) => OutpackError::from(err),
-            json::Error::Parse(_str, err) => OutpackError::from(io::Error::from(err)),
+            json::Error::Io(err) => OutpackError::new(ErrorCode::BadRequest, err.to_string()),
+            json::Error::Parse(_str, err) => {
+                OutpackError::new(ErrorCode::BadRequest, err.to_string())
+            }
         }
     }
 }
 
 impl<'r> Responder<'r, 'static> for OutpackError {
     fn respond_to(self, req: &'r Request<'_>) -> rocket::response::Result<'static> {
-        let kind = self.kind;
+        let code = self
+            .code()
+            .unwrap_or_else(|| ErrorCode::from_kind(self.kind.unwrap_or(ErrorKind::Other)));
         let json = FailResponse::from(self);
-        let status = match kind {
-            Some(ErrorKind::NotFound) => Status::NotFound,
-            Some(ErrorKind::InvalidInput) => Status::BadRequest,
-            Some(ErrorKind::UnexpectedEof) => Status::BadRequest,
-            _ => Status::InternalServerError,
-        };
+        let status = status(code);
         Response::build_from(json!(json).respond_to(req).unwrap())
             .status(status)
             .header(ContentType::JSON)
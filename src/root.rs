@@ -0,0 +1,44 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Find the outpack root that `path` lies within, by walking up from it
+/// until a directory containing `.outpack` is found.
+pub fn find_root(path: &Path) -> io::Result<PathBuf> {
+    path.ancestors()
+        .find(|dir| dir.join(".outpack").is_dir())
+        .map(Path::to_path_buf)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Could not find an outpack root at '{}' or any of its parents",
+                    path.display()
+                ),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init::outpack_init;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn can_find_root_from_subdirectory() {
+        let tmp = TempDir::new().unwrap();
+        outpack_init(tmp.path().to_str().unwrap(), None, true, false).unwrap();
+        let nested = tmp.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_root(&nested).unwrap(), tmp.path());
+        assert_eq!(find_root(tmp.path()).unwrap(), tmp.path());
+    }
+
+    #[test]
+    fn errors_if_no_root_found() {
+        let tmp = TempDir::new().unwrap();
+        let res = find_root(tmp.path());
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -1,12 +1,17 @@
 use rocket::fs::TempFile;
-use std::path::{Path, PathBuf};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 use std::{fs, io};
 use tempfile::tempdir_in;
+use walkdir::WalkDir;
 
-use crate::hash;
+use crate::config;
+use crate::error::{Error, ErrorCode};
+use crate::metadata::{Packet, PacketFile};
+use crate::{hash, index};
 
 pub fn file_path(root: &str, hash: &str) -> io::Result<PathBuf> {
-    let parsed: hash::Hash = hash.parse().map_err(hash::hash_error_to_io_error)?;
+    let parsed: hash::Hash = hash.parse()?;
     Ok(Path::new(root)
         .join(".outpack")
         .join("files")
@@ -15,6 +20,92 @@ pub fn file_path(root: &str, hash: &str) -> io::Result<PathBuf> {
         .join(&parsed.value[2..]))
 }
 
+// Names and paths come from metadata we may have been sent by anyone,
+// and end up in paths in the archive, or in those exported to, so must
+// not be able to point outside them.
+fn check_packet_file(packet: &Packet, file: &PacketFile) -> io::Result<()> {
+    let is_relative = |p: &Path| p.components().all(|c| matches!(c, Component::Normal(_)));
+    if !is_relative(Path::new(&packet.name)) || !is_relative(Path::new(&file.path)) {
+        return Err(Error::new(
+            ErrorCode::InvalidMetadata,
+            format!(
+                "Packet '{}' has a file with invalid path '{}/{}'",
+                packet.id, packet.name, file.path
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Where a root keeps one of the files of a packet it has unpacked:
+/// in the file store if it has one, otherwise in the archive. Fails if
+/// the packet's name or the file's path would lead anywhere else.
+pub(crate) fn packet_file_path(
+    root: &str,
+    core: &config::Core,
+    packet: &Packet,
+    file: &PacketFile,
+) -> io::Result<PathBuf> {
+    match &core.path_archive {
+        Some(path_archive) if !core.use_file_store => {
+            archive_file_path(root, path_archive, packet, file)
+        }
+        _ => {
+            check_packet_file(packet, file)?;
+            file_path(root, &file.hash)
+        }
+    }
+}
+
+pub(crate) fn archive_file_path(
+    root: &str,
+    path_archive: &str,
+    packet: &Packet,
+    file: &PacketFile,
+) -> io::Result<PathBuf> {
+    check_packet_file(packet, file)?;
+    Ok(Path::new(root)
+        .join(path_archive)
+        .join(&packet.name)
+        .join(&packet.id)
+        .join(&file.path))
+}
+
+/// Where the file with `hash` can be read from: the file store, if the
+/// root has one, otherwise the archive directory of an unpacked packet
+/// that contains it.
+pub fn find_file(root: &str, hash: &str) -> io::Result<PathBuf> {
+    let core = config::read_config(root)?.core;
+    if core.use_file_store {
+        return file_path(root, hash);
+    }
+    hash.parse::<hash::Hash>()?;
+    let index = index::get_packet_index(root)?;
+    let local = Path::new(root)
+        .join(".outpack")
+        .join("location")
+        .join("local");
+    for id in index.packets_with_file(hash) {
+        if !local.join(id).exists() {
+            continue;
+        }
+        let packet = index.packet(id).unwrap();
+        let file = packet.files.iter().find(|f| f.hash == hash).unwrap();
+        // A packet whose file has an invalid path can't provide it, but
+        // another might.
+        match packet_file_path(root, &core, packet, file) {
+            Ok(path) if path.exists() => return Ok(path),
+            _ => continue,
+        }
+    }
+    Err(Error::new(
+        ErrorCode::FileNotFound,
+        format!("hash '{}' not found", hash),
+    )
+    .into())
+}
+
 pub fn file_exists(root: &str, hash: &str) -> io::Result<bool> {
     let path = file_path(root, hash)?;
     Ok(fs::metadata(path).is_ok())
@@ -31,11 +122,53 @@ pub fn get_missing_files(root: &str, wanted: &[String]) -> io::Result<Vec<String
         .collect()
 }
 
-pub async fn put_file(root: &str, mut file: TempFile<'_>, hash: &str) -> io::Result<()> {
+/// The hashes of all files in the store, as `<algorithm>:<value>`.
+///
+/// Anything in the store that is not named for a hash (such as a
+/// temporary file left behind by an interrupted write) is skipped, as
+/// is anything that cannot be read.
+pub fn enumerate_files(root: &str) -> io::Result<Vec<String>> {
+    let path = Path::new(root).join(".outpack").join("files");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut hashes = Vec::new();
+    for entry in WalkDir::new(&path).min_depth(3).max_depth(3) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(&path).unwrap();
+        let parts: Vec<&str> = relative.iter().filter_map(|p| p.to_str()).collect();
+        if let [algorithm, prefix, rest] = parts[..] {
+            let hash = format!("{}:{}{}", algorithm, prefix, rest);
+            if is_hash_name(&hash) {
+                hashes.push(hash);
+            }
+        }
+    }
+    hashes.sort();
+    Ok(hashes)
+}
+
+fn is_hash_name(name: &str) -> bool {
+    name.parse::<hash::Hash>()
+        .is_ok_and(|hash| hash.to_string() == name)
+}
+
+/// Add the contents of `reader` to the store, checking that they match
+/// `hash`. The data is hashed while it is copied into place, rather than
+/// read back afterwards, as these files can be very large.
+pub fn put_from_reader<R: Read>(root: &str, reader: &mut R, hash: &str) -> io::Result<()> {
+    let expected: hash::Hash = hash.parse()?;
     let temp_dir = tempdir_in(root)?;
     let temp_path = temp_dir.path().join(hash);
-    file.copy_to(&temp_path).await?;
-    hash::validate_hash_file(&temp_path, hash).map_err(hash::hash_error_to_io_error)?;
+    let mut dest = io::BufWriter::new(fs::File::create(&temp_path)?);
+    let found = hash::copy_and_hash(reader, &mut dest, expected.algorithm)?;
+    hash::validate_hash(&found, &expected)?;
     let path = file_path(root, hash)?;
     if !file_exists(root, hash)? {
         fs::create_dir_all(path.parent().unwrap())?;
@@ -45,11 +178,125 @@ pub async fn put_file(root: &str, mut file: TempFile<'_>, hash: &str) -> io::Res
     }
 }
 
+/// Move the file at `path` into the store, checking that it matches
+/// `hash`. The file must be on the same filesystem as the store.
+pub fn put_from_path(root: &str, path: &Path, hash: &str) -> io::Result<()> {
+    let expected: hash::Hash = hash.parse()?;
+    let found = hash::hash_file(path, expected.algorithm)?;
+    hash::validate_hash(&found, &expected)?;
+    if !file_exists(root, hash)? {
+        let dest = file_path(root, hash)?;
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::rename(path, dest)?;
+    }
+    Ok(())
+}
+
+pub async fn put_file(root: &str, file: TempFile<'_>, hash: &str) -> io::Result<()> {
+    enum Source {
+        Buffered(Vec<u8>),
+        File(PathBuf),
+    }
+    let source = match &file {
+        TempFile::Buffered { content } => Source::Buffered(content.as_bytes().to_vec()),
+        TempFile::File { .. } => Source::File(file.path().unwrap().to_path_buf()),
+    };
+    let root = root.to_string();
+    let hash = hash.to_string();
+    // Uploads can be very large, so copying and hashing them must not
+    // hold up the async runtime.
+    rocket::tokio::task::spawn_blocking(move || match source {
+        Source::Buffered(content) => put_from_reader(&root, &mut content.as_slice(), &hash),
+        Source::File(path) => put_from_reader(&root, &mut fs::File::open(path)?, &hash),
+    })
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::hash::{hash_data, HashAlgorithm};
-    use crate::test_utils::tests::get_temp_outpack_root;
+    use crate::test_utils::tests::{
+        create_archive_packet, get_empty_outpack_root, get_temp_outpack_root,
+    };
+
+    // A file-backed TempFile, like those Rocket creates for large
+    // uploads. TempFile::persist_to does not flush its write before
+    // returning, so write the contents ourselves to be sure they are
+    // on disk before the file is read.
+    async fn temp_file_at(path: PathBuf, content: &str) -> TempFile<'_> {
+        let mut temp_file = TempFile::Buffered { content };
+        temp_file.persist_to(&path).await.unwrap();
+        fs::write(path, content).unwrap();
+        temp_file
+    }
+
+    #[test]
+    fn can_find_files_in_archive() {
+        let root = get_empty_outpack_root(Some("archive"), false, true);
+        let id = "20230101-000000-00000001";
+        create_archive_packet(
+            &root,
+            "archive",
+            "data",
+            id,
+            &[("out/data.csv", "a,b\n1,2\n")],
+            HashAlgorithm::Sha1,
+        );
+        let root = root.to_str().unwrap();
+        let hash = hash_data(b"a,b\n1,2\n", HashAlgorithm::Sha1).to_string();
+        let path = find_file(root, &hash).unwrap();
+        assert_eq!(
+            path,
+            Path::new(root)
+                .join("archive/data")
+                .join(id)
+                .join("out/data.csv")
+        );
+
+        let other = hash_data(b"other", HashAlgorithm::Sha1).to_string();
+        assert_eq!(
+            find_file(root, &other).unwrap_err().to_string(),
+            format!("hash '{}' not found", other)
+        );
+        assert!(find_file(root, "sha1").is_err());
+    }
+
+    #[test]
+    fn does_not_find_files_outside_archive() {
+        let root = get_empty_outpack_root(Some("archive"), false, true);
+        let bad = "20230101-000000-00000001";
+        let good = "20230101-000000-00000002";
+        create_archive_packet(
+            &root,
+            "archive",
+            "data",
+            bad,
+            &[("../../escaped.csv", "a,b\n1,2\n")],
+            HashAlgorithm::Sha1,
+        );
+        let root_str = root.to_str().unwrap();
+        assert!(root.join("archive/escaped.csv").exists());
+        let hash = hash_data(b"a,b\n1,2\n", HashAlgorithm::Sha1).to_string();
+        assert_eq!(
+            find_file(root_str, &hash).unwrap_err().to_string(),
+            format!("hash '{}' not found", hash)
+        );
+
+        create_archive_packet(
+            &root,
+            "archive",
+            "data",
+            good,
+            &[("data.csv", "a,b\n1,2\n")],
+            HashAlgorithm::Sha1,
+        );
+        assert_eq!(
+            find_file(root_str, &hash).unwrap(),
+            root.join("archive/data").join(good).join("data.csv")
+        );
+    }
 
     #[test]
     fn can_get_path() {
@@ -69,17 +316,25 @@ mod tests {
         assert_eq!(res.unwrap_err().to_string(), "Invalid hash format 'sha256'")
     }
 
+    #[test]
+    fn can_enumerate_files() {
+        let root = get_temp_outpack_root();
+        let root_str = root.to_str().unwrap();
+        assert_eq!(
+            enumerate_files(root_str).unwrap(),
+            vec!["sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"]
+        );
+        fs::remove_dir_all(root.join(".outpack").join("files")).unwrap();
+        assert_eq!(enumerate_files(root_str).unwrap().len(), 0);
+    }
+
     #[rocket::async_test]
     async fn put_file_is_idempotent() {
         let root = get_temp_outpack_root();
         let data = "Testing 123.";
-        let mut temp_file = TempFile::Buffered { content: data };
         let hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256);
         let hash_str = hash.to_string();
-        temp_file
-            .persist_to(root.join(hash.to_string()))
-            .await
-            .unwrap();
+        let temp_file = temp_file_at(root.join(&hash_str), data).await;
 
         let root_str = root.to_str().unwrap();
         let res = put_file(root_str, temp_file, &hash.to_string()).await;
@@ -88,8 +343,7 @@ mod tests {
         assert!(res.is_ok());
         assert_eq!(fs::read_to_string(expected).unwrap(), data);
 
-        let mut temp_file = TempFile::Buffered { content: data };
-        temp_file.persist_to(root.join(&hash_str)).await.unwrap();
+        let temp_file = temp_file_at(root.join(&hash_str), data).await;
         let res = put_file(root_str, temp_file, &hash_str).await;
         assert!(res.is_ok());
     }
@@ -98,8 +352,7 @@ mod tests {
     async fn put_file_validates_hash_format() {
         let root = get_temp_outpack_root();
         let data = "Testing 123.";
-        let mut temp_file = TempFile::Buffered { content: data };
-        temp_file.persist_to(root.join("badhash")).await.unwrap();
+        let temp_file = temp_file_at(root.join("badhash"), data).await;
         let root_path = root.to_str().unwrap();
         let res = put_file(root_path, temp_file, "badhash").await;
         assert_eq!(
@@ -112,8 +365,7 @@ mod tests {
     async fn put_file_validates_hash_match() {
         let root = get_temp_outpack_root();
         let data = "Testing 123.";
-        let mut temp_file = TempFile::Buffered { content: data };
-        temp_file.persist_to(root.join("badhash")).await.unwrap();
+        let temp_file = temp_file_at(root.join("badhash"), data).await;
         let root_path = root.to_str().unwrap();
         let res = put_file(root_path, temp_file, "md5:abcde").await;
         assert_eq!(
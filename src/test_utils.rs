@@ -1,6 +1,8 @@
 #[cfg(test)]
 pub mod tests {
+    use crate::hash::{hash_data, HashAlgorithm};
     use crate::metadata::Packet;
+    use serde_json::{json, Value};
     use std::collections::HashMap;
     use std::fs::File;
     use std::hash::Hash;
@@ -42,11 +44,122 @@ pub mod tests {
     pub fn initialize() {
         INIT.call_once(|| {
             let mut ar = Builder::new(File::create("example.tar").expect("File created"));
-            ar.append_dir_all("example", "tests/example").unwrap();
+            // Skip the packet index, which tests running against
+            // tests/example may be rewriting while we build the archive.
+            ar.append_path_with_name(
+                "tests/example/.outpack/config.json",
+                "example/.outpack/config.json",
+            )
+            .unwrap();
+            for dir in ["files", "location", "metadata"] {
+                ar.append_dir_all(
+                    format!("example/.outpack/{}", dir),
+                    format!("tests/example/.outpack/{}", dir),
+                )
+                .unwrap();
+            }
             ar.finish().unwrap();
         });
     }
 
+    /// A new, empty outpack root in a temporary directory.
+    pub fn get_empty_outpack_root(
+        path_archive: Option<&str>,
+        use_file_store: bool,
+        require_complete_tree: bool,
+    ) -> PathBuf {
+        let tmp_dir = tempdir::TempDir::new("outpack").expect("Temp dir created");
+        let root = tmp_dir.into_path();
+        crate::init::outpack_init(
+            root.to_str().unwrap(),
+            path_archive.map(String::from),
+            use_file_store,
+            require_complete_tree,
+        )
+        .unwrap();
+        root
+    }
+
+    /// Add a packet to a root with a file store, as if it had been run
+    /// there. `files` are pairs of path and contents.
+    pub fn create_packet(
+        root: &Path,
+        name: &str,
+        id: &str,
+        files: &[(&str, &str)],
+        depends: &[&str],
+    ) {
+        let root = root.to_str().unwrap();
+        let files: Vec<Value> = files
+            .iter()
+            .map(|(path, contents)| {
+                let hash = hash_data(contents.as_bytes(), HashAlgorithm::Sha256).to_string();
+                crate::store::put_from_reader(root, &mut contents.as_bytes(), &hash).unwrap();
+                json!({"path": path, "size": contents.len(), "hash": hash})
+            })
+            .collect();
+        let depends: Vec<Value> = depends
+            .iter()
+            .map(|id| json!({"packet": id, "query": "latest", "files": [{"here": "input.csv", "there": "data.csv"}]}))
+            .collect();
+        let metadata = json!({
+            "schema_version": "0.1.1",
+            "name": name,
+            "id": id,
+            "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+            "parameters": null,
+            "files": files,
+            "depends": depends,
+            "custom": null,
+            "git": null,
+        })
+        .to_string();
+        let hash = hash_data(metadata.as_bytes(), HashAlgorithm::Sha256);
+        crate::metadata::add_metadata(root, &metadata, &hash).unwrap();
+    }
+
+    /// Add a packet to a root without a file store, writing its files
+    /// into the archive at `archive` and hashing everything with
+    /// `algorithm`.
+    pub fn create_archive_packet(
+        root: &Path,
+        archive: &str,
+        name: &str,
+        id: &str,
+        files: &[(&str, &str)],
+        algorithm: HashAlgorithm,
+    ) {
+        let files: Vec<Value> = files
+            .iter()
+            .map(|(path, contents)| {
+                let dest = root.join(archive).join(name).join(id).join(path);
+                std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+                std::fs::write(dest, contents).unwrap();
+                let hash = hash_data(contents.as_bytes(), algorithm).to_string();
+                json!({"path": path, "size": contents.len(), "hash": hash})
+            })
+            .collect();
+        let metadata = json!({
+            "schema_version": "0.1.1",
+            "name": name,
+            "id": id,
+            "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+            "parameters": null,
+            "files": files,
+            "depends": [],
+            "custom": null,
+            "git": null,
+        })
+        .to_string();
+        let root_path = root.to_str().unwrap();
+        std::fs::write(root.join(".outpack").join("metadata").join(id), &metadata).unwrap();
+        let packet = serde_json::from_str(&metadata).unwrap();
+        crate::index::add_packet(root_path, &packet);
+        let hash = hash_data(metadata.as_bytes(), algorithm).to_string();
+        let time = std::time::SystemTime::now();
+        crate::location::mark_packet_known(id, "local", &hash, time, root_path).unwrap();
+    }
+
     pub fn get_temp_outpack_root() -> PathBuf {
         initialize();
         let tmp_dir = tempdir::TempDir::new("outpack").expect("Temp dir created");
@@ -0,0 +1,389 @@
+//! Upload sessions, for adding large files to the store in chunks.
+//!
+//! A session is started for a hash, chunks are written to it at any
+//! offset and in any order (re-sending a chunk is harmless), and once
+//! every byte has arrived it is finished: the data is checked against
+//! the hash and moved into the store. Sessions live on disk, under
+//! `.outpack/uploads`, so survive the server being restarted; those not
+//! touched for a while are removed by [`clean_sessions`].
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{fs, io};
+use tempfile::NamedTempFile;
+
+use crate::error::{self, Error, ErrorCode};
+use crate::{hash, store};
+
+/// Sessions not written to for this long are considered abandoned.
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+const SESSION_PREFIX: &str = "upload-";
+
+lazy_static! {
+    // Writing a chunk updates the session's list of received ranges, so
+    // requests for the same session must not interleave; those for
+    // different sessions can. Keyed by the session's path.
+    static ref SESSION_LOCKS: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>> =
+        Mutex::new(HashMap::new());
+}
+
+fn session_lock(path: &Path) -> Arc<Mutex<()>> {
+    SESSION_LOCKS
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+// Remove a session, along with its lock.
+fn remove_session(path: &Path) -> io::Result<()> {
+    fs::remove_dir_all(path)?;
+    SESSION_LOCKS.lock().unwrap().remove(path);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadSession {
+    pub id: String,
+    pub hash: String,
+    /// The byte ranges received so far, as sorted, non-overlapping
+    /// `[start, end)` pairs.
+    pub received: Vec<(u64, u64)>,
+    /// The total number of bytes received.
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    hash: String,
+    received: Vec<(u64, u64)>,
+}
+
+fn uploads_path(root: &str) -> PathBuf {
+    Path::new(root).join(".outpack").join("uploads")
+}
+
+fn session_path(root: &str, id: &str) -> io::Result<PathBuf> {
+    // The id ends up in a path, so must not be able to escape the
+    // uploads directory.
+    let valid = id.starts_with(SESSION_PREFIX)
+        && id.len() > SESSION_PREFIX.len()
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    let path = uploads_path(root).join(id);
+    if !valid || !path.is_dir() {
+        return Err(Error::new(
+            ErrorCode::NotFound,
+            format!("Upload session '{}' not found", id),
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+fn read_session(path: &Path) -> io::Result<SessionFile> {
+    let file = fs::File::open(path.join("session.json"))?;
+    Ok(serde_json::from_reader(io::BufReader::new(file))?)
+}
+
+fn write_session(path: &Path, session: &SessionFile) -> io::Result<()> {
+    let mut file = NamedTempFile::new_in(path)?;
+    serde_json::to_writer(&mut file, session)?;
+    file.persist(path.join("session.json"))
+        .map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn to_upload_session(id: &str, session: SessionFile) -> UploadSession {
+    let size = session
+        .received
+        .iter()
+        .map(|(start, end)| end - start)
+        .sum();
+    UploadSession {
+        id: String::from(id),
+        hash: session.hash,
+        received: session.received,
+        size,
+    }
+}
+
+// Add [start, end) to a sorted list of non-overlapping ranges, merging
+// any that overlap or touch.
+fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    if start == end {
+        return;
+    }
+    ranges.push((start, end));
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+/// Start a session for uploading the file with the given hash.
+pub fn start_session(root: &str, hash: &str) -> io::Result<UploadSession> {
+    hash.parse::<hash::Hash>()?;
+    // A convenient moment to tidy up after clients that gave up, though
+    // failing to do so is no reason not to start a new session.
+    if let Err(e) = clean_sessions(root, DEFAULT_SESSION_TIMEOUT, false) {
+        rocket::warn!("Failed to clean up old upload sessions: {}", e);
+    }
+
+    let uploads = uploads_path(root);
+    fs::create_dir_all(&uploads)?;
+    let path = tempfile::Builder::new()
+        .prefix(SESSION_PREFIX)
+        .rand_bytes(12)
+        .tempdir_in(&uploads)?
+        .into_path();
+    fs::File::create(path.join("data"))?;
+    let session = SessionFile {
+        hash: String::from(hash),
+        received: Vec::new(),
+    };
+    write_session(&path, &session)?;
+    let id = path.file_name().unwrap().to_string_lossy().to_string();
+    Ok(to_upload_session(&id, session))
+}
+
+pub fn get_session(root: &str, id: &str) -> io::Result<UploadSession> {
+    let path = session_path(root, id)?;
+    Ok(to_upload_session(id, read_session(&path)?))
+}
+
+/// Write `data` into a session's file, starting at byte `offset`.
+pub fn write_chunk(root: &str, id: &str, offset: u64, data: &[u8]) -> io::Result<UploadSession> {
+    let path = session_path(root, id)?;
+    let lock = session_lock(&path);
+    let _guard = lock.lock().unwrap();
+    let mut session = read_session(&path)?;
+    let mut file = fs::OpenOptions::new().write(true).open(path.join("data"))?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    add_range(&mut session.received, offset, offset + data.len() as u64);
+    write_session(&path, &session)?;
+    Ok(to_upload_session(id, session))
+}
+
+/// Finish a session, moving its file into the store once it has been
+/// checked against the session's hash. A session whose data does not
+/// match its hash is discarded, as there is no telling which chunk was
+/// wrong.
+pub fn finish_session(root: &str, id: &str) -> io::Result<()> {
+    // Not held while hashing the file, which may take a while; the
+    // client should not be sending any more chunks by now.
+    let path = session_path(root, id)?;
+    let session = {
+        let lock = session_lock(&path);
+        let _guard = lock.lock().unwrap();
+        read_session(&path)?
+    };
+    match session.received[..] {
+        [] | [(0, _)] => {}
+        _ => {
+            return Err(Error::new(
+                ErrorCode::BadRequest,
+                format!(
+                    "Upload session '{}' is incomplete: only received bytes {}",
+                    id,
+                    format_ranges(&session.received)
+                ),
+            )
+            .into());
+        }
+    }
+
+    let data = path.join("data");
+    let result = store::put_from_path(root, &data, &session.hash);
+    match &result {
+        // Anything other than a bad hash (e.g., a full disk) may go
+        // away, so keep the session to finish later.
+        Err(e) if error::code_of(e) != Some(ErrorCode::HashMismatch) => {}
+        _ => remove_session(&path)?,
+    }
+    result
+}
+
+/// Abandon a session, removing everything uploaded to it.
+pub fn abort_session(root: &str, id: &str) -> io::Result<()> {
+    let path = session_path(root, id)?;
+    let lock = session_lock(&path);
+    let _guard = lock.lock().unwrap();
+    remove_session(&path)
+}
+
+fn format_ranges(ranges: &[(u64, u64)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end - 1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Remove sessions that have not been written to for `max_age`,
+/// returning how many there were. With `dry_run`, nothing is removed.
+pub fn clean_sessions(root: &str, max_age: Duration, dry_run: bool) -> io::Result<usize> {
+    let uploads = uploads_path(root);
+    if !uploads.exists() {
+        return Ok(0);
+    }
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in fs::read_dir(uploads)? {
+        let path = entry?.path();
+        // The session file is rewritten with every chunk, so its age is
+        // the time since the session was last used.
+        let modified = match fs::metadata(path.join("session.json")) {
+            Ok(metadata) => metadata.modified()?,
+            Err(_) => fs::metadata(&path)?.modified()?,
+        };
+        let age = now.duration_since(modified).unwrap_or(Duration::ZERO);
+        if age >= max_age {
+            if !dry_run {
+                remove_session(&path)?;
+            }
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::test_utils::tests::get_empty_outpack_root;
+
+    fn sha256(data: &[u8]) -> String {
+        hash_data(data, HashAlgorithm::Sha256).to_string()
+    }
+
+    #[test]
+    fn can_merge_ranges() {
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, 10, 20);
+        add_range(&mut ranges, 0, 5);
+        assert_eq!(ranges, [(0, 5), (10, 20)]);
+        add_range(&mut ranges, 5, 10);
+        assert_eq!(ranges, [(0, 20)]);
+        add_range(&mut ranges, 15, 30);
+        add_range(&mut ranges, 30, 30);
+        assert_eq!(ranges, [(0, 30)]);
+    }
+
+    #[test]
+    fn can_upload_in_chunks_in_any_order() {
+        let root = get_empty_outpack_root(None, true, true);
+        let root = root.to_str().unwrap();
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let hash = sha256(data);
+
+        let session = start_session(root, &hash).unwrap();
+        assert_eq!(session.size, 0);
+        write_chunk(root, &session.id, 20, &data[20..]).unwrap();
+        let status = write_chunk(root, &session.id, 0, &data[..10]).unwrap();
+        assert_eq!(status.received, [(0, 10), (20, data.len() as u64)]);
+        assert_eq!(get_session(root, &session.id).unwrap(), status);
+
+        let err = finish_session(root, &session.id).unwrap_err();
+        assert_eq!(error::code_of(&err), Some(ErrorCode::BadRequest));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Upload session '{}' is incomplete: only received bytes 0-9, 20-42",
+                session.id
+            )
+        );
+
+        // Sending a chunk again is harmless.
+        write_chunk(root, &session.id, 5, &data[5..20]).unwrap();
+        let status = get_session(root, &session.id).unwrap();
+        assert_eq!(status.received, [(0, data.len() as u64)]);
+        assert_eq!(status.size, data.len() as u64);
+
+        assert!(!store::file_exists(root, &hash).unwrap());
+        finish_session(root, &session.id).unwrap();
+        let path = store::file_path(root, &hash).unwrap();
+        assert_eq!(fs::read(path).unwrap(), data);
+        assert_eq!(
+            get_session(root, &session.id).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn session_with_wrong_data_is_discarded() {
+        let root = get_empty_outpack_root(None, true, true);
+        let root = root.to_str().unwrap();
+        let hash = sha256(b"expected");
+        let session = start_session(root, &hash).unwrap();
+        write_chunk(root, &session.id, 0, b"something else").unwrap();
+        let err = finish_session(root, &session.id).unwrap_err();
+        assert_eq!(error::code_of(&err), Some(ErrorCode::HashMismatch));
+        assert!(get_session(root, &session.id).is_err());
+        assert!(!store::file_exists(root, &hash).unwrap());
+    }
+
+    #[test]
+    fn sessions_must_exist() {
+        let root = get_empty_outpack_root(None, true, true);
+        let root = root.to_str().unwrap();
+        for id in ["upload-abc", "../files", "upload-../../x"] {
+            let err = get_session(root, id).unwrap_err();
+            assert_eq!(error::code_of(&err), Some(ErrorCode::NotFound));
+            assert_eq!(
+                err.to_string(),
+                format!("Upload session '{}' not found", id)
+            );
+        }
+        assert!(start_session(root, "sha256").is_err());
+    }
+
+    #[test]
+    fn can_abort_and_clean_sessions() {
+        let root = get_empty_outpack_root(None, true, true);
+        let root = root.to_str().unwrap();
+        let hash = sha256(b"data");
+        let a = start_session(root, &hash).unwrap();
+        let b = start_session(root, &hash).unwrap();
+        assert_ne!(a.id, b.id);
+
+        abort_session(root, &a.id).unwrap();
+        assert!(get_session(root, &a.id).is_err());
+
+        assert_eq!(
+            clean_sessions(root, Duration::from_secs(60), false).unwrap(),
+            0
+        );
+        assert_eq!(clean_sessions(root, Duration::ZERO, true).unwrap(), 1);
+        assert!(get_session(root, &b.id).is_ok());
+        assert_eq!(clean_sessions(root, Duration::ZERO, false).unwrap(), 1);
+        assert!(get_session(root, &b.id).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn can_start_sessions_when_cleaning_up_fails() {
+        let root = get_empty_outpack_root(None, true, true);
+        let uploads = uploads_path(root.to_str().unwrap());
+        fs::create_dir_all(&uploads).unwrap();
+        std::os::unix::fs::symlink(uploads.join("missing"), uploads.join("dangling")).unwrap();
+        let root = root.to_str().unwrap();
+        assert!(clean_sessions(root, Duration::ZERO, true).is_err());
+        let session = start_session(root, &sha256(b"data")).unwrap();
+        assert!(get_session(root, &session.id).is_ok());
+    }
+}
@@ -10,7 +10,7 @@ lazy_static! {
 
 pub fn is_packet(name: &OsString) -> bool {
     let o = name.to_str();
-    o.map_or(false, is_packet_str)
+    o.is_some_and(is_packet_str)
 }
 
 pub fn is_packet_str(name: &str) -> bool {
@@ -0,0 +1,502 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::{fs, io};
+
+use crate::index::Index;
+use crate::{config, hash, index, location, metadata, store, utils};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Problem {
+    /// A file in the store whose contents do not match its hash.
+    CorruptFile { hash: String },
+    /// Metadata whose hash differs from the one recorded by a location.
+    CorruptMetadata { packet: String, location: String },
+    /// Metadata that can't be read, or has a file with a path outside
+    /// the packet; or, only checked when asked for with
+    /// `VerifyOptions::schema`, that does not conform to the outpack
+    /// metadata schema.
+    InvalidMetadata { packet: String, errors: Vec<String> },
+    /// A file used by an unpacked packet that cannot be found.
+    MissingFile { packet: String, hash: String },
+    /// A dependency of an unpacked packet that has not itself been
+    /// unpacked; only checked when `require_complete_tree` is set.
+    MissingDependency { packet: String, dependency: String },
+    /// A packet that a location claims to have but whose metadata is
+    /// not present.
+    MissingMetadata { packet: String, location: String },
+    /// A file in the store that no packet refers to.
+    OrphanedFile { hash: String },
+    /// Metadata that no location claims to have.
+    OrphanedMetadata { packet: String },
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct VerifyOptions {
+    /// Also check all metadata against the outpack metadata schema.
+    /// Metadata is checked as it is imported, so this is mostly useful
+    /// for metadata that arrived by other means or before that check.
+    pub schema: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct VerifyReport {
+    pub corrupt: Vec<Problem>,
+    pub missing: Vec<Problem>,
+    pub orphaned: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Whether the repository is consistent. Orphaned objects are
+    /// reported but are not errors; they are what `gc` tidies up.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty()
+    }
+}
+
+// Every packet with a metadata file, whether or not it can be read,
+// straight from the metadata directory rather than through the index.
+fn metadata_ids(root: &str) -> io::Result<Vec<String>> {
+    let path = Path::new(root).join(".outpack").join("metadata");
+    let mut ids = fs::read_dir(path)?
+        .filter_map(|r| r.ok())
+        .filter(|e| utils::is_packet(&e.file_name()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect::<Vec<String>>();
+    ids.sort();
+    Ok(ids)
+}
+
+// Packets left out of the index, as their metadata can't be read, unless
+// already reported by the schema check.
+fn verify_unreadable(index: &Index, report: &mut VerifyReport) {
+    for (id, problem) in index.unreadable() {
+        let reported = report
+            .corrupt
+            .iter()
+            .any(|p| matches!(p, Problem::InvalidMetadata { packet, .. } if packet == id));
+        if !reported {
+            report.corrupt.push(Problem::InvalidMetadata {
+                packet: id.clone(),
+                errors: vec![problem.clone()],
+            });
+        }
+    }
+}
+
+fn verify_store(root: &str, index: &Index, report: &mut VerifyReport) -> io::Result<()> {
+    let referenced: HashSet<&str> = index
+        .packets
+        .iter()
+        .flat_map(|packet| packet.files.iter().map(|f| f.hash.as_str()))
+        .collect();
+    // Files that only unreadable packets refer to would look orphaned,
+    // so none are reported as such until those are fixed.
+    let complete = index.unreadable().is_empty();
+    for hash in store::enumerate_files(root)? {
+        let path = store::file_path(root, &hash)?;
+        match hash::validate_hash_file(&path, &hash) {
+            // Gone since we listed the store, or unreadable; either way
+            // there is nothing we can say about its contents.
+            Err(e) if e.kind == hash::HashErrorKind::FileReadFailed => continue,
+            Err(_) => report
+                .corrupt
+                .push(Problem::CorruptFile { hash: hash.clone() }),
+            Ok(()) => {}
+        }
+        if complete && !referenced.contains(hash.as_str()) {
+            report.orphaned.push(Problem::OrphanedFile { hash });
+        }
+    }
+    Ok(())
+}
+
+fn verify_locations(root: &str, report: &mut VerifyReport) -> io::Result<()> {
+    let path = Path::new(root).join(".outpack").join("location");
+    let metadata_path = Path::new(root).join(".outpack").join("metadata");
+    let mut locations = fs::read_dir(path)?
+        .filter_map(|r| r.ok())
+        .collect::<Vec<_>>();
+    locations.sort_by_key(|entry| entry.file_name());
+
+    let mut known = HashSet::new();
+    for entry in locations {
+        let location = entry.file_name().to_string_lossy().to_string();
+        for packet in location::read_location(entry.path())? {
+            let path = metadata_path.join(&packet.packet);
+            if !path.exists() {
+                report.missing.push(Problem::MissingMetadata {
+                    packet: packet.packet,
+                    location: location.clone(),
+                });
+                continue;
+            }
+            if hash::validate_hash_data(&fs::read(path)?, &packet.hash).is_err() {
+                report.corrupt.push(Problem::CorruptMetadata {
+                    packet: packet.packet.clone(),
+                    location: location.clone(),
+                });
+            }
+            known.insert(packet.packet);
+        }
+    }
+
+    for id in metadata_ids(root)? {
+        if !known.contains(&id) {
+            report
+                .orphaned
+                .push(Problem::OrphanedMetadata { packet: id });
+        }
+    }
+    Ok(())
+}
+
+fn verify_schema(root: &str, report: &mut VerifyReport) -> io::Result<()> {
+    for id in metadata_ids(root)? {
+        let text = metadata::get_metadata_text(root, &id)?;
+        let result = match serde_json::from_str(&text) {
+            Ok(value) => metadata::validate_metadata(&value),
+            Err(e) => Err(vec![format!("Invalid JSON: {}", e)]),
+        };
+        if let Err(errors) = result {
+            report
+                .corrupt
+                .push(Problem::InvalidMetadata { packet: id, errors });
+        }
+    }
+    Ok(())
+}
+
+fn verify_packets(root: &str, index: &Index, report: &mut VerifyReport) -> io::Result<()> {
+    let core = config::read_config(root)?.core;
+    let unpacked: HashSet<String> = metadata::get_ids(root, Some(true))?.into_iter().collect();
+
+    for packet in index.packets.iter() {
+        if !unpacked.contains(&packet.id) {
+            continue;
+        }
+        let mut errors = Vec::new();
+        for file in &packet.files {
+            match store::packet_file_path(root, &core, packet, file) {
+                Ok(path) if !path.exists() => report.missing.push(Problem::MissingFile {
+                    packet: packet.id.clone(),
+                    hash: file.hash.clone(),
+                }),
+                Ok(_) => {}
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if !errors.is_empty() {
+            report.corrupt.push(Problem::InvalidMetadata {
+                packet: packet.id.clone(),
+                errors,
+            });
+        }
+        if core.require_complete_tree {
+            for dependency in &packet.depends {
+                if !unpacked.contains(&dependency.packet) {
+                    report.missing.push(Problem::MissingDependency {
+                        packet: packet.id.clone(),
+                        dependency: dependency.packet.clone(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that the repository at `root` is consistent: that files in the
+/// store match their hashes, that metadata matches the hashes recorded
+/// for it by each location, and that unpacked packets have all their
+/// files (and, with `require_complete_tree`, their dependencies).
+pub fn verify(root: &str) -> io::Result<VerifyReport> {
+    verify_with_options(root, &VerifyOptions::default())
+}
+
+/// As `verify`, optionally also checking metadata against the schema.
+pub fn verify_with_options(root: &str, options: &VerifyOptions) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    // Straight from the metadata files, so that anything too broken to
+    // be indexed is still reported in full.
+    if options.schema {
+        verify_schema(root, &mut report)?;
+    }
+    let index = index::get_packet_index(root)?;
+    verify_unreadable(&index, &mut report);
+    verify_store(root, &index, &mut report)?;
+    verify_locations(root, &mut report)?;
+    verify_packets(root, &index, &mut report)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{hash_data, HashAlgorithm};
+    use crate::init::outpack_init;
+    use crate::test_utils::tests::{create_packet, get_empty_outpack_root, get_temp_outpack_root};
+    use std::time::SystemTime;
+    use tempfile::TempDir;
+
+    const ID: &str = "20230427-150828-68772cee";
+    const DEPENDENCY: &str = "20170818-164847-7574883b";
+
+    // A root holding a single packet, with one file, that was imported
+    // properly and so should verify cleanly.
+    fn create_root(depends: &[&str]) -> (TempDir, String, String) {
+        create_root_with_store(depends, true)
+    }
+
+    // As create_root, but without a file store the file is kept in the
+    // archive instead.
+    fn create_root_with_store(depends: &[&str], use_file_store: bool) -> (TempDir, String, String) {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().to_str().unwrap().to_string();
+        let path_archive = (!use_file_store).then(|| String::from("archive"));
+        outpack_init(&root, path_archive, use_file_store, true).unwrap();
+
+        let data = "Testing 123.";
+        let file_hash = hash_data(data.as_bytes(), HashAlgorithm::Sha256).to_string();
+        let path = if use_file_store {
+            store::file_path(&root, &file_hash).unwrap()
+        } else {
+            Path::new(&root)
+                .join("archive")
+                .join("data")
+                .join(ID)
+                .join("data.csv")
+        };
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+
+        let depends_json = depends
+            .iter()
+            .map(|id| {
+                format!(
+                    r#"{{"packet": "{}", "query": "latest",
+                        "files": [{{"here": "input.csv", "there": "data.csv"}}]}}"#,
+                    id
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+        let metadata = format!(
+            r#"{{"schema_version": "0.1.1", "name": "data", "id": "{}",
+                "time": {{"start": 1682608108.4139, "end": 1682608108.4309}},
+                "parameters": null,
+                "files": [{{"path": "data.csv", "size": 12, "hash": "{}"}}],
+                "depends": [{}], "custom": null, "git": null}}"#,
+            ID, file_hash, depends_json
+        );
+        for id in depends {
+            location::mark_packet_known(id, "local", "sha256:00", SystemTime::now(), &root)
+                .unwrap();
+        }
+        let hash = hash_data(metadata.as_bytes(), HashAlgorithm::Sha256);
+        if use_file_store {
+            metadata::add_metadata(&root, &metadata, &hash).unwrap();
+        } else {
+            // Importing checks the file store, so write the metadata
+            // out as an unpacked packet ourselves.
+            let path = Path::new(&root).join(".outpack").join("metadata").join(ID);
+            fs::write(path, &metadata).unwrap();
+            location::mark_packet_known(ID, "local", &hash.to_string(), SystemTime::now(), &root)
+                .unwrap();
+        }
+        (tmp, root, file_hash)
+    }
+
+    #[test]
+    fn consistent_root_is_ok() {
+        let (_tmp, root, _) = create_root(&[]);
+        let report = verify(&root).unwrap();
+        assert_eq!(report, VerifyReport::default());
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn detects_corrupt_files() {
+        let (_tmp, root, file_hash) = create_root(&[]);
+        fs::write(store::file_path(&root, &file_hash).unwrap(), "changed").unwrap();
+        let report = verify(&root).unwrap();
+        assert_eq!(
+            report.corrupt,
+            vec![Problem::CorruptFile { hash: file_hash }]
+        );
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn ignores_stray_files_in_store() {
+        let (_tmp, root, file_hash) = create_root(&[]);
+        let path = store::file_path(&root, &file_hash).unwrap();
+        fs::write(path.parent().unwrap().join(".tmpAbc123"), "partial").unwrap();
+        let report = verify(&root).unwrap();
+        assert_eq!(report, VerifyReport::default());
+    }
+
+    #[test]
+    fn can_check_metadata_against_schema() {
+        let (_tmp, root, _) = create_root(&[]);
+        let path = Path::new(&root).join(".outpack").join("metadata").join(ID);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace(r#""custom": null"#, r#""custom": 1"#)).unwrap();
+        let options = VerifyOptions { schema: true };
+        let report = verify_with_options(&root, &options).unwrap();
+        assert!(report.corrupt.contains(&Problem::InvalidMetadata {
+            packet: String::from(ID),
+            errors: vec![String::from(
+                "/custom: 1 is not of types \"null\", \"object\""
+            )]
+        }));
+        // Only checked when asked for.
+        let report = verify(&root).unwrap();
+        assert!(!report
+            .corrupt
+            .iter()
+            .any(|p| matches!(p, Problem::InvalidMetadata { .. })));
+    }
+
+    #[test]
+    fn reports_unreadable_metadata_without_failing() {
+        let (_tmp, root, _) = create_root(&[]);
+        let path = Path::new(&root).join(".outpack").join("metadata").join(ID);
+        fs::write(&path, "not json").unwrap();
+        let report = verify(&root).unwrap();
+        let invalid: Vec<&Problem> = report
+            .corrupt
+            .iter()
+            .filter(|p| matches!(p, Problem::InvalidMetadata { .. }))
+            .collect();
+        assert!(matches!(
+            invalid[..],
+            [Problem::InvalidMetadata { packet, errors }]
+                if packet == ID && errors[0].starts_with("Failed to read metadata")
+        ));
+        // The packet's file can't be told apart from an orphaned one.
+        assert_eq!(report.orphaned, vec![]);
+
+        // The schema check reports the same packet, in more detail.
+        let options = VerifyOptions { schema: true };
+        let report = verify_with_options(&root, &options).unwrap();
+        let invalid: Vec<&Problem> = report
+            .corrupt
+            .iter()
+            .filter(|p| matches!(p, Problem::InvalidMetadata { .. }))
+            .collect();
+        assert!(matches!(
+            invalid[..],
+            [Problem::InvalidMetadata { packet, errors }]
+                if packet == ID && errors[0].starts_with("Invalid JSON")
+        ));
+    }
+
+    #[test]
+    fn reports_files_with_invalid_paths() {
+        let root = get_empty_outpack_root(None, true, true);
+        create_packet(&root, "data", ID, &[("../escape.txt", "x")], &[]);
+        let report = verify(root.to_str().unwrap()).unwrap();
+        assert_eq!(
+            report.corrupt,
+            vec![Problem::InvalidMetadata {
+                packet: String::from(ID),
+                errors: vec![format!(
+                    "Packet '{}' has a file with invalid path 'data/../escape.txt'",
+                    ID
+                )]
+            }]
+        );
+    }
+
+    #[test]
+    fn detects_corrupt_metadata() {
+        let (_tmp, root, _) = create_root(&[]);
+        let path = Path::new(&root).join(".outpack").join("metadata").join(ID);
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("data.csv", "other.csv")).unwrap();
+        let report = verify(&root).unwrap();
+        assert_eq!(
+            report.corrupt,
+            vec![Problem::CorruptMetadata {
+                packet: String::from(ID),
+                location: String::from("local")
+            }]
+        );
+    }
+
+    #[test]
+    fn finds_files_in_archive() {
+        let (_tmp, root, file_hash) = create_root_with_store(&[], false);
+        assert_eq!(verify(&root).unwrap(), VerifyReport::default());
+
+        let path = Path::new(&root)
+            .join("archive")
+            .join("data")
+            .join(ID)
+            .join("data.csv");
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            verify(&root).unwrap().missing,
+            vec![Problem::MissingFile {
+                packet: String::from(ID),
+                hash: file_hash
+            }]
+        );
+    }
+
+    #[test]
+    fn detects_missing_and_orphaned_objects() {
+        let (_tmp, root, file_hash) = create_root(&[DEPENDENCY]);
+        fs::remove_file(store::file_path(&root, &file_hash).unwrap()).unwrap();
+        let report = verify(&root).unwrap();
+        assert_eq!(
+            report.missing,
+            vec![
+                Problem::MissingMetadata {
+                    packet: String::from(DEPENDENCY),
+                    location: String::from("local")
+                },
+                Problem::MissingFile {
+                    packet: String::from(ID),
+                    hash: file_hash
+                },
+            ]
+        );
+        assert_eq!(report.orphaned, vec![]);
+    }
+
+    #[test]
+    fn detects_orphaned_metadata() {
+        let (_tmp, root, _) = create_root(&[]);
+        let path = Path::new(&root).join(".outpack").join("location");
+        fs::remove_file(path.join("local").join(ID)).unwrap();
+        let report = verify(&root).unwrap();
+        assert_eq!(
+            report.orphaned,
+            vec![Problem::OrphanedMetadata {
+                packet: String::from(ID)
+            }]
+        );
+        assert!(report.is_ok());
+    }
+
+    #[test]
+    fn reports_missing_dependencies_for_complete_tree() {
+        let root = get_temp_outpack_root();
+        let report = verify(root.to_str().unwrap()).unwrap();
+        assert!(report.missing.contains(&Problem::MissingDependency {
+            packet: String::from(DEPENDENCY),
+            dependency: String::from("20170818-164043-7cdcde4b")
+        }));
+        assert_eq!(
+            report.orphaned,
+            vec![Problem::OrphanedFile {
+                hash: String::from(
+                    "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"
+                )
+            }]
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use jsonschema::{Draft, JSONSchema, SchemaResolverError};
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::local::blocking::Client;
 use rocket::serde::{Deserialize, Serialize};
 use rocket::{Build, Rocket};
@@ -293,7 +293,8 @@ fn returns_404_if_packet_not_found() {
     assert_eq!(response.content_type(), Some(ContentType::JSON));
 
     let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
-    validate_error(&body, Some("packet with id 'bad-id' does not exist"))
+    validate_error(&body, Some("packet with id 'bad-id' does not exist"));
+    assert_error_code(&body, "PACKET_NOT_FOUND");
 }
 
 #[test]
@@ -317,6 +318,133 @@ fn can_get_file() {
     assert_eq!(response.into_bytes().unwrap(), buffer);
 }
 
+fn read_example_file() -> Vec<u8> {
+    let path = Path::new("tests/example/.outpack/files/sha256/b1/")
+        .join("89579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248");
+    fs::read(path).unwrap()
+}
+
+#[test]
+fn file_has_etag_and_accepts_ranges() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client.get(format!("/file/{}", hash)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Accept-Ranges"), Some("bytes"));
+    assert_eq!(
+        response.headers().get_one("ETag"),
+        Some(format!("\"{}\"", hash).as_str())
+    );
+
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("If-None-Match", format!("\"{}\"", hash)))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotModified);
+    assert!(response.into_bytes().is_none());
+
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("If-None-Match", "\"md5:abc\""))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().unwrap(), read_example_file());
+}
+
+#[test]
+fn can_get_file_range() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let contents = read_example_file();
+
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("Range", "bytes=10-19"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(response.content_type(), Some(ContentType::Binary));
+    assert_eq!(
+        response.headers().get_one("Content-Range"),
+        Some("bytes 10-19/115")
+    );
+    assert_eq!(response.headers().get_one("Content-Length"), Some("10"));
+    assert_eq!(response.into_bytes().unwrap(), &contents[10..20]);
+
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("Range", "bytes=-5"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(response.into_bytes().unwrap(), &contents[110..]);
+}
+
+#[test]
+fn can_get_multiple_file_ranges() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let digest = "b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let contents = read_example_file();
+
+    let response = client
+        .get(format!("/file/sha256:{}", digest))
+        .header(Header::new("Range", "bytes=0-4, 100-"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(
+        response.headers().get_one("Content-Type").unwrap(),
+        format!("multipart/byteranges; boundary={}", digest)
+    );
+    let length: usize = response
+        .headers()
+        .get_one("Content-Length")
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut expected = format!(
+        "--{0}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-4/115\r\n\r\n",
+        digest
+    )
+    .into_bytes();
+    expected.extend_from_slice(&contents[0..5]);
+    expected.extend(format!(
+        "\r\n--{0}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 100-114/115\r\n\r\n",
+        digest
+    ).into_bytes());
+    expected.extend_from_slice(&contents[100..]);
+    expected.extend(format!("\r\n--{}--\r\n", digest).into_bytes());
+
+    let body = response.into_bytes().unwrap();
+    assert_eq!(body.len(), length);
+    assert_eq!(body, expected);
+}
+
+#[test]
+fn unsatisfiable_range_is_rejected() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("Range", "bytes=200-"))
+        .dispatch();
+    assert_eq!(response.status(), Status::RangeNotSatisfiable);
+    assert_eq!(
+        response.headers().get_one("Content-Range"),
+        Some("bytes */115")
+    );
+
+    // Ranges we can't parse are ignored, and the whole file sent.
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(Header::new("Range", "lines=1-2"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().unwrap(), read_example_file());
+}
+
 #[test]
 fn returns_404_if_file_not_found() {
     let rocket = get_test_rocket();
@@ -402,6 +530,7 @@ fn missing_packets_propagates_errors() {
 
     let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
     validate_error(&body, Some("Invalid packet id"));
+    assert_error_code(&body, "INVALID_PACKET_ID");
 }
 
 #[test]
@@ -467,6 +596,7 @@ fn missing_files_propagates_errors() {
 
     let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
     validate_error(&body, Some("Invalid hash format 'badhash'"));
+    assert_error_code(&body, "INVALID_HASH");
 }
 
 #[test]
@@ -483,6 +613,139 @@ fn missing_files_validates_request_body() {
 
     let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
     validate_error(&body, Some("EOF while parsing a value at line 1 column 0"));
+    assert_error_code(&body, "BAD_REQUEST");
+}
+
+#[derive(Serialize, Deserialize)]
+struct Query {
+    query: String,
+    include_metadata: bool,
+}
+
+#[test]
+fn can_query_packets() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/packets/query")
+        .json(&Query {
+            query: String::from(r#"latest(parameter:disease == "YF")"#),
+            include_metadata: false,
+        })
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_success("server", "ids.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries.first().unwrap().as_str(),
+        Some("20180818-164043-7cdcde4b")
+    );
+}
+
+#[test]
+fn can_query_packets_with_metadata() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/packets/query")
+        .json(&Query {
+            query: String::from("parameter:pull_data == TRUE"),
+            include_metadata: true,
+        })
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_success("server", "list.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].get("id").unwrap().as_str().unwrap(),
+        "20180220-095832-16a4bbed"
+    );
+    assert_eq!(
+        entries[0].get("name").unwrap().as_str().unwrap(),
+        "modup-201707-params1"
+    );
+}
+
+#[test]
+fn can_query_packets_with_this_parameters() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/packets/query")
+        .header(ContentType::JSON)
+        .body(
+            r#"{
+                "query": "parameter:size == this:size",
+                "this": { "size": 10 }
+            }"#,
+        )
+        .dispatch();
+
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_success("server", "ids.json", &body);
+    let entries = body.get("data").unwrap().as_array().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries.first().unwrap().as_str(),
+        Some("20180220-095832-16a4bbed")
+    );
+}
+
+#[test]
+fn query_reports_parse_errors() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/packets/query")
+        .json(&Query {
+            query: String::from("name == invalid"),
+            include_metadata: false,
+        })
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("expected lookup or literal"));
+    let error = body.get("errors").unwrap().get(0).unwrap();
+    assert_eq!(error.get("error").unwrap(), "QUERY_PARSE_ERROR");
+    assert_eq!(error.get("position").unwrap().get("line").unwrap(), 1);
+    assert_eq!(error.get("position").unwrap().get("column").unwrap(), 9);
+}
+
+#[test]
+fn query_reports_evaluation_errors() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/packets/query")
+        .json(&Query {
+            query: String::from("single(parameter:pull_data == false)"),
+            include_metadata: false,
+        })
+        .dispatch();
+
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some("Query found 0 packets, but expected exactly one"),
+    );
+    let error = body.get("errors").unwrap().get(0).unwrap();
+    assert_eq!(error.get("error").unwrap(), "QUERY_EVAL_ERROR");
+    assert!(error.get("position").is_none());
 }
 
 #[test]
@@ -538,6 +801,62 @@ fn file_post_handles_errors() {
         &body,
         Some("Expected hash 'md5:bad4a54' but found 'md5:098f6bcd4621d373cade4e832627b4f6'"),
     );
+    assert_error_code(&body, "HASH_MISMATCH");
+}
+
+fn post_metadata(client: &Client, metadata: &Value) -> (Status, Value) {
+    let content = metadata.to_string();
+    let hash = format!(
+        "sha256:{:x}",
+        Sha256::new().chain_update(&content).finalize()
+    );
+    let response = client
+        .post(format!("/packet/{}", hash))
+        .body(content)
+        .header(ContentType::Text)
+        .dispatch();
+    let status = response.status();
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    (status, body)
+}
+
+#[test]
+fn post_metadata_reports_what_is_missing() {
+    let root = get_test_dir();
+    let rocket = outpack::api::api(&root).unwrap();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let mut metadata = serde_json::json!({
+        "schema_version": "0.1.1",
+        "name": "data",
+        "id": "20230427-150828-68772cee",
+        "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+        "parameters": null,
+        "files": [{"path": "a.txt", "size": 1, "hash": "md5:0cc175b9c0f1b6a831c399e269772661"}],
+        "depends": [],
+        "custom": null,
+        "git": null,
+    });
+    let (status, body) = post_metadata(&client, &metadata);
+    assert_eq!(status, Status::BadRequest);
+    validate_error(&body, Some("as files missing"));
+    assert_error_code(&body, "MISSING_FILES");
+
+    metadata["files"] = serde_json::json!([]);
+    metadata["depends"] = serde_json::json!([
+        {"packet": "20230427-150828-00000000", "query": "latest",
+         "files": [{"here": "input.csv", "there": "data.csv"}]}
+    ]);
+    let (status, body) = post_metadata(&client, &metadata);
+    assert_eq!(status, Status::BadRequest);
+    validate_error(&body, Some("as dependencies missing"));
+    assert_error_code(&body, "MISSING_DEPENDENCIES");
+
+    let (status, body) = post_metadata(&client, &serde_json::json!({"name": "data"}));
+    assert_eq!(status, Status::BadRequest);
+    validate_error(&body, Some("does not match the schema"));
+    validate_error(&body, Some(r#"/: \"id\" is a required property"#));
+    validate_error(&body, Some(r#"/: \"time\" is a required property"#));
+    assert_error_code(&body, "INVALID_METADATA");
 }
 
 #[test]
@@ -556,6 +875,8 @@ fn can_post_metadata() {
                               "parameters": null,
                               "files": [],
                               "depends": [],
+                              "custom": null,
+                              "git": null,
                               "script": [
                                 "orderly.R"
                               ]
@@ -588,6 +909,533 @@ fn can_post_metadata() {
     assert_eq!(get_metadata_response.status(), Status::Ok);
 }
 
+#[test]
+fn can_upload_file_in_chunks() {
+    let root = get_test_dir();
+    let rocket = outpack::api::api(&root).unwrap();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let content = "the quick brown fox jumps over the lazy dog";
+    let hash = format!(
+        "sha256:{:x}",
+        Sha256::new().chain_update(content).finalize()
+    );
+
+    let response = client.post(format!("/upload/{}", hash)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let id = body["data"]["id"].as_str().unwrap().to_string();
+    assert_eq!(body["data"]["hash"], hash);
+    assert_eq!(body["data"]["size"], 0);
+
+    let response = client
+        .put(format!("/upload/{}?offset=20", id))
+        .body(&content[20..])
+        .header(ContentType::Binary)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = client.post(format!("/upload/{}/finish", id)).dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some(&format!(
+            "Upload session '{}' is incomplete: only received bytes 20-42",
+            id
+        )),
+    );
+
+    client
+        .put(format!("/upload/{}?offset=0", id))
+        .body(&content[..20])
+        .header(ContentType::Binary)
+        .dispatch();
+    let response = client.get(format!("/upload/{}", id)).dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["received"], serde_json::json!([[0, 43]]));
+
+    let response = client.post(format!("/upload/{}/finish", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_success("server", "null-response.json", &body);
+
+    let response = client.get(format!("/file/{}", hash)).dispatch();
+    assert_eq!(response.into_string().unwrap(), content);
+    let response = client.get(format!("/upload/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn can_abort_upload() {
+    let root = get_test_dir();
+    let rocket = outpack::api::api(&root).unwrap();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let hash = format!("sha256:{:x}", Sha256::new().chain_update("x").finalize());
+
+    let response = client.post(format!("/upload/{}", hash)).dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let id = body["data"]["id"].as_str().unwrap().to_string();
+
+    let response = client.delete(format!("/upload/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.delete(format!("/upload/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some(&format!("Upload session '{}' not found", id)));
+
+    let response = client.post("/upload/sha256").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+// Post a packet with a single file to the server, returning its id.
+fn post_packet(client: &Client, path: &str, data: &str) -> String {
+    let id = "20230427-150828-68772cee";
+    let hash = format!("sha256:{:x}", Sha256::new().chain_update(data).finalize());
+    let response = client
+        .post(format!("/file/{}", hash))
+        .body(data)
+        .header(ContentType::Binary)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let metadata = serde_json::json!({
+        "schema_version": "0.1.1",
+        "name": "data",
+        "id": id,
+        "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+        "parameters": null,
+        "files": [{"path": path, "size": data.len(), "hash": hash}],
+        "depends": [],
+        "custom": null,
+        "git": null,
+    })
+    .to_string();
+    let hash = format!(
+        "sha256:{:x}",
+        Sha256::new().chain_update(&metadata).finalize()
+    );
+    let response = client
+        .post(format!("/packet/{}", hash))
+        .body(metadata)
+        .header(ContentType::Text)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    String::from(id)
+}
+
+#[test]
+fn can_get_packet_file_by_path() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let id = post_packet(&client, "out/report.html", "<p>hello</p>");
+
+    let response = client
+        .get(format!("/packet/{}/file/out/report.html", id))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    assert_eq!(
+        response.headers().get_one("Content-Disposition"),
+        Some("attachment; filename=\"report.html\"")
+    );
+    assert_eq!(response.into_string().unwrap(), "<p>hello</p>");
+
+    let response = client
+        .get(format!("/packet/{}/file/out/report.html", id))
+        .header(Header::new("Range", "bytes=3-7"))
+        .dispatch();
+    assert_eq!(response.status(), Status::PartialContent);
+    assert_eq!(response.into_string().unwrap(), "hello");
+
+    let response = client
+        .get(format!("/packet/{}/file/report.html", id))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some(&format!("Packet '{}' has no file 'report.html'", id)),
+    );
+}
+
+#[test]
+fn can_get_packet_archive() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let id = post_packet(&client, "out/data.csv", "a,b\n1,2\n");
+
+    let response = client.get(format!("/packet/{}/archive", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::ZIP));
+    assert_eq!(
+        response.headers().get_one("Content-Disposition"),
+        Some(format!("attachment; filename=\"{}.zip\"", id).as_str())
+    );
+    let bytes = response.into_bytes().unwrap();
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+    let mut contents = String::new();
+    zip.by_name("out/data.csv")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "a,b\n1,2\n");
+
+    let response = client
+        .get(format!("/packet/{}/archive?format=tar.gz&glob=*.txt", id))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::GZIP));
+    let bytes = response.into_bytes().unwrap();
+    let mut archive = Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+    assert_eq!(archive.entries().unwrap().count(), 0);
+}
+
+#[test]
+fn packet_archive_handles_errors() {
+    let rocket = get_test_rocket();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    let id = post_packet(&client, "data.csv", "a\n");
+
+    let response = client
+        .get(format!("/packet/{}/archive?format=rar", id))
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some("Unsupported archive format 'rar', expected 'zip' or 'tar.gz'"),
+    );
+
+    let response = client
+        .get("/packet/20230427-150828-68772cef/archive")
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some("packet with id '20230427-150828-68772cef' does not exist"),
+    );
+}
+
+fn get_auth_rocket() -> Rocket<Build> {
+    use outpack::auth::{Auth, Scope, TokenAuthenticator, TokenConfig};
+    let tokens = vec![
+        TokenConfig {
+            token: String::from("admin"),
+            scopes: vec![Scope::Read, Scope::Write],
+            packets: None,
+        },
+        TokenConfig {
+            token: String::from("reader"),
+            scopes: vec![Scope::Read],
+            packets: None,
+        },
+        TokenConfig {
+            token: String::from("params"),
+            scopes: vec![Scope::Read],
+            packets: Some(vec![String::from("modup-201707-params1")]),
+        },
+    ];
+    let auth = Auth::none().with(Box::new(TokenAuthenticator::new(tokens)));
+    let options = outpack::api::ServerOptions {
+        auth,
+        ..Default::default()
+    };
+    outpack::api::api_with_options(&get_test_dir(), options).unwrap()
+}
+
+fn bearer(token: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+#[test]
+fn requests_must_be_authenticated() {
+    let client = Client::tracked(get_auth_rocket()).expect("valid rocket instance");
+    assert_eq!(client.get("/").dispatch().status(), Status::Ok);
+
+    let response = client.get("/metadata/list").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(
+        response.headers().get_one("WWW-Authenticate"),
+        Some("Bearer")
+    );
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("Missing bearer token"));
+    assert_eq!(body["errors"][0]["error"], "UNAUTHORIZED");
+
+    let response = client
+        .get("/metadata/list")
+        .header(bearer("wrong"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("Invalid bearer token"));
+
+    let response = client
+        .get("/metadata/list")
+        .header(bearer("reader"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn writing_requires_write_scope() {
+    let client = Client::tracked(get_auth_rocket()).expect("valid rocket instance");
+    let data = "test";
+    let hash = format!("sha256:{:x}", Sha256::new().chain_update(data).finalize());
+
+    let response = client
+        .post(format!("/file/{}", hash))
+        .body(data)
+        .header(ContentType::Binary)
+        .header(bearer("reader"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("This requires the 'write' scope"));
+    assert_eq!(body["errors"][0]["error"], "FORBIDDEN");
+
+    let response = client
+        .post(format!("/file/{}", hash))
+        .body(data)
+        .header(ContentType::Binary)
+        .header(bearer("admin"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn reading_can_be_restricted_to_packet_names() {
+    let client = Client::tracked(get_auth_rocket()).expect("valid rocket instance");
+
+    let response = client
+        .get("/metadata/list")
+        .header(bearer("params"))
+        .dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let ids: Vec<&str> = body["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["packet"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, ["20180220-095832-16a4bbed"]);
+
+    let response = client
+        .get("/packit/metadata")
+        .header(bearer("params"))
+        .dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+
+    let response = client
+        .get("/metadata/20180220-095832-16a4bbed/json")
+        .header(bearer("params"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client
+        .get("/metadata/20180818-164043-7cdcde4b/text")
+        .header(bearer("params"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(
+        &body,
+        Some("packet with id '20180818-164043-7cdcde4b' does not exist"),
+    );
+
+    let response = client
+        .post("/packets/query")
+        .json(&serde_json::json!({"query": "name == \"modup-201707-queries1\""}))
+        .header(bearer("params"))
+        .dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(body["data"], serde_json::json!([]));
+
+    // Queries only ever see the readable packets, so the latest packet
+    // is the latest of those.
+    let response = client
+        .post("/packets/query")
+        .json(&serde_json::json!({"query": "latest"}))
+        .header(bearer("params"))
+        .dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(
+        body["data"],
+        serde_json::json!(["20180220-095832-16a4bbed"])
+    );
+
+    let response = client.get("/checksum").header(bearer("params")).dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    let expected = format!(
+        "sha256:{:x}",
+        Sha256::new()
+            .chain_update("20180220-095832-16a4bbed")
+            .finalize()
+    );
+    assert_eq!(body["data"], expected);
+
+    let response = client
+        .post("/packets/missing")
+        .json(&serde_json::json!({
+            "ids": ["20180220-095832-16a4bbed", "20180818-164043-7cdcde4b"],
+            "unpacked": false
+        }))
+        .header(bearer("params"))
+        .dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(
+        body["data"],
+        serde_json::json!(["20180818-164043-7cdcde4b"])
+    );
+
+    // A file in the store that no readable packet refers to
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(bearer("params"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client
+        .get(format!("/file/{}", hash))
+        .header(bearer("reader"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    for (token, missing) in [
+        ("params", serde_json::json!([hash])),
+        ("reader", serde_json::json!([])),
+    ] {
+        let response = client
+            .post("/files/missing")
+            .json(&serde_json::json!({ "hashes": [hash] }))
+            .header(bearer(token))
+            .dispatch();
+        let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(body["data"], missing);
+    }
+}
+
+#[test]
+fn read_only_server_rejects_writes() {
+    let options = outpack::api::ServerOptions {
+        read_only: true,
+        ..Default::default()
+    };
+    let rocket = outpack::api::api_with_options(&get_test_dir(), options).unwrap();
+    let client = Client::tracked(rocket).expect("valid rocket instance");
+    assert_eq!(client.get("/metadata/list").dispatch().status(), Status::Ok);
+
+    let data = "test";
+    let hash = format!("sha256:{:x}", Sha256::new().chain_update(data).finalize());
+    let response = client
+        .post(format!("/file/{}", hash))
+        .body(data)
+        .header(ContentType::Binary)
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("This server is read-only"));
+    assert_eq!(body["errors"][0]["error"], "FORBIDDEN");
+
+    let response = client.post(format!("/upload/{}", hash)).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn read_only_can_be_configured_and_relaxes_preflight() {
+    let root = get_test_dir();
+    let path = Path::new(&root).join(".outpack").join("config.json");
+    let mut config: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    config["core"]["require_complete_tree"] = Value::Bool(false);
+    fs::write(&path, config.to_string()).unwrap();
+    let err = outpack::api::api(&root).unwrap_err();
+    assert_eq!(err, "Outpack must be configured to require a complete tree");
+
+    config["server"] = serde_json::json!({"read_only": true});
+    fs::write(&path, config.to_string()).unwrap();
+    let client = Client::tracked(outpack::api::api(&root).unwrap()).unwrap();
+    let response = client
+        .post("/packet/sha256:abc")
+        .body("{}")
+        .header(ContentType::Text)
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+// An orderly-style root: sha1 hashes, and files kept only in the
+// archive, with a single packet.
+fn get_archive_root() -> (String, String, String) {
+    let root = TempDir::new("outpack").unwrap().into_path();
+    let root = root.to_str().unwrap().to_string();
+    outpack::init::outpack_init(&root, Some(String::from("archive")), false, true).unwrap();
+    let config_path = Path::new(&root).join(".outpack").join("config.json");
+    let mut config: Value =
+        serde_json::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+    config["core"]["hash_algorithm"] = Value::from("sha1");
+    fs::write(&config_path, config.to_string()).unwrap();
+
+    let id = "20230427-150828-68772cee";
+    let data = "a,b\n1,2\n";
+    let dir = Path::new(&root).join("archive").join("data").join(id);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("data.csv"), data).unwrap();
+    let hash = format!("sha1:{:x}", sha1::Sha1::new().chain_update(data).finalize());
+    let metadata = serde_json::json!({
+        "schema_version": "0.1.1",
+        "name": "data",
+        "id": id,
+        "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+        "parameters": null,
+        "files": [{"path": "data.csv", "size": data.len(), "hash": hash}],
+        "depends": [],
+        "custom": null,
+        "git": null,
+    })
+    .to_string();
+    let metadata_path = Path::new(&root).join(".outpack").join("metadata").join(id);
+    fs::write(metadata_path, &metadata).unwrap();
+    let metadata_hash = format!(
+        "sha1:{:x}",
+        sha1::Sha1::new().chain_update(&metadata).finalize()
+    );
+    let time = std::time::SystemTime::now();
+    outpack::location::mark_packet_known(id, "local", &metadata_hash, time, &root).unwrap();
+    (root, String::from(id), hash)
+}
+
+#[test]
+fn can_serve_archive_root() {
+    let (root, id, hash) = get_archive_root();
+    let client = Client::tracked(outpack::api::api(&root).unwrap()).unwrap();
+
+    let response = client.get("/checksum").dispatch();
+    let body: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert!(body["data"].as_str().unwrap().starts_with("sha1:"));
+
+    let response = client.get(format!("/file/{}", hash)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "a,b\n1,2\n");
+
+    let response = client
+        .get(format!("/packet/{}/file/data.csv", id))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let missing = format!("sha1:{:x}", sha1::Sha1::new().chain_update("x").finalize());
+    let response = client.get(format!("/file/{}", missing)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    // With no file store to put them in, files can't be added.
+    let response = client
+        .post(format!("/file/{}", missing))
+        .body("x")
+        .header(ContentType::Binary)
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let body = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    validate_error(&body, Some("This server is read-only"));
+}
+
 #[test]
 fn catches_arbitrary_404() {
     let rocket = get_test_rocket();
@@ -634,6 +1482,10 @@ fn validate_error(instance: &Value, message: Option<&str>) {
     }
 }
 
+fn assert_error_code(instance: &Value, code: &str) {
+    assert_eq!(instance["errors"][0]["error"], code);
+}
+
 fn assert_valid(instance: &Value, compiled: &JSONSchema) {
     let result = compiled.validate(&instance);
     if let Err(errors) = result {
@@ -0,0 +1,316 @@
+#![cfg(feature = "client")]
+
+use outpack::auth::{Auth, Scope, TokenAuthenticator, TokenConfig};
+use outpack::client::Client;
+use outpack::config::{Location, LocationKind};
+use outpack::query::QueryContext;
+use rocket::config::{LogLevel, Shutdown};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::time::Duration;
+use tempdir::TempDir;
+
+// Serve `root` from a background thread on a free port, returning the
+// url of the server once it is accepting connections. The server runs
+// until the test process exits.
+fn start_server(root: &str) -> String {
+    start_server_with_auth(root, Auth::none())
+}
+
+fn start_server_with_auth(root: &str, auth: Auth) -> String {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = rocket::Config {
+        address: Ipv4Addr::LOCALHOST.into(),
+        port,
+        log_level: LogLevel::Off,
+        shutdown: Shutdown {
+            ctrlc: false,
+            ..Default::default()
+        },
+        ..rocket::Config::debug_default()
+    };
+    let options = outpack::api::ServerOptions {
+        auth,
+        ..Default::default()
+    };
+    let rocket = outpack::api::api_with_options(root, options)
+        .unwrap()
+        .configure(config);
+    std::thread::spawn(move || {
+        let _ = rocket::execute(rocket.launch());
+    });
+
+    for _ in 0..500 {
+        if TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_ok() {
+            return format!("http://127.0.0.1:{}", port);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("Server did not start on port {}", port);
+}
+
+fn empty_root() -> TempDir {
+    let root = TempDir::new("outpack").unwrap();
+    outpack::init::outpack_init(root.path().to_str().unwrap(), None, true, true).unwrap();
+    root
+}
+
+fn sha256(content: &str) -> String {
+    format!(
+        "sha256:{:x}",
+        Sha256::new().chain_update(content).finalize()
+    )
+}
+
+fn packet_metadata(id: &str, file: (&str, &str), depends: &[&str]) -> String {
+    let depends: Vec<_> = depends
+        .iter()
+        .map(|id| json!({"packet": id, "query": "latest", "files": [{"here": "input.csv", "there": "data.csv"}]}))
+        .collect();
+    json!({
+        "schema_version": "0.1.1",
+        "name": "data",
+        "id": id,
+        "time": {"start": 1682608108.4139, "end": 1682608108.4309},
+        "parameters": null,
+        "files": [{"path": file.0, "size": file.1.len(), "hash": sha256(file.1)}],
+        "depends": depends,
+        "custom": null,
+        "git": null,
+    })
+    .to_string()
+}
+
+#[test]
+fn can_get_root_and_checksum() {
+    let client = Client::new(&start_server("tests/example"));
+    assert_eq!(client.root().unwrap().schema_version, "0.1.1");
+    assert!(client.checksum(None).unwrap().starts_with("sha256:"));
+    assert!(client.checksum(Some("md5")).unwrap().starts_with("md5:"));
+}
+
+#[test]
+fn can_list_metadata() {
+    let client = Client::new(&start_server("tests/example"));
+    let entries = client.list_location_metadata().unwrap();
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].packet, "20170818-164847-7574883b");
+    assert_eq!(client.packit_metadata(None).unwrap().len(), 4);
+    assert_eq!(client.packit_metadata(Some(1662480556.0)).unwrap().len(), 1);
+    assert_eq!(client.packit_metadata(Some(2e9)).unwrap().len(), 0);
+}
+
+#[test]
+fn can_get_metadata() {
+    let client = Client::new(&start_server("tests/example"));
+    let id = "20180818-164043-7cdcde4b";
+    let json = client.metadata_json(id).unwrap();
+    assert_eq!(json["id"], id);
+    let text = client.metadata_text(id).unwrap();
+    assert_eq!(
+        text,
+        std::fs::read_to_string(format!("tests/example/.outpack/metadata/{}", id)).unwrap()
+    );
+
+    let err = client
+        .metadata_json("20180818-164043-7cdcde4a")
+        .unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::NotFound));
+    assert_eq!(
+        err.detail,
+        "packet with id '20180818-164043-7cdcde4a' does not exist"
+    );
+}
+
+#[test]
+fn can_download_files() {
+    let client = Client::new(&start_server("tests/example"));
+    let dir = TempDir::new("outpack").unwrap();
+    let dest = dir.path().join("file");
+    let hash = "sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    client.download_file(hash, &dest).unwrap();
+    assert_eq!(std::fs::metadata(&dest).unwrap().len(), 115);
+
+    let missing = "sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248";
+    let err = client.download_file(missing, &dest).unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::NotFound));
+}
+
+#[test]
+fn can_get_missing_packets_and_files() {
+    let client = Client::new(&start_server("tests/example"));
+    let ids = vec![
+        String::from("20180818-164043-7cdcde4b"),
+        String::from("20170818-164043-7cdcde4a"),
+    ];
+    assert_eq!(
+        client.missing_packets(&ids, false).unwrap(),
+        ["20170818-164043-7cdcde4a"]
+    );
+    let hashes = vec![
+        String::from("sha256:b189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"),
+        String::from("sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"),
+    ];
+    assert_eq!(
+        client.missing_files(&hashes).unwrap(),
+        ["sha256:a189579a9326f585d308304bd9e03326be5d395ac71b31df359ab8bac408d248"]
+    );
+}
+
+#[test]
+fn can_query_packets() {
+    let client = Client::new(&start_server("tests/example"));
+    let context = QueryContext::default();
+    let query = "latest(name == \"modup-201707-queries1\")";
+    assert_eq!(
+        client.query(query, &context).unwrap(),
+        ["20180818-164043-7cdcde4b"]
+    );
+    let packets = client.query_metadata(query, &context).unwrap();
+    assert_eq!(packets[0].name, "modup-201707-queries1");
+
+    let err = client.query("latest(name ==", &context).unwrap_err();
+    assert_eq!(err.error, "QUERY_PARSE_ERROR");
+    assert_eq!(err.kind, Some(ErrorKind::InvalidInput));
+    assert!(err.position.is_some());
+}
+
+#[test]
+fn upload_errors_are_returned() {
+    let root = empty_root();
+    let client = Client::new(&start_server(root.path().to_str().unwrap()));
+    let err = client
+        .upload_file("md5:bad4a54", "test".as_bytes())
+        .unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::InvalidInput));
+    assert_eq!(
+        err.detail,
+        "Expected hash 'md5:bad4a54' but found 'md5:098f6bcd4621d373cade4e832627b4f6'"
+    );
+
+    let metadata = packet_metadata("20230101-000000-00000001", ("data.csv", "a\n"), &[]);
+    let err = client
+        .upload_packet(&sha256(&metadata), &metadata)
+        .unwrap_err();
+    assert!(err.detail.starts_with("Can't import metadata"));
+}
+
+#[test]
+fn unreachable_server_is_an_error() {
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let client = Client::new(&format!("http://127.0.0.1:{}", port));
+    let err = client.root().unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::Other));
+}
+
+#[test]
+fn can_upload_pull_and_push_packets() {
+    let server_root = empty_root();
+    let client = Client::new(&start_server(server_root.path().to_str().unwrap()));
+
+    let a = "20230101-000000-00000001";
+    let b = "20230101-000000-00000002";
+    let metadata_a = packet_metadata(a, ("data.csv", "a,b\n1,2\n"), &[]);
+    let metadata_b = packet_metadata(b, ("out.csv", "x\n3\n"), &[a]);
+    for content in ["a,b\n1,2\n", "x\n3\n"] {
+        client
+            .upload_file(&sha256(content), content.as_bytes())
+            .unwrap();
+    }
+    client
+        .upload_packet(&sha256(&metadata_a), &metadata_a)
+        .unwrap();
+    client
+        .upload_packet(&sha256(&metadata_b), &metadata_b)
+        .unwrap();
+    assert_eq!(client.list_location_metadata().unwrap().len(), 2);
+
+    // Pull both packets down into a new root...
+    let local = empty_root();
+    let local_path = local.path().to_str().unwrap();
+    let server = Location::new(
+        "server",
+        LocationKind::Http {
+            url: String::from(client.url()),
+        },
+    );
+    outpack::location::add_location(local_path, server).unwrap();
+    let report = outpack::pull::pull(local_path, "server", None).unwrap();
+    assert_eq!(report.packets, [a, b]);
+    assert_eq!(report.files, 2);
+
+    // ...and push the later one, with its dependency, to another server.
+    let other_root = empty_root();
+    let other = Client::new(&start_server(other_root.path().to_str().unwrap()));
+    let location = Location::new(
+        "other",
+        LocationKind::Http {
+            url: String::from(other.url()),
+        },
+    );
+    outpack::location::add_location(local_path, location).unwrap();
+    let report = outpack::push::push(local_path, "other", "name == \"data\"").unwrap();
+    assert_eq!(report.packets, [a, b]);
+    assert_eq!(report.files, 2);
+    let ids = vec![String::from(a), String::from(b)];
+    assert!(other.missing_packets(&ids, true).unwrap().is_empty());
+    assert_eq!(other.metadata_text(b).unwrap(), metadata_b);
+}
+
+#[test]
+fn can_upload_file_in_chunks() {
+    let root = empty_root();
+    let client = Client::new(&start_server(root.path().to_str().unwrap()));
+    let content = "the quick brown fox jumps over the lazy dog";
+    let hash = sha256(content);
+    client
+        .upload_file_in_chunks(&hash, content.as_bytes(), 10)
+        .unwrap();
+    assert!(client
+        .missing_files(std::slice::from_ref(&hash))
+        .unwrap()
+        .is_empty());
+
+    let session = client.start_upload(&hash).unwrap();
+    let status = client
+        .upload_chunk(&session.id, 5, content.as_bytes()[5..].to_vec())
+        .unwrap();
+    assert_eq!(status.received, [(5, 43)]);
+    assert_eq!(client.upload_status(&session.id).unwrap(), status);
+    let err = client.finish_upload(&session.id).unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::InvalidInput));
+    client.abort_upload(&session.id).unwrap();
+    let err = client.upload_status(&session.id).unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::NotFound));
+}
+
+#[test]
+fn can_send_bearer_token() {
+    let auth = Auth::none().with(Box::new(TokenAuthenticator::new(vec![TokenConfig {
+        token: String::from("secret"),
+        scopes: vec![Scope::Read],
+        packets: None,
+    }])));
+    let url = start_server_with_auth("tests/example", auth);
+
+    let err = Client::new(&url).list_location_metadata().unwrap_err();
+    assert_eq!(err.kind, Some(ErrorKind::PermissionDenied));
+    assert_eq!(err.error, "UNAUTHORIZED");
+
+    let client = Client::new(&url).with_token("secret");
+    assert_eq!(client.list_location_metadata().unwrap().len(), 4);
+    let err = client
+        .upload_file("md5:bad4a54", "test".as_bytes())
+        .unwrap_err();
+    assert_eq!(err.error, "FORBIDDEN");
+}
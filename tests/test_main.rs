@@ -7,3 +7,325 @@ fn prints_usage_if_args_invalid() {
     let mut cmd = Command::cargo_bin("outpack_server").unwrap();
     cmd.assert().stdout(predicate::str::contains("Usage:"));
 }
+
+#[test]
+fn gc_reports_and_removes_unreferenced_files() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+    let prefix = root.path().join(".outpack/files/sha256/ab");
+    std::fs::create_dir_all(&prefix).unwrap();
+    std::fs::write(prefix.join("cdef"), "orphan").unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "gc",
+        "--root",
+        root_path,
+        "--dry-run",
+        "--grace-period",
+        "0",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "Found 1 unreferenced files (6 bytes)",
+        ))
+        .stdout(predicate::str::contains("Would remove 1 files (6 bytes)"));
+    assert!(prefix.join("cdef").exists());
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["gc", "--root", root_path]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Removed 0 files"));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["gc", "--root", root_path, "--grace-period", "0"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Removed 1 files (6 bytes)"));
+    assert!(!prefix.join("cdef").exists());
+}
+
+#[test]
+fn verify_reports_problems_with_exit_code() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--root", root_path]);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!({"corrupt": [], "missing": [], "orphaned": []})
+    );
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--root", "tests/example"]);
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("\"type\": \"missing_dependency\""));
+}
+
+#[test]
+fn verify_can_check_metadata_against_schema() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--root", "tests/example"]);
+    cmd.assert()
+        .stdout(predicate::str::contains("invalid_metadata").not());
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["verify", "--schema", "--root", "tests/example"]);
+    let output = cmd.assert().failure().get_output().stdout.clone();
+    let report: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let invalid: Vec<&serde_json::Value> = report["corrupt"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|p| p["type"] == "invalid_metadata")
+        .collect();
+    assert_eq!(
+        invalid,
+        [&serde_json::json!({
+            "type": "invalid_metadata",
+            "packet": "20170818-164847-7574883b",
+            "errors": ["/depends/0/query: null is not of type \"string\""]
+        })]
+    );
+}
+
+#[test]
+fn can_init_and_discover_root_from_subdirectory() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["init", "--use-file-store"])
+        .current_dir(root.path());
+    cmd.assert().success();
+    assert!(root.path().join(".outpack/config.json").exists());
+
+    let nested = root.path().join("a/b");
+    std::fs::create_dir_all(&nested).unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("verify").current_dir(&nested);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\"corrupt\": []"));
+}
+
+#[test]
+fn reports_errors_without_panicking() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("list").current_dir(root.path());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Could not find an outpack root",
+        ))
+        .stderr(predicate::str::contains("panicked").not());
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "show",
+        "--root",
+        "tests/example",
+        "20180818-164043-7cdcde4a",
+    ]);
+    cmd.assert()
+        .failure()
+        .stderr("Error: packet with id '20180818-164043-7cdcde4a' does not exist\n");
+}
+
+#[test]
+fn can_list_and_show_packets() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["list", "--root", "tests/example"]);
+    cmd.assert().success().stdout(predicate::str::contains(
+        "20180818-164043-7cdcde4b\tmodup-201707-queries1\n",
+    ));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "show",
+        "--root",
+        "tests/example",
+        "20180818-164043-7cdcde4b",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("\"modup-201707-queries1\""));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "files",
+        "--root",
+        "tests/example",
+        "20170818-164847-7574883b",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("orderly.yml\t"));
+}
+
+#[test]
+fn can_generate_completions() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["completions", "bash"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("_outpack()"));
+}
+
+#[test]
+fn can_manage_locations() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let other = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    let other_path = other.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+    outpack::init::outpack_init(other_path, None, true, false).unwrap();
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("outpack").unwrap();
+        cmd.args(args).current_dir(root.path());
+        cmd.assert()
+    };
+    run(&["location", "add", "origin", "path", other_path]).success();
+    run(&["location", "add", "server", "http", "http://localhost:8000"]).success();
+    run(&["location", "add", "origin", "http", "http://localhost:8000"])
+        .failure()
+        .stderr("Error: A location with name 'origin' already exists\n");
+    run(&["location", "rename", "server", "remote"]).success();
+    run(&["location", "remove", "origin"]).success();
+    run(&["location", "list"])
+        .success()
+        .stdout("local\tlocal\nremote\thttp\thttp://localhost:8000\n");
+}
+
+#[test]
+fn can_pull_from_location() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let other = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    let other_path = other.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+    outpack::init::outpack_init(other_path, None, true, false).unwrap();
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("outpack").unwrap();
+        cmd.args(args).current_dir(root.path());
+        cmd.assert()
+    };
+    run(&["location", "add", "origin", "path", other_path]).success();
+    run(&["pull", "origin"])
+        .success()
+        .stdout("Pulled metadata for 0 packets\nUnpacked 0 packets (0 files)\n");
+
+    // The metadata in the example does not match the hashes recorded
+    // for it, so must not be imported.
+    let example = std::env::current_dir().unwrap().join("tests/example");
+    run(&[
+        "location",
+        "add",
+        "example",
+        "path",
+        example.to_str().unwrap(),
+    ])
+    .success();
+    run(&["pull", "example"])
+        .failure()
+        .stderr(predicate::str::starts_with(
+            "Error: Metadata for '20170818-164847-7574883b' from 'example' does not match its hash",
+        ));
+    run(&["list"]).success().stdout("");
+}
+
+#[test]
+fn can_push_to_location() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let other = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    let other_path = other.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+    outpack::init::outpack_init(other_path, None, true, false).unwrap();
+
+    let run = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("outpack").unwrap();
+        cmd.args(args).current_dir(root.path());
+        cmd.assert()
+    };
+    run(&["location", "add", "origin", "path", other_path]).success();
+    run(&["push", "origin", "name == \"data\""])
+        .success()
+        .stdout("Pushed 0 packets (0 files)\n");
+    run(&["push", "local", "name == \"data\""])
+        .failure()
+        .stderr("Error: Cannot push to the 'local' location\n");
+}
+
+#[test]
+fn can_export_packet() {
+    let root = tempdir::TempDir::new("outpack").unwrap();
+    let dest = tempdir::TempDir::new("outpack").unwrap();
+    let root_path = root.path().to_str().unwrap();
+    outpack::init::outpack_init(root_path, None, true, false).unwrap();
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["export", "20230101-000000-00000001", "--dest"])
+        .arg(dest.path())
+        .current_dir(root.path());
+    cmd.assert()
+        .failure()
+        .stderr("Error: packet with id '20230101-000000-00000001' does not exist\n");
+}
+
+#[test]
+fn query_prints_usage_if_args_invalid() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.arg("query");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Usage:"));
+}
+
+#[test]
+fn can_query_from_command_line() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["query", "--root", "tests/example", "latest"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("20180818-164043-7cdcde4b"));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["query", "--parse-only", "latest"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Latest(None)"));
+
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args(["query", "--root", "tests/example", "invalid"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed to parse query"));
+}
+
+#[test]
+fn can_pass_this_and_environment_on_command_line() {
+    let mut cmd = Command::cargo_bin("outpack").unwrap();
+    cmd.args([
+        "query",
+        "--root",
+        "tests/example",
+        "parameter:disease == this:disease && parameter:size == environment:size",
+        "--this",
+        "disease=YF",
+        "--env",
+        "size=10",
+    ]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("20180220-095832-16a4bbed"));
+}
@@ -1,21 +1,14 @@
-use std::process::Command;
+use std::collections::HashMap;
 
-use assert_cmd::prelude::*;
-use predicates::prelude::*;
+use serde_json::Value;
 
-use outpack::query::QueryError;
+use outpack::query::{QueryContext, QueryError};
 
 pub fn test_query(root: &str, query: &str, result: &str) {
-    let packets = outpack::query::run_query(root, query).unwrap();
+    let packets = outpack::query::run_query(root, query, &QueryContext::default()).unwrap();
     assert_eq!(packets, result);
 }
 
-#[test]
-fn prints_usage_if_args_invalid() {
-    let mut cmd = Command::cargo_bin("outpack_query").unwrap();
-    cmd.assert().stdout(predicate::str::contains("Usage:"));
-}
-
 #[test]
 fn locates_latest_packet() {
     let root_path = "tests/example";
@@ -25,7 +18,7 @@ fn locates_latest_packet() {
 #[test]
 fn returns_parse_error_if_syntax_invalid() {
     let root_path = "tests/example";
-    let e = outpack::query::run_query(root_path, "invalid").unwrap_err();
+    let e = outpack::query::run_query(root_path, "invalid", &QueryContext::default()).unwrap_err();
     assert!(matches!(e, outpack::query::QueryError::ParseError(..)));
     let text = format!("{}", e);
     assert!(text.contains("Failed to parse query\n"));
@@ -54,6 +47,42 @@ fn can_get_packet_by_id() {
     test_query(root_path, r#""123""#, "Found no packets");
 }
 
+#[test]
+fn can_find_packets() {
+    let root_path = "tests/example";
+    let packets = outpack::query::find_packets(
+        root_path,
+        r#"name == "modup-201707-queries1""#,
+        &QueryContext::default(),
+    )
+    .unwrap();
+    let ids: Vec<&str> = packets.iter().map(|packet| &packet.id[..]).collect();
+    assert_eq!(
+        ids,
+        vec![
+            "20170818-164830-33e0ab01",
+            "20170818-164847-7574883b",
+            "20180818-164043-7cdcde4b"
+        ]
+    );
+}
+
+#[test]
+fn can_use_this_and_environment_in_query() {
+    let root_path = "tests/example";
+    let context = QueryContext {
+        this: HashMap::from([(String::from("disease"), Value::from("YF"))]),
+        environment: HashMap::from([(String::from("size"), Value::from(10))]),
+    };
+    let packets = outpack::query::run_query(
+        root_path,
+        "parameter:disease == this:disease && parameter:size == environment:size",
+        &context,
+    )
+    .unwrap();
+    assert_eq!(packets, "20180220-095832-16a4bbed");
+}
+
 #[test]
 fn can_get_packet_by_name() {
     let root_path = "tests/example";
@@ -68,7 +97,8 @@ fn can_get_packet_by_name() {
         "20170818-164830-33e0ab01\n20170818-164847-7574883b\n20180818-164043-7cdcde4b",
     );
     test_query(root_path, r#"name == "notathing""#, "Found no packets");
-    let e = outpack::query::run_query(root_path, "name == invalid").unwrap_err();
+    let e = outpack::query::run_query(root_path, "name == invalid", &QueryContext::default())
+        .unwrap_err();
     assert!(matches!(e, QueryError::ParseError(..)));
     assert!(e.to_string().contains("expected lookup or literal"));
 }
@@ -86,7 +116,12 @@ fn can_get_latest_of_lookup() {
 #[test]
 fn can_get_packet_by_parameter() {
     let root_path = "tests/example";
-    let packets = outpack::query::run_query(root_path, r#"parameter:disease == "YF""#).unwrap();
+    let packets = outpack::query::run_query(
+        root_path,
+        r#"parameter:disease == "YF""#,
+        &QueryContext::default(),
+    )
+    .unwrap();
     assert_eq!(
         packets,
         "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n\
@@ -129,7 +164,12 @@ fn can_get_packet_by_boolean_parameter() {
     );
     test_query(root_path, "parameter:pull_data == 1", "Found no packets");
     test_query(root_path, "parameter:pull_data == 0", "Found no packets");
-    let e = outpack::query::run_query(root_path, "parameter:pull_data == T").unwrap_err();
+    let e = outpack::query::run_query(
+        root_path,
+        "parameter:pull_data == T",
+        &QueryContext::default(),
+    )
+    .unwrap_err();
     assert!(matches!(e, QueryError::ParseError(..)));
     assert!(e.to_string().contains("expected lookup or literal"));
 }
@@ -218,11 +258,42 @@ fn can_get_packet_other_comparisons() {
     );
 
     test_query(root_path, r#"parameter:disease < "AB""#, "Found no packets");
-    test_query(root_path, r#"parameter:disease > "AB""#, "Found no packets");
+    test_query(
+        root_path,
+        r#"parameter:disease > "AB""#,
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
     test_query(
         root_path,
         r#"parameter:disease <= "YF""#,
-        "Found no packets",
+        "20170818-164830-33e0ab01\n20180220-095832-16a4bbed\n20180818-164043-7cdcde4b",
+    );
+    test_query(root_path, "parameter:disease > true", "Found no packets");
+}
+
+#[test]
+fn can_get_packet_string_tests() {
+    let root_path = "tests/example";
+    test_query(root_path, "name =~ 'params1$'", "20180220-095832-16a4bbed");
+    test_query(
+        root_path,
+        "startswith(id, '2018') && contains(name, 'queries')",
+        "20180818-164043-7cdcde4b",
+    );
+    test_query(
+        root_path,
+        "iequals(parameter:disease, 'yf') && endswith(name, 'params1')",
+        "20180220-095832-16a4bbed",
+    );
+    test_query(
+        root_path,
+        "id in ['20170818-164847-7574883b', '20180818-164043-7cdcde4b']",
+        "20170818-164847-7574883b\n20180818-164043-7cdcde4b",
+    );
+    test_query(
+        root_path,
+        "latest(name =~ 'queries')",
+        "20180818-164043-7cdcde4b",
     );
 }
 
@@ -314,8 +385,12 @@ fn query_can_assert_single_return() {
         "single(parameter:pull_data == TRUE)",
         "20180220-095832-16a4bbed",
     );
-    let e =
-        outpack::query::run_query(root_path, "single(parameter:pull_data == false)").unwrap_err();
+    let e = outpack::query::run_query(
+        root_path,
+        "single(parameter:pull_data == false)",
+        &QueryContext::default(),
+    )
+    .unwrap_err();
     assert!(matches!(e, QueryError::EvalError(..)));
     assert!(e
         .to_string()